@@ -0,0 +1,159 @@
+use std::path::{Path, PathBuf};
+
+use serde::Serialize;
+
+use crate::{list::Format, Error};
+
+/// How a host dependency is looked up
+enum Probe {
+    /// Executables in `PATH`, in the order of preference
+    Binary(&'static [&'static str]),
+    /// Executable in `PATH`, or downloaded by brie to the `.bin` directory of libraries
+    Tool(&'static str),
+    /// Shared library loaded by the dynamic loader
+    Library(&'static str),
+}
+
+struct Check {
+    name: &'static str,
+    probe: Probe,
+    /// Whether games are unlikely to run without it
+    required: bool,
+    hint: &'static str,
+}
+
+const CHECKS: &[Check] = &[
+    Check {
+        name: "vulkan loader",
+        probe: Probe::Library("libvulkan.so.1"),
+        required: true,
+        hint: "Install the Vulkan loader with its 32-bit variant, e.g. `vulkan-icd-loader` and \
+            `lib32-vulkan-icd-loader` on Arch, `libvulkan1` and `libvulkan1:i386` on Debian",
+    },
+    Check {
+        name: "wine",
+        probe: Probe::Binary(&["wine", "wine64"]),
+        required: false,
+        hint: "Only needed for units with `runtime: system`, \
+            `ge-proton` and `tkg` runtimes are downloaded by brie",
+    },
+    Check {
+        name: "winetricks",
+        probe: Probe::Tool("winetricks"),
+        required: false,
+        hint: "Downloaded by brie on the first launch of a wine unit",
+    },
+    Check {
+        name: "cabextract",
+        probe: Probe::Tool("cabextract"),
+        required: false,
+        hint: "Downloaded by brie on the first launch of a wine unit",
+    },
+    Check {
+        name: "unzip",
+        probe: Probe::Binary(&["unzip"]),
+        required: false,
+        hint: "Needed by some winetricks verbs, install `unzip`",
+    },
+    Check {
+        name: "gamemoderun",
+        probe: Probe::Binary(&["gamemoderun"]),
+        required: false,
+        hint: "Needed for `gamemoderun` in `wrapper`, install `gamemode`",
+    },
+    Check {
+        name: "mangohud",
+        probe: Probe::Binary(&["mangohud"]),
+        required: false,
+        hint: "Needed for `mangohud` in `wrapper`, install `mangohud`",
+    },
+    Check {
+        name: "gamescope",
+        probe: Probe::Binary(&["gamescope"]),
+        required: false,
+        hint: "Needed for `gamescope` in `wrapper`, install `gamescope`",
+    },
+];
+
+/// Result of a host dependency check, as printed by `brie doctor`
+#[derive(Serialize)]
+pub struct Report {
+    pub name: &'static str,
+    /// Where the dependency was found, if it was
+    pub found: Option<PathBuf>,
+    pub required: bool,
+    pub hint: &'static str,
+}
+
+/// Checks the host for tools and libraries commonly needed to run units, and prints what's missing.
+/// Fails if a required dependency is missing.
+pub fn doctor(libraries: &Path, format: Format) -> Result<(), Error> {
+    let reports = CHECKS
+        .iter()
+        .map(|check| Report {
+            name: check.name,
+            found: probe(&check.probe, libraries),
+            required: check.required,
+            hint: check.hint,
+        })
+        .collect::<Vec<_>>();
+
+    match format {
+        Format::Json => println!("{}", serde_json::to_string_pretty(&reports)?),
+        Format::Text => {
+            for report in &reports {
+                match (&report.found, report.required) {
+                    (Some(path), _) => println!("ok       {}: {}", report.name, path.display()),
+                    (None, true) => println!("MISSING  {}. {}", report.name, report.hint),
+                    (None, false) => println!("missing  {}. {}", report.name, report.hint),
+                }
+            }
+        }
+    }
+
+    let missing = reports
+        .iter()
+        .filter(|r| r.required && r.found.is_none())
+        .map(|r| r.name)
+        .collect::<Vec<_>>();
+    match missing.is_empty() {
+        true => Ok(()),
+        false => Err(Error::Missing(missing.join(", "))),
+    }
+}
+
+fn probe(probe: &Probe, libraries: &Path) -> Option<PathBuf> {
+    match probe {
+        Probe::Binary(binaries) => binaries.iter().find_map(|b| which::which(b).ok()),
+        Probe::Tool(tool) => which::which(tool).ok().or_else(|| {
+            let downloaded = libraries.join(".bin").join(tool);
+            downloaded.is_file().then_some(downloaded)
+        }),
+        Probe::Library(library) => {
+            brie_wine::find_shared_library(library).map(|dir| Path::new(&dir).join(library))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, path::Path};
+
+    use super::{probe, Probe};
+
+    #[test]
+    fn downloaded_tool() {
+        let dir = Path::new(".tmp").join("downloaded_tool");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join(".bin")).unwrap();
+
+        let tool = Probe::Tool("brie-doctor-test-tool");
+        assert_eq!(probe(&tool, &dir), None);
+
+        let path = dir.join(".bin").join("brie-doctor-test-tool");
+        fs::write(&path, "").unwrap();
+        assert_eq!(probe(&tool, &dir), Some(path));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,241 @@
+use std::{borrow::Cow, fmt::Debug, fs, io, path::Path, str::FromStr};
+
+use brie_cfg::{Brie, Runtime, Unit};
+use brie_wine::Paths;
+use indexmap::IndexMap;
+use serde::Serialize;
+
+use crate::{prefix_name, wine_unit, Error};
+
+/// Output format of informational commands
+#[derive(Clone, Copy, Default)]
+pub enum Format {
+    #[default]
+    Text,
+    Json,
+}
+
+impl FromStr for Format {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(Self::Text),
+            "json" => Ok(Self::Json),
+            _ => Err(Error::UnknownFormat(s.to_owned())),
+        }
+    }
+}
+
+/// A configured unit, as printed by `brie list`
+#[derive(Serialize)]
+pub struct UnitInfo<'a> {
+    pub key: &'a str,
+    pub name: Option<&'a str>,
+    /// Either `native` or `wine`
+    pub kind: &'static str,
+    /// Name of the wine prefix directory, wine units only
+    pub prefix: Option<String>,
+    pub runtime: Option<&'a Runtime>,
+    /// Configured library versions by library name
+    pub libraries: IndexMap<&'static str, Cow<'a, str>>,
+}
+
+impl<'a> UnitInfo<'a> {
+    fn new(key: &'a str, unit: &'a Unit) -> Self {
+        let name = unit.common().name.as_deref();
+        match unit {
+            Unit::Native(_) => Self {
+                key,
+                name,
+                kind: "native",
+                prefix: None,
+                runtime: None,
+                libraries: IndexMap::new(),
+            },
+            Unit::Wine(unit) => Self {
+                key,
+                name,
+                kind: "wine",
+                prefix: Some(prefix_name(key, unit)),
+                runtime: Some(&unit.runtime),
+                libraries: unit
+                    .libraries
+                    .iter()
+                    .map(|(library, version)| (library.to_str(), version.to_str()))
+                    .collect(),
+            },
+        }
+    }
+}
+
+/// A downloaded runtime or library, as printed by `brie versions`
+#[derive(Serialize)]
+pub struct Installed {
+    /// Directory name in the libraries folder
+    pub name: String,
+    /// Downloaded versions
+    pub versions: Vec<String>,
+    /// Versions which `latest` and `latest-<n>` currently resolve to
+    pub aliases: IndexMap<String, String>,
+}
+
+/// Prints configured units
+pub fn units(cfg: &Brie, format: Format) -> Result<(), Error> {
+    let units = cfg
+        .units
+        .iter()
+        .map(|(key, unit)| UnitInfo::new(key, unit))
+        .collect::<Vec<_>>();
+
+    match format {
+        Format::Json => println!("{}", serde_json::to_string_pretty(&units)?),
+        Format::Text => {
+            for unit in units {
+                let name = unit.name.unwrap_or(unit.key);
+                println!("{}: {name} ({})", unit.key, unit.kind);
+                if let Some(prefix) = unit.prefix {
+                    println!("  prefix: {prefix}");
+                }
+                if let Some(runtime) = unit.runtime {
+                    println!("  runtime: {}", runtime_str(runtime));
+                }
+                for (library, version) in unit.libraries {
+                    println!("  {library}: {version}");
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Prints the unit as it would be launched, with the resolved env, prefix and command.
+/// Library versions are resolved from the cached state of downloads, without checking for updates.
+pub fn show(paths: &Paths, key: &str, unit: Unit, format: Format) -> Result<(), Error> {
+    // Keep the output free of logs, e.g. about reading the state file
+    log::set_max_level(log::LevelFilter::Warn);
+
+    match unit {
+        Unit::Native(unit) => print(&unit, format),
+        Unit::Wine(unit) => {
+            let prefix = prefix_name(key, &unit);
+            let resolved = brie_wine::resolve(paths, wine_unit(prefix, unit))?;
+            print(&resolved, format)
+        }
+    }
+}
+
+fn print(value: &(impl Debug + Serialize), format: Format) -> Result<(), Error> {
+    match format {
+        Format::Json => println!("{}", serde_json::to_string_pretty(value)?),
+        Format::Text => println!("{value:#?}"),
+    }
+
+    Ok(())
+}
+
+/// Prints downloaded versions of runtimes and libraries
+pub fn versions(libraries: &Path, format: Format) -> Result<(), Error> {
+    let installed = installed(libraries)?;
+
+    match format {
+        Format::Json => println!("{}", serde_json::to_string_pretty(&installed)?),
+        Format::Text => {
+            for library in installed {
+                println!("{}:", library.name);
+                for version in library.versions {
+                    println!("  - {version}");
+                }
+                for (alias, version) in library.aliases {
+                    println!("  - {alias} -> {version}");
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn runtime_str(runtime: &Runtime) -> String {
+    match runtime {
+        Runtime::System { path: None } => "system".to_owned(),
+        Runtime::System { path: Some(path) } => format!("system ({})", path.display()),
+        Runtime::GeProton { version } => format!("ge-proton {}", version.to_str()),
+        Runtime::Tkg { version } => format!("tkg {}", version.to_str()),
+        Runtime::Proton { path } => format!("proton ({})", path.display()),
+    }
+}
+
+/// Reads downloaded versions from the libraries directory.
+/// Hidden entries, like helper tools in `.bin`, are skipped.
+fn installed(libraries: &Path) -> Result<Vec<Installed>, io::Error> {
+    if !libraries.exists() {
+        return Ok(Vec::new());
+    }
+
+    let mut installed = Vec::new();
+    for entry in visible_entries(libraries)? {
+        if !entry.path().is_dir() {
+            continue;
+        }
+
+        let mut versions = Vec::new();
+        let mut aliases = IndexMap::new();
+        for version in visible_entries(&entry.path())? {
+            let name = version.file_name().to_string_lossy().to_string();
+            match fs::read_link(version.path()) {
+                Ok(target) => {
+                    let target = target.file_name().unwrap_or_default();
+                    aliases.insert(name, target.to_string_lossy().to_string());
+                }
+                Err(_) if version.path().is_dir() => versions.push(name),
+                Err(_) => {}
+            }
+        }
+
+        versions.sort();
+        aliases.sort_keys();
+        installed.push(Installed {
+            name: entry.file_name().to_string_lossy().to_string(),
+            versions,
+            aliases,
+        });
+    }
+
+    installed.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(installed)
+}
+
+fn visible_entries(dir: &Path) -> Result<Vec<fs::DirEntry>, io::Error> {
+    Ok(fs::read_dir(dir)?
+        .filter_map(Result::ok)
+        .filter(|e| !e.file_name().to_string_lossy().starts_with('.'))
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, os::unix, path::Path};
+
+    use super::installed;
+
+    #[test]
+    fn installed_versions() {
+        let dir = Path::new(".tmp").join("installed_versions");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join(".bin")).unwrap();
+        fs::create_dir_all(dir.join("dxvk").join("v2.3")).unwrap();
+        fs::create_dir_all(dir.join("dxvk").join("v2.4")).unwrap();
+        unix::fs::symlink("v2.4", dir.join("dxvk").join("latest")).unwrap();
+        fs::write(dir.join("dxvk").join(".updated"), "").unwrap();
+
+        let installed = installed(&dir).unwrap();
+        assert_eq!(installed.len(), 1);
+        assert_eq!(installed[0].name, "dxvk");
+        assert_eq!(installed[0].versions, ["v2.3", "v2.4"]);
+        assert_eq!(installed[0].aliases["latest"], "v2.4");
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
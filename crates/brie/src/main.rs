@@ -1,24 +1,80 @@
-use std::env::args;
+use std::{
+    io::{self, BufRead, IsTerminal},
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicBool, Ordering},
+    time::Duration,
+};
 
-use brie_wine::{mp, Paths, Unit};
+use brie_wine::{mp, Options, Paths, Unit};
 use indexmap::IndexMap;
 
+mod doctor;
+mod list;
 mod native;
 
+/// Switches [`Logger`] to log everything at trace level, set by units with `debug: true`
+static DEBUG: AtomicBool = AtomicBool::new(false);
+
+/// Logs only brie's own crate below info level, unless [`DEBUG`] is set
+struct Logger {
+    normal: simple_logger::SimpleLogger,
+    debug: simple_logger::SimpleLogger,
+}
+
+impl Logger {
+    fn current(&self) -> &simple_logger::SimpleLogger {
+        match DEBUG.load(Ordering::Relaxed) {
+            true => &self.debug,
+            false => &self.normal,
+        }
+    }
+}
+
+impl log::Log for Logger {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        log::Log::enabled(self.current(), metadata)
+    }
+
+    fn log(&self, record: &log::Record) {
+        log::Log::log(self.current(), record);
+    }
+
+    fn flush(&self) {
+        log::Log::flush(self.current());
+    }
+}
+
 fn main() {
-    let log = simple_logger::SimpleLogger::new()
+    let normal = simple_logger::SimpleLogger::new()
         .with_level(log::LevelFilter::Info)
         .with_module_level("brie", log::LevelFilter::Trace);
-    let max_level = log.max_level();
+    let max_level = normal.max_level();
+    let debug = simple_logger::SimpleLogger::new().with_level(log::LevelFilter::Trace);
+    let log = Logger { normal, debug };
     let _ = indicatif_log_bridge::LogWrapper::new(mp().clone(), log).try_init();
     log::set_max_level(max_level);
 
-    if let Err(e) = launch() {
+    let mut pause_on_error = false;
+    if let Err(e) = launch(&mut pause_on_error) {
         eprintln!("Error: {e}");
+        if pause_on_error {
+            pause();
+        }
         std::process::exit(1);
     }
 }
 
+/// Waits for Enter, so that the error can be read before a terminal opened by a shortcut closes
+fn pause() {
+    let stdin = io::stdin();
+    if !stdin.is_terminal() {
+        return;
+    }
+
+    eprintln!("Press Enter to exit...");
+    let _ = stdin.lock().read_line(&mut String::new());
+}
+
 #[derive(Debug)]
 struct Units(Vec<String>);
 
@@ -43,6 +99,12 @@ enum Error {
     Xdg(#[from] xdg::BaseDirectoriesError),
     #[error("Config error. {0}")]
     Config(#[from] brie_cfg::Error),
+    #[error("Missing value for `{0}` argument.")]
+    MissingValue(&'static str),
+    #[error("Invalid value `{1}` for `{0}` argument.")]
+    InvalidValue(&'static str, String),
+    #[error("Unknown argument `{0}`.")]
+    UnknownArgument(String),
     #[error("Unit not provided as an argument. Available units:\n{0}")]
     NoUnitProvided(Units),
     #[error("Unit `{0}` not found. Available units:\n{1}")]
@@ -51,59 +113,511 @@ enum Error {
     Wine(#[from] brie_wine::Error),
     #[error("Native unit error. {0}")]
     Native(#[from] native::Error),
+    #[error("Unit `{0}` is not a wine unit and has no wine prefix.")]
+    NotWine(String),
+    #[error("Prefix archive error. {0}")]
+    Archive(#[from] brie_wine::ArchiveError),
+    #[error("Unknown format `{0}`. Supported formats are `text` and `json`.")]
+    UnknownFormat(String),
+    #[error("IO error. {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Unable to expand path. {0}")]
+    Expand(#[from] shellexpand::LookupError<std::env::VarError>),
+    #[error("JSON error. {0}")]
+    Json(#[from] serde_json::Error),
+    #[error("Required dependencies are missing: {0}.")]
+    Missing(String),
+}
+
+/// Commands which are run instead of launching a unit
+#[derive(Clone, Copy)]
+enum Subcommand {
+    /// Exports the wine prefix of a unit
+    Export,
+    /// Imports the wine prefix of a unit
+    Import,
+    /// Installs libraries into a wine prefix which is not managed by brie
+    InstallLibraries,
+    /// Lists configured units
+    List,
+    /// Lists downloaded runtime and library versions
+    Versions,
+    /// Checks the host for commonly needed tools and libraries
+    Doctor,
+    /// Prints a unit as it would be launched
+    Show,
+    /// Downloads runtimes and libraries of units without launching them
+    Prefetch,
 }
 
-fn launch() -> Result<(), Error> {
+/// Command line arguments. Flags must precede the unit name,
+/// all arguments after the unit name are appended to the unit command,
+/// unless the first one is the name of a command from the unit `commands`.
+#[derive(Default)]
+struct Args {
+    config: Option<PathBuf>,
+    options: Options,
+    /// Overwrite an existing prefix on import
+    force: bool,
+    /// Output format of `list`, `versions`, `doctor` and `show`
+    format: list::Format,
+    /// Wine prefix for `install-libraries`
+    prefix: Option<PathBuf>,
+    /// Wine binary for `install-libraries`, system wine if not set
+    wine: Option<PathBuf>,
+    /// Env variables overriding the ones from the unit config
+    env: Vec<(String, String)>,
+    /// Wait for a key press before exiting on error
+    pause_on_error: bool,
+    unit: Option<String>,
+    rest: Vec<String>,
+}
+
+impl Args {
+    fn parse(mut args: impl Iterator<Item = String>) -> Result<Self, Error> {
+        let mut parsed = Self::default();
+
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--config" => {
+                    let path = args.next().ok_or(Error::MissingValue("--config"))?;
+                    parsed.config = Some(path.into());
+                }
+                "--no-wait" => parsed.options.no_wait = true,
+                "--offline" => parsed.options.offline = true,
+                "--wait-timeout" => {
+                    let secs = args.next().ok_or(Error::MissingValue("--wait-timeout"))?;
+                    let timeout = secs
+                        .parse()
+                        .map_err(|_| Error::InvalidValue("--wait-timeout", secs))?;
+                    parsed.options.wait_timeout = Some(Duration::from_secs(timeout));
+                }
+                "--prefix-only" => parsed.options.prefix_only = true,
+                "--reinstall-libraries" => parsed.options.reinstall_libraries = true,
+                "--force" => parsed.force = true,
+                "--pause-on-error" => parsed.pause_on_error = true,
+                "--progress-summary" => brie_wine::set_progress_summary(true),
+                "--format" => {
+                    let format = args.next().ok_or(Error::MissingValue("--format"))?;
+                    parsed.format = format.parse()?;
+                }
+                "--prefix" => {
+                    let path = args.next().ok_or(Error::MissingValue("--prefix"))?;
+                    parsed.prefix = Some(path.into());
+                }
+                "--wine" => {
+                    let path = args.next().ok_or(Error::MissingValue("--wine"))?;
+                    parsed.wine = Some(path.into());
+                }
+                "--env" => {
+                    let var = args.next().ok_or(Error::MissingValue("--env"))?;
+                    let (key, value) = var
+                        .split_once('=')
+                        .filter(|(key, _)| !key.is_empty())
+                        .ok_or_else(|| Error::InvalidValue("--env", var.clone()))?;
+                    parsed.env.push((key.to_owned(), value.to_owned()));
+                }
+                "--" => {
+                    parsed.unit = args.next();
+                    break;
+                }
+                flag if flag.starts_with("--") => {
+                    return Err(Error::UnknownArgument(flag.to_owned()));
+                }
+                _ => {
+                    parsed.unit = Some(arg);
+                    break;
+                }
+            }
+        }
+
+        parsed.rest = args.collect();
+
+        Ok(parsed)
+    }
+}
+
+/// Prints dlls which the given libraries install, and where they are copied to.
+/// Lists all libraries if none are given.
+fn libinfo(names: impl Iterator<Item = String>) -> Result<(), Error> {
+    let mut libraries = names
+        .map(|name| name.parse())
+        .collect::<Result<Vec<brie_cfg::Library>, _>>()?;
+    if libraries.is_empty() {
+        libraries = brie_cfg::Library::ALL.to_vec();
+    }
+
+    for library in libraries {
+        println!("{}:", library.to_str());
+        for dlls in brie_wine::library_dlls(library) {
+            for file in dlls.files {
+                println!(
+                    "  {}/{file} -> {}",
+                    dlls.source,
+                    dlls.target(file).display()
+                );
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Installs libraries given as `<library>[=<version>]` into a wine prefix which is not managed
+/// by brie. The config is optional, and only used for paths, tokens and tools.
+fn install_libraries(data_home: &Path, mut cfg: brie_cfg::Brie, args: Args) -> Result<(), Error> {
+    let prefix = args.prefix.ok_or(Error::MissingValue("--prefix"))?;
+    let libraries = args
+        .unit
+        .into_iter()
+        .chain(args.rest)
+        .map(|library| {
+            let (name, version) = match library.split_once('=') {
+                Some((name, version)) => (name, version.to_owned().into()),
+                None => (library.as_str(), brie_cfg::ReleaseVersion::Latest),
+            };
+            Ok((name.parse()?, version))
+        })
+        .collect::<Result<IndexMap<brie_cfg::Library, _>, Error>>()?;
+    if libraries.is_empty() {
+        return Err(Error::MissingValue("<library>"));
+    }
+
+    brie_wine::set_download_headers(&cfg.download_headers);
+    let paths = Paths::from_config(data_home, &cfg.paths)?;
+    let options = options(&mut cfg, args.options);
+    let runtime = brie_cfg::Runtime::System { path: args.wine };
+    let tokens = cfg.tokens.unwrap_or_default();
+    brie_wine::install_libraries(&paths, &tokens, &prefix, &runtime, libraries, &options)?;
+
+    Ok(())
+}
+
+/// Downloads runtimes and libraries of the given wine units, or of all of them if none are given
+fn prefetch(paths: &Paths, mut cfg: brie_cfg::Brie, args: Args) -> Result<(), Error> {
+    let keys = args.unit.into_iter().chain(args.rest).collect::<Vec<_>>();
+    if let Some(key) = keys.iter().find(|&key| !cfg.units.contains_key(key)) {
+        return Err(Error::NotFound(key.clone(), Units::new(&cfg.units)));
+    }
+
+    let units = std::mem::take(&mut cfg.units)
+        .into_iter()
+        .filter(|(key, _)| keys.is_empty() || keys.contains(key))
+        .filter_map(|(key, unit)| match unit {
+            brie_cfg::Unit::Wine(unit) => Some(wine_unit(prefix_name(&key, &unit), unit)),
+            brie_cfg::Unit::Native(_) => None,
+        })
+        .collect::<Vec<_>>();
+
+    let options = options(&mut cfg, args.options);
+    let tokens = cfg.tokens.unwrap_or_default();
+    brie_wine::prefetch(paths, &tokens, &units, &options)?;
+
+    Ok(())
+}
+
+/// Launch options with the config level settings applied on top of the command line ones
+fn options(cfg: &mut brie_cfg::Brie, options: Options) -> Options {
+    Options {
+        tools: std::mem::take(&mut cfg.tools),
+        post_extract: std::mem::take(&mut cfg.post_extract),
+        latest_check_interval: cfg.latest_check_interval.map(Duration::from_secs),
+        ..options
+    }
+}
+
+/// Prints the version, and with `verbose` also the features supported by this build
+fn version(verbose: bool) {
+    println!(
+        "brie {} ({} {})",
+        env!("CARGO_PKG_VERSION"),
+        env!("BRIE_GIT_HASH"),
+        env!("BRIE_TARGET")
+    );
+
+    if !verbose {
+        return;
+    }
+
+    let libraries = brie_cfg::Library::ALL.map(brie_cfg::Library::to_str);
+    println!("runtimes: {}", brie_cfg::Runtime::KINDS.join(", "));
+    println!("libraries: {}", libraries.join(", "));
+    println!("archive formats: {}", brie_wine::ARCHIVE_FORMATS.join(", "));
+    println!("winetricks: downloaded on launch, latest unless pinned with `tools.winetricks`");
+    println!("cabextract: downloaded on launch, latest unless pinned with `tools.cabextract`");
+}
+
+/// Runs brie, setting `pause_on_error` as soon as the flag or the config option is read
+fn launch(pause_on_error: &mut bool) -> Result<(), Error> {
+    let mut args = std::env::args().skip(1).peekable();
+    let subcommand = match args.peek().map(String::as_str) {
+        Some("libinfo") => return libinfo(args.skip(1)),
+        Some("--version" | "-V") => {
+            version(args.any(|arg| arg == "--verbose" || arg == "-v"));
+            return Ok(());
+        }
+        Some("export") => Some(Subcommand::Export),
+        Some("import") => Some(Subcommand::Import),
+        Some("install-libraries") => Some(Subcommand::InstallLibraries),
+        Some("list") => Some(Subcommand::List),
+        Some("versions") => Some(Subcommand::Versions),
+        Some("doctor") => Some(Subcommand::Doctor),
+        Some("show") => Some(Subcommand::Show),
+        Some("prefetch") => Some(Subcommand::Prefetch),
+        _ => None,
+    };
+    if subcommand.is_some() {
+        args.next();
+    }
+
+    let args = Args::parse(args)?;
+    *pause_on_error = args.pause_on_error;
     let xdg = xdg::BaseDirectories::with_prefix("brie")?;
 
-    let config_home = xdg.get_config_home();
     let data_home = xdg.get_data_home();
+    let config = brie_cfg::config_path(args.config, || xdg.get_config_home().join("brie.yaml"));
+    if let Some(subcommand @ (Subcommand::Versions | Subcommand::Doctor)) = subcommand {
+        // Downloaded versions and host dependencies can be checked without a config
+        let paths = match config.exists() {
+            true => Paths::from_config(&data_home, &brie_cfg::read(config)?.paths)?,
+            false => Paths::new(&data_home),
+        };
+        return match subcommand {
+            Subcommand::Doctor => doctor::doctor(&paths.libraries, args.format),
+            _ => list::versions(&paths.libraries, args.format),
+        };
+    }
+    if let Some(Subcommand::InstallLibraries) = subcommand {
+        let cfg = match config.exists() {
+            true => brie_cfg::read(config)?,
+            false => brie_cfg::Brie::default(),
+        };
+        return install_libraries(&data_home, cfg, args);
+    }
 
-    let mut cfg = brie_cfg::read(config_home.join("brie.yaml"))?;
+    let mut cfg = brie_cfg::read(config)?;
+    brie_wine::set_download_headers(&cfg.download_headers);
+    let paths = Paths::from_config(&data_home, &cfg.paths)?;
+    *pause_on_error |= cfg.pause_on_error;
+    if let Some(Subcommand::List) = subcommand {
+        return list::units(&cfg, args.format);
+    }
+    if let Some(Subcommand::Prefetch) = subcommand {
+        return prefetch(&paths, cfg, args);
+    }
 
-    let mut args = args();
     let name = args
-        .nth(1)
+        .unit
         .ok_or_else(|| Error::NoUnitProvided(Units::new(&cfg.units)))?;
-    let mut unit = cfg
+    let unit = cfg
         .units
         .remove(&name)
         .ok_or_else(|| Error::NotFound(name.clone(), Units::new(&cfg.units)))?;
 
-    unit.common_mut().command.extend(args);
+    if let Some(Subcommand::Show) = subcommand {
+        let unit = apply_args(unit, args.rest, args.env);
+        return list::show(&paths, &name, unit, args.format);
+    }
+
+    if let Some(subcommand) = subcommand {
+        let brie_cfg::Unit::Wine(unit) = unit else {
+            return Err(Error::NotWine(name));
+        };
+
+        let file = args.rest.first().ok_or(Error::MissingValue("<file>"))?;
+        let prefix = paths.prefixes.join(prefix_name(&name, &unit));
+        match subcommand {
+            Subcommand::Export => brie_wine::export_prefix(&prefix, Path::new(file))?,
+            Subcommand::Import => brie_wine::import_prefix(&prefix, Path::new(file), args.force)?,
+            Subcommand::InstallLibraries
+            | Subcommand::List
+            | Subcommand::Versions
+            | Subcommand::Doctor
+            | Subcommand::Show
+            | Subcommand::Prefetch => {
+                unreachable!()
+            }
+        }
+
+        return Ok(());
+    }
+
+    let unit = apply_args(unit, args.rest, args.env);
+    if unit.common().debug {
+        DEBUG.store(true, Ordering::Relaxed);
+        log::set_max_level(log::LevelFilter::Trace);
+    }
 
     match unit {
+        brie_cfg::Unit::Native(_) if args.options.prefix_only => {
+            log::info!("Native units have no wine prefix, skipping launch");
+        }
         brie_cfg::Unit::Native(unit) => {
             native::launch(unit)?;
         }
         brie_cfg::Unit::Wine(unit) => {
-            let paths = Paths::new(&data_home);
-            let unit = Unit {
-                runtime: unit.runtime,
-                libraries: unit.libraries,
-                env: unit.common.env,
-                prefix: unit
-                    .prefix
-                    .unwrap_or_else(|| sanitize_directory_name(&unit.common.name.unwrap_or(name))),
-                mounts: unit.mounts,
-                before: unit.before,
-                winetricks: unit.winetricks,
-                cd: unit.common.cd,
-                command: unit.common.command,
-                wrapper: unit.common.wrapper,
+            let prefix = prefix_name(&name, &unit);
+            let prefix_only = args.options.prefix_only || unit.prefix_only;
+            let options = Options {
+                prefix_backups: cfg.prefix_backups,
+                prefix_only,
+                ..options(&mut cfg, args.options)
             };
 
-            brie_wine::launch(&paths, &cfg.tokens.unwrap_or_default(), unit)?;
+            let tokens = cfg.tokens.unwrap_or_default();
+            brie_wine::launch(&paths, &tokens, wine_unit(prefix, unit), &options)?;
         }
     };
 
     Ok(())
 }
 
+/// Applies arguments following the unit name and `--env` overrides to the unit.
+/// The first argument selects a named command when it matches one, the rest are appended to the command.
+fn apply_args(
+    mut unit: brie_cfg::Unit,
+    rest: Vec<String>,
+    env: Vec<(String, String)>,
+) -> brie_cfg::Unit {
+    let mut rest = rest.into_iter().peekable();
+    if let brie_cfg::Unit::Wine(unit) = &mut unit {
+        let command = rest
+            .peek()
+            .and_then(|name| unit.commands.shift_remove(name));
+        if let Some(command) = command {
+            rest.next();
+            unit.common.command = command;
+        }
+    }
+
+    unit.common_mut().command.extend(rest);
+    unit.common_mut().env.extend(env);
+    unit
+}
+
+fn wine_unit(prefix: String, unit: brie_cfg::WineUnit) -> Unit {
+    Unit {
+        runtime: unit.runtime,
+        libraries: unit.libraries,
+        arch: unit.arch,
+        link_dlls: unit.link_dlls,
+        exclude_dlls: unit.exclude_dlls,
+        env: unit.common.env,
+        path: unit.path,
+        library_paths: unit.library_paths,
+        prefix,
+        link_home_dirs: unit.link_home_dirs,
+        user: unit.user,
+        mounts: unit.mounts,
+        before: unit.before,
+        before_winetricks: unit.before_winetricks,
+        winetricks: unit.winetricks,
+        winetricks_settings: unit.winetricks_settings,
+        registry: unit.registry,
+        dpi: unit.dpi,
+        install: unit.install,
+        nvidia: unit.nvidia,
+        vulkan_device: unit.vulkan_device,
+        shader_cache: unit.shader_cache,
+        display: unit.display,
+        drive_c: unit.drive_c,
+        background: unit.background,
+        min_free_space: unit.min_free_space,
+        refuse_low_space: unit.refuse_low_space,
+        cd: unit.common.cd,
+        command: unit.common.command,
+        wrapper: unit.common.wrapper,
+        nice: unit.common.nice,
+        ionice: unit.common.ionice,
+        debug: unit.common.debug,
+    }
+}
+
+/// Name of the unit prefix directory, derived from the unit name unless set explicitly
+fn prefix_name(key: &str, unit: &brie_cfg::WineUnit) -> String {
+    match &unit.prefix {
+        Some(prefix) => prefix.clone(),
+        None => sanitize_directory_name(unit.common.name.as_deref().unwrap_or(key)),
+    }
+}
+
 fn sanitize_directory_name(dir_name: &str) -> String {
     static ILLEGAL: &[char] = &['/', '\\', ':', '*', '?', '"', '<', '>', '|'];
-    dir_name
+    static RESERVED: &[&str] = &[
+        "con", "prn", "aux", "nul", "com1", "com2", "com3", "com4", "com5", "com6", "com7", "com8",
+        "com9", "lpt1", "lpt2", "lpt3", "lpt4", "lpt5", "lpt6", "lpt7", "lpt8", "lpt9",
+    ];
+    const MAX_LENGTH: usize = 128;
+    const FALLBACK: &str = "unnamed";
+
+    let is_trimmed = |c: char| c == '.' || c.is_whitespace();
+
+    let name = dir_name
+        .chars()
+        .filter(|&c| !ILLEGAL.contains(&c) && !c.is_control())
+        .collect::<String>();
+
+    // Leading dots would either hide the directory or make it point to a parent directory
+    let name = name
+        .trim_start_matches(is_trimmed)
         .chars()
-        .filter(|&c| !ILLEGAL.contains(&c))
-        .collect()
+        .take(MAX_LENGTH)
+        .collect::<String>();
+    let mut name = name.trim_end_matches(is_trimmed).to_owned();
+
+    if name.is_empty() {
+        return FALLBACK.to_owned();
+    }
+
+    // Device names are not allowed on Windows, even with an extension
+    let stem = name.split('.').next().unwrap_or_default();
+    if RESERVED.iter().any(|r| r.eq_ignore_ascii_case(stem)) {
+        let end = stem.len();
+        name.insert(end, '_');
+    }
+
+    name
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{sanitize_directory_name, Args, Error};
+
+    #[test]
+    fn env_args() {
+        let args = ["--env", "A=1", "--env", "B=x=y", "--env", "C=", "unit"];
+        let args = Args::parse(args.into_iter().map(String::from)).unwrap();
+        assert_eq!(
+            args.env,
+            [("A", "1"), ("B", "x=y"), ("C", "")].map(|(k, v)| (k.to_owned(), v.to_owned()))
+        );
+        assert_eq!(args.unit.as_deref(), Some("unit"));
+
+        for invalid in ["A", "=1"] {
+            let args = ["--env", invalid].into_iter().map(String::from);
+            assert!(matches!(
+                Args::parse(args),
+                Err(Error::InvalidValue("--env", value)) if value == invalid
+            ));
+        }
+    }
+
+    #[test]
+    fn sanitize() {
+        assert_eq!(
+            sanitize_directory_name("The Witcher 3: Wild Hunt"),
+            "The Witcher 3 Wild Hunt"
+        );
+        assert_eq!(sanitize_directory_name(".."), "unnamed");
+        assert_eq!(sanitize_directory_name("."), "unnamed");
+        assert_eq!(sanitize_directory_name("../../etc"), "etc");
+        assert_eq!(sanitize_directory_name(" .hidden. "), "hidden");
+        assert_eq!(sanitize_directory_name("con"), "con_");
+        assert_eq!(sanitize_directory_name("LPT1.txt"), "LPT1_.txt");
+        assert_eq!(sanitize_directory_name("console"), "console");
+        assert_eq!(sanitize_directory_name("🧀🍷"), "🧀🍷");
+        assert_eq!(sanitize_directory_name("<>:|?*"), "unnamed");
+        assert_eq!(sanitize_directory_name(""), "unnamed");
+        assert_eq!(sanitize_directory_name(&"a".repeat(1000)).len(), 128);
+    }
 }
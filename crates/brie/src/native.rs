@@ -2,12 +2,14 @@ use std::{
     borrow::Cow,
     env::VarError,
     io,
-    path::Path,
+    os::unix::fs::PermissionsExt,
+    path::{Path, PathBuf},
     process::{Command, Stdio},
 };
 
-use brie_cfg::NativeUnit;
-use log::debug;
+use brie_cfg::{NativeUnit, SearchPath};
+use indexmap::IndexMap;
+use log::{debug, info};
 use path_absolutize::Absolutize;
 
 #[derive(thiserror::Error, Debug)]
@@ -21,6 +23,17 @@ pub enum Error {
 }
 
 pub fn launch(unit: NativeUnit) -> Result<(), Error> {
+    let mut command = command(unit)?;
+
+    debug!("Running command: {command:?}");
+    command.status()?;
+
+    Ok(())
+}
+
+/// Builds the command of the unit, run in the expanded `cd` directory
+fn command(unit: NativeUnit) -> Result<Command, Error> {
+    let search_path = unit.path;
     let mut unit = unit.common;
 
     let cd = match unit.cd.as_ref() {
@@ -28,8 +41,22 @@ pub fn launch(unit: NativeUnit) -> Result<(), Error> {
         None => None,
     };
 
+    let path = search_path_env(&search_path, &unit.env)?;
+    if !search_path.prepend.is_empty() || !search_path.append.is_empty() {
+        unit.env.insert("PATH".to_owned(), path.clone());
+    }
+
     match unit.command.first_mut() {
-        Some(command) => *command = resolve(cd.as_deref(), command)?.to_string(),
+        Some(command) => {
+            // Commands relative to `cd` take precedence over the ones found in `PATH`
+            let resolved = resolve(cd.as_deref(), command)?.into_owned();
+            *command = match Path::new(&resolved).is_file() {
+                true => resolved,
+                false => {
+                    lookup(command, &path).map_or(resolved, |p| p.to_string_lossy().to_string())
+                }
+            };
+        }
         None => {
             return Err(Error::EmptyCommand);
         }
@@ -50,10 +77,52 @@ pub fn launch(unit: NativeUnit) -> Result<(), Error> {
         .stderr(Stdio::inherit())
         .envs(&unit.env);
 
-    debug!("Running command: {command:?}");
-    command.status()?;
+    brie_wine::set_priority(&mut command, unit.nice, unit.ionice);
 
-    Ok(())
+    if unit.debug {
+        brie_wine::log_env(&unit.env);
+        info!("Command: {args:?}");
+    }
+
+    Ok(command)
+}
+
+/// Builds `PATH` for the command from the unit `path` entries around `PATH`
+/// from the unit environment, or the brie environment if not set by the unit
+fn search_path_env(
+    search_path: &SearchPath,
+    env: &IndexMap<String, String>,
+) -> Result<String, Error> {
+    let system_path = match env.get("PATH") {
+        Some(path) => Some(path.clone()),
+        None => std::env::var_os("PATH").and_then(|p| p.into_string().ok()),
+    };
+
+    let mut path = Vec::new();
+    for entry in &search_path.prepend {
+        path.push(shellexpand::full(entry)?.into_owned());
+    }
+    path.extend(system_path);
+    for entry in &search_path.append {
+        path.push(shellexpand::full(entry)?.into_owned());
+    }
+
+    Ok(path.join(":"))
+}
+
+/// Finds an executable by its name in the `PATH` entries. Commands with a path are not looked up.
+fn lookup(command: &str, path: &str) -> Option<PathBuf> {
+    if command.contains('/') {
+        return None;
+    }
+
+    path.split(':')
+        .filter(|dir| !dir.is_empty())
+        .map(|dir| Path::new(dir).join(command))
+        .find(|path| {
+            path.metadata()
+                .is_ok_and(|m| m.is_file() && m.permissions().mode() & 0o111 != 0)
+        })
 }
 
 fn resolve<'a>(cd: Option<&str>, command: &'a str) -> Result<Cow<'a, str>, Error> {
@@ -73,7 +142,11 @@ fn resolve<'a>(cd: Option<&str>, command: &'a str) -> Result<Cow<'a, str>, Error
 
 #[cfg(test)]
 mod tests {
-    use super::resolve;
+    use std::path::Path;
+
+    use brie_cfg::{NativeUnit, UnitCommon};
+
+    use super::{command, lookup, resolve};
 
     #[test]
     pub fn resolve_global() {
@@ -85,4 +158,32 @@ mod tests {
         assert_eq!(resolve(Some("/a"), "./ls").unwrap(), "/a/ls");
         assert_eq!(resolve(Some("/a/b"), "../ls").unwrap(), "/a/ls");
     }
+
+    #[test]
+    pub fn lookup_path() {
+        assert_eq!(
+            lookup("sh", "/nonexistent::/bin").unwrap(),
+            Path::new("/bin/sh")
+        );
+        assert_eq!(lookup("sh", "/nonexistent"), None);
+        assert_eq!(lookup("./sh", "/bin"), None);
+    }
+
+    #[test]
+    pub fn expanded_cd() {
+        let home = std::env::var("HOME").unwrap();
+        let unit = NativeUnit {
+            common: UnitCommon {
+                cd: Some("~/games".into()),
+                command: vec!["./game".into()],
+                ..UnitCommon::default()
+            },
+            ..NativeUnit::default()
+        };
+
+        let command = command(unit).unwrap();
+        let cd = Path::new(&home).join("games");
+        assert_eq!(command.get_current_dir(), Some(cd.as_path()));
+        assert_eq!(command.get_program(), cd.join("game"));
+    }
 }
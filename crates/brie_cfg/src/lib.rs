@@ -1,32 +1,161 @@
-use std::{io, path::PathBuf};
+use std::{borrow::Cow, fmt, io, path::PathBuf, str::FromStr};
 
 use indexmap::IndexMap;
+use log::warn;
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 use serde_with::{formats::PreferOne, serde_as, OneOrMany};
 
-#[derive(Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub use schema::schema;
+
+mod schema;
+
+#[derive(Default, Debug, Serialize, Deserialize, PartialEq, Eq, JsonSchema)]
 pub struct Brie {
     pub tokens: Option<Tokens>,
 
     #[serde(default)]
     pub paths: Paths,
+    #[serde(default)]
+    pub tools: Tools,
+    /// Commands run in the directory of a downloaded library or runtime after it is extracted,
+    /// keyed by the directory name in the libraries folder, e.g. `dxvk-gplasync` or `wine-ge-custom`
+    #[serde(default)]
+    pub post_extract: IndexMap<String, Vec<Vec<String>>>,
+    /// Wait for a key press before exiting when `brie` fails, same as `--pause-on-error`
+    #[serde(default)]
+    pub pause_on_error: bool,
+    /// Timeout of steamgriddb API requests in seconds, 30 by default
+    pub steamgriddb_timeout: Option<u64>,
+    /// Seconds between checks for new releases of `latest` runtimes and libraries,
+    /// a day by default. `0` never checks once a version is downloaded
+    pub latest_check_interval: Option<u64>,
+    /// Headers added to every download, e.g. for mirrors behind an authenticating proxy.
+    /// Values are secrets like tokens, headers with unset environment variables are skipped.
+    /// A github token takes precedence over an `Authorization` header for github downloads
+    #[serde(default, deserialize_with = "secret_map")]
+    #[schemars(with = "IndexMap<String, Secret>")]
+    pub download_headers: IndexMap<String, String>,
+    /// Convert downloaded icons to multi-resolution `.ico` files,
+    /// which are then used by desktop entries and steam shortcuts
+    #[serde(default)]
+    pub ico_icons: bool,
+    /// Number of registry backups kept in `.backups` of a wine prefix, taken before the prefix
+    /// is updated to another wine version. Disabled with 0, which is the default
+    #[serde(default)]
+    pub prefix_backups: usize,
     pub units: IndexMap<String, Unit>,
 }
 
-#[derive(Default, Debug, Serialize, Deserialize, PartialEq, Eq)]
+/// API tokens. Each token is either the token itself, `{ env: VAR }` or `{ file: path }`,
+/// which are resolved when the config is read
+#[derive(Default, Debug, Serialize, Deserialize, PartialEq, Eq, JsonSchema)]
 pub struct Tokens {
-    pub steamgriddb: Option<String>,
+    /// One or more tokens, the next one is used while a token is rate limited.
+    /// Tokens read from unset environment variables are skipped
+    #[serde(default, deserialize_with = "secrets")]
+    #[schemars(with = "Option<schema::OneOrMany<Secret>>")]
+    pub steamgriddb: Vec<String>,
+    #[serde(default, deserialize_with = "secret")]
+    #[schemars(with = "Option<Secret>")]
     pub github: Option<String>,
 }
 
-#[derive(Default, Debug, Serialize, Deserialize, PartialEq, Eq)]
+/// Where a secret value is read from
+#[derive(Deserialize, JsonSchema)]
+#[serde(untagged)]
+enum Secret {
+    Plain(String),
+    /// An environment variable, the token is not set if the variable is not set
+    Env {
+        env: String,
+    },
+    /// A file with the token, `~` is expanded and surrounding whitespace is trimmed
+    File {
+        file: String,
+    },
+}
+
+impl Secret {
+    fn resolve<E: serde::de::Error>(self) -> Result<Option<String>, E> {
+        match self {
+            Self::Plain(value) => Ok(Some(value)),
+            Self::Env { env } => Ok(std::env::var(env).ok()),
+            Self::File { file } => std::fs::read_to_string(&*shellexpand::tilde(&file))
+                .map(|token| Some(token.trim().to_owned()))
+                .map_err(|e| E::custom(format!("Unable to read token from `{file}`. {e}"))),
+        }
+    }
+}
+
+/// A single secret or a list of them
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Secrets {
+    One(Secret),
+    Many(Vec<Secret>),
+}
+
+fn secret<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<Option<String>, D::Error> {
+    match Option::<Secret>::deserialize(deserializer)? {
+        Some(secret) => secret.resolve(),
+        None => Ok(None),
+    }
+}
+
+fn secrets<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<Vec<String>, D::Error> {
+    let secrets = match Option::<Secrets>::deserialize(deserializer)? {
+        Some(Secrets::One(secret)) => vec![secret],
+        Some(Secrets::Many(secrets)) => secrets,
+        None => Vec::new(),
+    };
+
+    let mut resolved = Vec::with_capacity(secrets.len());
+    for secret in secrets {
+        resolved.extend(secret.resolve()?);
+    }
+    Ok(resolved)
+}
+
+fn secret_map<'de, D: serde::Deserializer<'de>>(
+    deserializer: D,
+) -> Result<IndexMap<String, String>, D::Error> {
+    let mut resolved = IndexMap::new();
+    for (key, secret) in IndexMap::<String, Secret>::deserialize(deserializer)? {
+        if let Some(value) = secret.resolve()? {
+            resolved.insert(key, value);
+        }
+    }
+    Ok(resolved)
+}
+
+#[derive(Default, Debug, Serialize, Deserialize, PartialEq, Eq, JsonSchema)]
 pub struct Paths {
     pub steam_config: Option<String>,
     pub sunshine: Option<String>,
     pub desktop: Option<String>,
+    /// Directory of downloaded runtimes and libraries, `libraries` in the data directory by default
+    pub libraries: Option<String>,
+    /// Directory of wine prefixes, `prefixes` in the data directory by default
+    pub prefixes: Option<String>,
+}
+
+/// Helper tool settings. Latest tool versions are used unless pinned
+#[derive(Default, Debug, Serialize, Deserialize, PartialEq, Eq, JsonSchema)]
+pub struct Tools {
+    /// Winetricks git ref, e.g. a release tag like `20240105`
+    pub winetricks: Option<String>,
+    /// Arch Linux cabextract package version, e.g. `1.11-2`
+    pub cabextract: Option<String>,
+    /// Cache winetricks downloads in the libraries directory, shared by all prefixes,
+    /// instead of `$XDG_CACHE_HOME/winetricks`
+    #[serde(default)]
+    pub shared_winetricks_cache: bool,
 }
 
-#[derive(Serialize, Deserialize, Copy, Clone, PartialEq, Eq, Hash, PartialOrd, Ord, Debug)]
+#[derive(
+    Serialize, Deserialize, Copy, Clone, PartialEq, Eq, Hash, PartialOrd, Ord, Debug, JsonSchema,
+)]
 #[serde(rename_all = "kebab-case")]
 pub enum Library {
     Dxvk,
@@ -36,22 +165,212 @@ pub enum Library {
     Vkd3dProton,
 }
 
-#[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
+impl Library {
+    pub const ALL: [Library; 5] = [
+        Self::Dxvk,
+        Self::DxvkGplAsync,
+        Self::DxvkNvapi,
+        Self::NvidiaLibs,
+        Self::Vkd3dProton,
+    ];
+
+    /// Name of the library as used in the config
+    #[must_use]
+    pub fn to_str(self) -> &'static str {
+        match self {
+            Self::Dxvk => "dxvk",
+            Self::DxvkGplAsync => "dxvk-gpl-async",
+            Self::DxvkNvapi => "dxvk-nvapi",
+            Self::NvidiaLibs => "nvidia-libs",
+            Self::Vkd3dProton => "vkd3d-proton",
+        }
+    }
+
+    /// Groups of libraries which install the same dlls, e.g. d3d9-d3d11 and dxgi for both DXVK builds
+    const EXCLUSIVE: [&'static [Library]; 1] = [&[Self::Dxvk, Self::DxvkGplAsync]];
+
+    /// Returns the libraries from each mutually exclusive group which are all present in `libraries`.
+    /// The dlls and overrides of whichever of them is installed last are used
+    #[must_use]
+    pub fn conflicts(libraries: &[Library]) -> Vec<Vec<Library>> {
+        Self::EXCLUSIVE
+            .iter()
+            .map(|group| {
+                group
+                    .iter()
+                    .copied()
+                    .filter(|l| libraries.contains(l))
+                    .collect::<Vec<_>>()
+            })
+            .filter(|present| present.len() > 1)
+            .collect()
+    }
+}
+
+impl FromStr for Library {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::ALL
+            .into_iter()
+            .find(|l| l.to_str() == s)
+            .ok_or_else(|| Error::UnknownLibrary(s.to_owned()))
+    }
+}
+
+/// Architecture of library dlls installed to the wine prefix
+#[derive(Serialize, Deserialize, Copy, Clone, PartialEq, Eq, Hash, Debug, JsonSchema)]
 #[serde(rename_all = "kebab-case")]
+pub enum Arch {
+    X64,
+    X86,
+}
+
+impl Arch {
+    fn all() -> Vec<Self> {
+        vec![Self::X64, Self::X86]
+    }
+}
+
+/// Release version, either `latest`, a release before the latest one as `latest-<n>`, or a tag
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(from = "String", into = "String")]
 pub enum ReleaseVersion {
-    #[serde(alias = "*")]
     Latest,
-    #[serde(untagged)]
+    /// The n-th release before the latest one
+    LatestOffset(u32),
     Tag(String),
 }
 
 impl ReleaseVersion {
     #[must_use]
-    pub fn to_str(&self) -> &str {
+    pub fn to_str(&self) -> Cow<'_, str> {
         match self {
-            Self::Latest => "latest",
-            Self::Tag(tag) => tag,
+            Self::Latest => Cow::Borrowed("latest"),
+            Self::LatestOffset(offset) => Cow::Owned(format!("latest-{offset}")),
+            Self::Tag(tag) => Cow::Borrowed(tag),
+        }
+    }
+
+    /// Whether the version is resolved relative to the latest release, and may change over time
+    #[must_use]
+    pub fn is_latest(&self) -> bool {
+        self.latest_offset().is_some()
+    }
+
+    /// Number of releases between the latest release and this one, `None` for tags
+    #[must_use]
+    pub fn latest_offset(&self) -> Option<u32> {
+        match self {
+            Self::Latest => Some(0),
+            Self::LatestOffset(offset) => Some(*offset),
+            Self::Tag(_) => None,
+        }
+    }
+}
+
+impl From<String> for ReleaseVersion {
+    fn from(version: String) -> Self {
+        if version == "latest" || version == "*" {
+            return Self::Latest;
+        }
+
+        match version.strip_prefix("latest-").map(str::parse) {
+            Some(Ok(0)) => Self::Latest,
+            Some(Ok(offset)) => Self::LatestOffset(offset),
+            _ => Self::Tag(version),
+        }
+    }
+}
+
+impl From<ReleaseVersion> for String {
+    fn from(version: ReleaseVersion) -> Self {
+        version.to_str().into_owned()
+    }
+}
+
+/// Size in bytes, either a number of bytes or a number with a `K`, `M`, `G` or `T` suffix, e.g. `20G`.
+/// Suffixes are powers of 1024, and may be followed by `B` or `iB`
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(try_from = "RawByteSize", into = "u64")]
+pub struct ByteSize(pub u64);
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum RawByteSize {
+    Bytes(u64),
+    Text(String),
+}
+
+impl TryFrom<RawByteSize> for ByteSize {
+    type Error = Error;
+
+    fn try_from(size: RawByteSize) -> Result<Self, Self::Error> {
+        match size {
+            RawByteSize::Bytes(bytes) => Ok(Self(bytes)),
+            RawByteSize::Text(text) => text.parse(),
+        }
+    }
+}
+
+impl FromStr for ByteSize {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || Error::InvalidSize(s.to_owned());
+
+        let s = s.trim();
+        let split = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+        let (number, suffix) = s.split_at(split);
+        let number = number.parse::<u64>().map_err(|_| invalid())?;
+
+        let suffix = suffix.trim_start().to_ascii_uppercase();
+        let unit = suffix
+            .strip_suffix("IB")
+            .or_else(|| suffix.strip_suffix('B'))
+            .unwrap_or(&suffix);
+        let exponent = match unit {
+            "" => 0,
+            "K" => 1,
+            "M" => 2,
+            "G" => 3,
+            "T" => 4,
+            _ => return Err(invalid()),
+        };
+
+        number
+            .checked_mul(1024u64.pow(exponent))
+            .map(Self)
+            .ok_or_else(invalid)
+    }
+}
+
+impl From<ByteSize> for u64 {
+    fn from(size: ByteSize) -> Self {
+        size.0
+    }
+}
+
+impl fmt::Display for ByteSize {
+    #[allow(clippy::cast_precision_loss)]
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        const UNITS: [&str; 4] = ["KiB", "MiB", "GiB", "TiB"];
+
+        if self.0 < 1024 {
+            return write!(f, "{} B", self.0);
+        }
+
+        let mut size = self.0 as f64;
+        let mut unit = "B";
+        for next in UNITS {
+            if size < 1024.0 {
+                break;
+            }
+            size /= 1024.0;
+            unit = next;
         }
+
+        write!(f, "{size:.2} {unit}")
     }
 }
 
@@ -84,53 +403,229 @@ impl Unit {
 }
 
 #[serde_as]
-#[derive(Debug, Default, Serialize, Deserialize, PartialEq, Eq)]
+#[derive(Debug, Default, Serialize, Deserialize, PartialEq, Eq, JsonSchema)]
 pub struct UnitCommon {
     pub name: Option<String>,
     pub steamgriddb_id: Option<u32>,
+    /// Search query for steamgriddb, when the display `name` doesn't match any title
+    pub steamgriddb_query: Option<String>,
     pub cd: Option<String>,
     #[serde_as(deserialize_as = "OneOrMany<_, PreferOne>")]
+    #[schemars(with = "schema::OneOrMany<String>")]
     pub command: Vec<String>,
     #[serde(default)]
     pub env: IndexMap<String, String>,
     #[serde(default)]
     pub generate: Generate,
+    /// Arguments passed to `brie` after the unit key in generated desktop files, sunshine apps
+    /// and steam shortcuts, e.g. a named command
+    #[serde(default)]
+    pub launch_args: Vec<String>,
     #[serde(default)]
     #[serde_as(deserialize_as = "OneOrMany<_, PreferOne>")]
+    #[schemars(with = "schema::OneOrMany<String>")]
     pub wrapper: Vec<String>,
+    /// Nice level of the launched process, from -20 (highest priority) to 19 (lowest).
+    /// Negative values require privileges
+    #[serde(default)]
+    pub nice: Option<i32>,
+    /// Best-effort IO priority level of the launched process, from 0 (highest) to 7 (lowest)
+    #[serde(default)]
+    pub ionice: Option<u8>,
+    /// Log the launch at trace level, including the full environment and command
+    #[serde(default)]
+    pub debug: bool,
 }
 
-#[derive(Debug, Default, Serialize, Deserialize, PartialEq, Eq)]
+#[serde_as]
+#[derive(Debug, Default, Serialize, Deserialize, PartialEq, Eq, JsonSchema)]
 pub struct WineUnit {
     #[serde(flatten)]
     pub common: UnitCommon,
 
+    /// Name of the wine prefix directory, the unit key by default. Units with the same `prefix`
+    /// share it, and setup steps already applied to it by another unit are skipped
     #[serde(default)]
     pub prefix: Option<String>,
+    /// Folders in `drive_c/users/<user>` (e.g. `Documents`) which are kept as symlinks
+    /// to the home directory when the prefix is created. All of them are replaced
+    /// with directories by default
+    #[serde(default)]
+    pub link_home_dirs: Vec<String>,
+    /// User name inside the wine prefix, e.g. `steamuser`. The host user name is used by default
     #[serde(default)]
-    pub winetricks: Vec<String>,
+    pub user: Option<String>,
+    /// Winetricks verbs, each either a single verb or a full list of arguments
+    #[serde(default)]
+    #[serde_as(deserialize_as = "Vec<OneOrMany<_, PreferOne>>")]
+    #[schemars(with = "Vec<schema::OneOrMany<String>>")]
+    pub winetricks: Vec<Vec<String>>,
+    /// Winetricks settings, e.g. `win10` or `fontsmooth=rgb`, run as `winetricks settings <setting>`.
+    /// Settings are applied again when they change, unlike verbs, which are only installed once
+    #[serde(default)]
+    pub winetricks_settings: Vec<String>,
     #[serde(default)]
     pub mounts: IndexMap<char, String>,
+    /// Commands run once the prefix is set up, after winetricks, mounts and libraries
     #[serde(default)]
     pub before: Vec<Vec<String>>,
+    /// Commands run right after the prefix is created or updated, before winetricks
+    #[serde(default)]
+    pub before_winetricks: Vec<Vec<String>>,
     #[serde(default)]
     pub runtime: Runtime,
     #[serde(default)]
     pub libraries: IndexMap<Library, ReleaseVersion>,
+    /// Architectures of library dlls to install, both by default
+    #[serde(default = "Arch::all")]
+    pub arch: Vec<Arch>,
+    /// Symlink library dlls from the libraries directory instead of copying them to the prefix
+    #[serde(default)]
+    pub link_dlls: bool,
+    /// Library dlls to skip, e.g. `d3d9` to keep the wine builtin one. They are not overridden either
+    #[serde(default)]
+    pub exclude_dlls: Vec<String>,
+    #[serde(default)]
+    pub path: SearchPath,
+    /// Host directories with shared libraries for the wine process, appended to `LD_LIBRARY_PATH`
+    #[serde(default)]
+    pub library_paths: Vec<String>,
+    #[serde(default)]
+    pub registry: Vec<RegEntry>,
+    /// Wine DPI, e.g. 144 for 150% scaling. Applied once as a registry value, like `registry`
+    #[serde(default)]
+    pub dpi: Option<u32>,
+    /// Installer which is run once before the first launch
+    #[serde(default)]
+    pub install: Option<Installer>,
+    /// Additional named commands, selected with `brie <unit> <name>` instead of `command`
+    #[serde(default)]
+    #[serde_as(deserialize_as = "IndexMap<_, OneOrMany<_, PreferOne>>")]
+    #[schemars(with = "IndexMap<String, schema::OneOrMany<String>>")]
+    pub commands: IndexMap<String, Vec<String>>,
+    /// Only prepare the wine prefix when the unit is launched, same as `brie --prefix-only`.
+    /// Units without a `command` are expected to set it, e.g. a prefix which is only used to install into
+    #[serde(default)]
+    pub prefix_only: bool,
+    /// Install NVIDIA specific libraries (`dxvk-nvapi`, `nvidia-libs` and nvngx dlls) even without
+    /// an NVIDIA GPU with `true`, or skip them with `false`. Detected from the GPU by default
+    #[serde(default)]
+    pub nvidia: Option<bool>,
+    /// Vulkan device to run on, either a `vendorID:deviceID` pair in hex, or a part of the device name
+    #[serde(default)]
+    pub vulkan_device: Option<String>,
+    /// Persistent shader cache directory of NVIDIA, DXVK and mesa drivers.
+    /// Relative paths are relative to `shader-cache` in the data directory
+    #[serde(default)]
+    pub shader_cache: Option<String>,
+    /// Display server the unit is run with, on a Wayland session
+    #[serde(default)]
+    pub display: Option<DisplayBackend>,
+    /// Volume label and serial number reported for `c:`, for games checking them for copy protection
+    #[serde(default)]
+    pub drive_c: Option<DriveInfo>,
+    /// Return right after starting the command, without waiting for it and for wineserver to exit.
+    /// The command is detached into its own process group, e.g. for launchers which exit immediately
+    #[serde(default)]
+    pub background: bool,
+    /// Free space needed on the filesystem of the wine prefix, checked before launch, e.g. `20G`
+    #[serde(default)]
+    pub min_free_space: Option<ByteSize>,
+    /// Refuse to launch when there is less than `min_free_space` free, instead of only warning
+    #[serde(default)]
+    pub refuse_low_space: bool,
+}
+
+/// Display server backend of a wine unit
+#[derive(Debug, Default, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum DisplayBackend {
+    /// Leave the environment as it is
+    #[default]
+    Auto,
+    Wayland,
+    /// Force XWayland on a Wayland session
+    X11,
+}
+
+/// Volume information of a wine drive
+#[derive(Debug, Default, Clone, Serialize, Deserialize, PartialEq, Eq, JsonSchema)]
+pub struct DriveInfo {
+    pub label: Option<String>,
+    /// Volume serial number in hex, e.g. `1234-ABCD`. Normalized to `1234ABCD` when the config is read
+    pub serial: Option<String>,
+}
+
+/// A windows installer run in the wine prefix
+#[serde_as]
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, JsonSchema)]
+pub struct Installer {
+    /// Path to the installer executable
+    pub exe: String,
+    /// Arguments for an unattended installation, e.g. `/VERYSILENT` for Inno Setup installers
+    #[serde(default)]
+    #[serde_as(deserialize_as = "OneOrMany<_, PreferOne>")]
+    #[schemars(with = "schema::OneOrMany<String>")]
+    pub silent_args: Vec<String>,
+}
+
+/// Additional entries for the `PATH` environment variable.
+#[derive(Debug, Default, Serialize, Deserialize, PartialEq, Eq, JsonSchema)]
+pub struct SearchPath {
+    /// Entries added before all other entries, including the runtime directory
+    #[serde(default)]
+    pub prepend: Vec<String>,
+    /// Entries added after all other entries
+    #[serde(default)]
+    pub append: Vec<String>,
+}
+
+/// A registry value set in the wine prefix
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, JsonSchema)]
+pub struct RegEntry {
+    /// Registry key, e.g. `HKCU\Software\Wine\Direct3D`
+    pub key: String,
+    /// Value name. The default value of the key is used when omitted
+    pub name: Option<String>,
+    #[serde(default, rename = "type")]
+    pub kind: RegType,
+    /// Value data. Decimal or `0x`-prefixed hex number for `dword`, hex bytes for `binary`
+    #[serde(default)]
+    pub data: String,
 }
 
-#[derive(Debug, Default, Serialize, Deserialize, PartialEq, Eq)]
+#[derive(Debug, Default, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, JsonSchema)]
+#[serde(rename_all = "kebab-case")]
+pub enum RegType {
+    #[default]
+    String,
+    Dword,
+    Binary,
+    /// Removes the value
+    Delete,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize, PartialEq, Eq, JsonSchema)]
 pub struct NativeUnit {
     #[serde(flatten)]
     pub common: UnitCommon,
+
+    #[serde(default)]
+    pub path: SearchPath,
 }
 
-#[derive(Debug, Serialize, Deserialize, PartialEq, Eq)]
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, JsonSchema)]
 #[serde(rename_all = "kebab-case", tag = "kind")]
 pub enum Runtime {
     System { path: Option<PathBuf> },
     GeProton { version: ReleaseVersion },
     Tkg { version: ReleaseVersion },
+    Proton { path: PathBuf },
+}
+
+impl Runtime {
+    /// Names of supported runtime kinds as used in the config
+    pub const KINDS: [&'static str; 4] = ["system", "ge-proton", "tkg", "proton"];
 }
 
 impl Default for Runtime {
@@ -139,7 +634,7 @@ impl Default for Runtime {
     }
 }
 
-#[derive(Debug, Default, Serialize, Deserialize, PartialEq, Eq)]
+#[derive(Debug, Default, Serialize, Deserialize, PartialEq, Eq, JsonSchema)]
 pub struct Generate {
     #[serde(default)]
     pub sunshine: bool,
@@ -157,6 +652,34 @@ pub enum Error {
     Yaml(#[from] serde_yaml::Error),
     #[error("Config file does not exist at `{0}`")]
     NoConfig(PathBuf),
+    #[error("Invalid mount `{1}` in unit `{0}`. Mounts must be drive letters `a`-`z`.")]
+    InvalidMount(String, char),
+    #[error("Duplicate mount `{1}` in unit `{0}`. Drive letters are case-insensitive.")]
+    DuplicateMount(String, char),
+    #[error("Invalid user name `{1}` in unit `{0}`. User names must be non-empty and can't contain path separators.")]
+    InvalidUser(String, String),
+    #[error("Invalid `{1}` value `{2}` in unit `{0}`. Allowed values are {3}.")]
+    InvalidPriority(String, &'static str, i32, &'static str),
+    #[error("Invalid drive serial `{1}` in unit `{0}`. Serials must be up to 8 hex digits, e.g. `1234-ABCD`.")]
+    InvalidSerial(String, String),
+    #[error("Invalid size `{0}`. Sizes are a number of bytes, or a number with a `K`, `M`, `G` or `T` suffix.")]
+    InvalidSize(String),
+    #[error("Unknown library `{0}`.")]
+    UnknownLibrary(String),
+    #[error("Unsupported key `{0}` in `defaults`. Supported keys are `runtime`, `libraries`, `winetricks` and `env`.")]
+    UnknownDefault(String),
+}
+
+/// Environment variable which overrides the default config file location
+pub const CONFIG_ENV: &str = "BRIE_CONFIG";
+
+/// Resolves the config file location. An explicitly provided path takes precedence
+/// over the `BRIE_CONFIG` environment variable, which takes precedence over the default.
+#[must_use]
+pub fn config_path(explicit: Option<PathBuf>, default: impl FnOnce() -> PathBuf) -> PathBuf {
+    explicit
+        .or_else(|| std::env::var_os(CONFIG_ENV).map(PathBuf::from))
+        .unwrap_or_else(default)
 }
 
 pub fn read(path: PathBuf) -> Result<Brie, Error> {
@@ -172,17 +695,152 @@ pub fn read(path: PathBuf) -> Result<Brie, Error> {
     cfg.apply_merge()?;
     cfg.apply_merge()?;
     cfg.apply_merge()?;
+    apply_defaults(&mut cfg)?;
 
-    let cfg: Brie = serde_yaml::from_value(cfg)?;
+    let mut cfg: Brie = serde_yaml::from_value(cfg)?;
+    validate(&mut cfg)?;
 
     Ok(cfg)
 }
 
+/// Wine unit keys which can be set in the top-level `defaults` block
+const DEFAULT_KEYS: &[&str] = &["runtime", "libraries", "winetricks", "env"];
+
+/// Moves values from the top-level `defaults` block into each wine unit.
+/// Values set in the unit take precedence, `libraries` and `env` are merged per key.
+fn apply_defaults(cfg: &mut serde_yaml::Value) -> Result<(), Error> {
+    use serde_yaml::Value;
+
+    let defaults: serde_yaml::Mapping =
+        match cfg.as_mapping_mut().and_then(|cfg| cfg.remove("defaults")) {
+            Some(Value::Null) | None => return Ok(()),
+            Some(defaults) => serde_yaml::from_value(defaults)?,
+        };
+
+    for key in defaults.keys() {
+        match key.as_str() {
+            Some(key) if DEFAULT_KEYS.contains(&key) => {}
+            key => return Err(Error::UnknownDefault(key.unwrap_or_default().to_owned())),
+        }
+    }
+
+    let Some(units) = cfg.get_mut("units").and_then(Value::as_mapping_mut) else {
+        return Ok(());
+    };
+
+    for unit in units.values_mut() {
+        let Some(unit) = unit.as_mapping_mut() else {
+            continue;
+        };
+        if unit.get("kind").and_then(Value::as_str) == Some("native") {
+            continue;
+        }
+
+        for (key, value) in &defaults {
+            match (unit.get_mut(key), value) {
+                (Some(Value::Mapping(current)), Value::Mapping(value)) if key != "runtime" => {
+                    for (k, v) in value {
+                        if !current.contains_key(k) {
+                            current.insert(k.clone(), v.clone());
+                        }
+                    }
+                }
+                (Some(_), _) => {}
+                (None, _) => {
+                    unit.insert(key.clone(), value.clone());
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Checks unit fields which can't be expressed by types and normalizes them.
+fn validate(cfg: &mut Brie) -> Result<(), Error> {
+    for (name, unit) in &mut cfg.units {
+        let common = unit.common();
+        if let Some(nice) = common.nice.filter(|n| !(-20..=19).contains(n)) {
+            return Err(Error::InvalidPriority(
+                name.clone(),
+                "nice",
+                nice,
+                "-20 to 19",
+            ));
+        }
+        if let Some(ionice) = common.ionice.filter(|n| *n > 7) {
+            let ionice = i32::from(ionice);
+            return Err(Error::InvalidPriority(
+                name.clone(),
+                "ionice",
+                ionice,
+                "0 to 7",
+            ));
+        }
+
+        let Unit::Wine(unit) = unit else {
+            continue;
+        };
+
+        let libraries = unit.libraries.keys().copied().collect::<Vec<_>>();
+        for conflict in Library::conflicts(&libraries) {
+            let names = conflict.iter().map(|l| l.to_str()).collect::<Vec<_>>();
+            warn!(
+                "Unit `{name}` has conflicting libraries {}, which override the same dlls. \
+                Only the last one is effectively used, keep one of them.",
+                names.join(", ")
+            );
+        }
+
+        if unit.common.command.is_empty() && !unit.prefix_only {
+            warn!(
+                "Unit `{name}` has no `command`, launching it only prepares the wine prefix. \
+                Set `prefix_only: true` if this is intended."
+            );
+        }
+
+        // Wine expects lowercase drive letters in `dosdevices`
+        let mut mounts = IndexMap::with_capacity(unit.mounts.len());
+        for (drive, path) in std::mem::take(&mut unit.mounts) {
+            if !drive.is_ascii_alphabetic() {
+                return Err(Error::InvalidMount(name.clone(), drive));
+            }
+            if mounts.insert(drive.to_ascii_lowercase(), path).is_some() {
+                return Err(Error::DuplicateMount(name.clone(), drive));
+            }
+        }
+        unit.mounts = mounts;
+
+        // The user name is used as a directory name in `drive_c/users`
+        if let Some(user) = &unit.user {
+            if user.is_empty() || user == "." || user == ".." || user.contains(['/', '\\']) {
+                return Err(Error::InvalidUser(name.clone(), user.clone()));
+            }
+        }
+
+        if let Some(serial) = unit.drive_c.as_mut().and_then(|d| d.serial.as_mut()) {
+            let normalized = serial.replace('-', "").to_ascii_uppercase();
+            if normalized.is_empty()
+                || normalized.len() > 8
+                || !normalized.chars().all(|c| c.is_ascii_hexdigit())
+            {
+                return Err(Error::InvalidSerial(name.clone(), serial.clone()));
+            }
+            *serial = normalized;
+        }
+    }
+
+    Ok(())
+}
+
 #[cfg(test)]
 mod tests {
     use pretty_assertions::assert_eq;
 
-    use crate::Brie;
+    use crate::{
+        apply_defaults, validate, Brie, ByteSize, Error, Library, ReleaseVersion, Runtime, Tokens,
+        Unit,
+    };
 
     #[test]
     fn serialize() {
@@ -196,4 +854,256 @@ mod tests {
             include_str!("../tests/test.ron").trim_end()
         );
     }
+
+    #[test]
+    fn validate_mounts() {
+        let parse = |mounts: &str| {
+            let cfg = format!("units: {{ game: {{ command: game.exe, mounts: {mounts} }} }}");
+            let mut cfg: Brie = serde_yaml::from_str(&cfg).unwrap();
+            validate(&mut cfg).map(|()| cfg)
+        };
+
+        let cfg = parse("{ D: /mnt, e: /etc }").unwrap();
+        let Unit::Wine(unit) = &cfg.units["game"] else {
+            panic!("Expected a wine unit");
+        };
+        assert_eq!(unit.mounts.keys().collect::<Vec<_>>(), [&'d', &'e']);
+
+        assert!(matches!(
+            parse("{ '1': /mnt }"),
+            Err(Error::InvalidMount(unit, '1')) if unit == "game"
+        ));
+        assert!(matches!(
+            parse("{ ж: /mnt }"),
+            Err(Error::InvalidMount(_, 'ж'))
+        ));
+        assert!(matches!(
+            parse("{ D: /mnt, d: /etc }"),
+            Err(Error::DuplicateMount(unit, 'd')) if unit == "game"
+        ));
+    }
+
+    #[test]
+    fn validate_user() {
+        let parse = |user: &str| {
+            let cfg = format!("units: {{ game: {{ command: game.exe, user: '{user}' }} }}");
+            let mut cfg: Brie = serde_yaml::from_str(&cfg).unwrap();
+            validate(&mut cfg)
+        };
+
+        assert!(parse("steamuser").is_ok());
+        assert!(matches!(parse(""), Err(Error::InvalidUser(unit, _)) if unit == "game"));
+        assert!(matches!(parse(".."), Err(Error::InvalidUser(..))));
+        assert!(matches!(parse("a/b"), Err(Error::InvalidUser(..))));
+    }
+
+    #[test]
+    fn validate_serial() {
+        let parse = |serial: &str| {
+            let cfg = format!(
+                "units: {{ game: {{ command: game.exe, drive_c: {{ serial: '{serial}' }} }} }}"
+            );
+            let mut cfg: Brie = serde_yaml::from_str(&cfg).unwrap();
+            validate(&mut cfg).map(|()| cfg)
+        };
+
+        let cfg = parse("1234-abcd").unwrap();
+        let Unit::Wine(unit) = &cfg.units["game"] else {
+            panic!("Expected a wine unit");
+        };
+        assert_eq!(
+            unit.drive_c.as_ref().unwrap().serial.as_deref(),
+            Some("1234ABCD")
+        );
+
+        assert!(
+            matches!(parse("12345-6789"), Err(Error::InvalidSerial(unit, _)) if unit == "game")
+        );
+        assert!(matches!(parse("xyz"), Err(Error::InvalidSerial(..))));
+        assert!(matches!(parse(""), Err(Error::InvalidSerial(..))));
+    }
+
+    #[test]
+    fn tokens() {
+        let dir = std::path::Path::new(".tmp").join("tokens");
+        std::fs::create_dir_all(&dir).unwrap();
+        let file = dir.join("github");
+        std::fs::write(&file, "ghp_file\n").unwrap();
+        std::env::set_var("BRIE_TEST_STEAMGRIDDB_TOKEN", "sgdb_env");
+
+        let parse = |tokens: &str| serde_yaml::from_str::<Tokens>(tokens);
+
+        let tokens = parse(&format!(
+            "{{ steamgriddb: {{ env: BRIE_TEST_STEAMGRIDDB_TOKEN }}, github: {{ file: '{}' }} }}",
+            file.display()
+        ))
+        .unwrap();
+        assert_eq!(tokens.steamgriddb, ["sgdb_env"]);
+        assert_eq!(tokens.github.as_deref(), Some("ghp_file"));
+
+        let tokens =
+            parse("{ steamgriddb: plain, github: { env: BRIE_TEST_UNSET_TOKEN } }").unwrap();
+        assert_eq!(tokens.steamgriddb, ["plain"]);
+        assert_eq!(tokens.github, None);
+
+        let tokens = parse(
+            "{ steamgriddb: [first, { env: BRIE_TEST_STEAMGRIDDB_TOKEN }, { env: BRIE_TEST_UNSET_TOKEN }] }",
+        )
+        .unwrap();
+        assert_eq!(tokens.steamgriddb, ["first", "sgdb_env"]);
+
+        assert!(parse("{ github: { file: /nonexistent/token } }").is_err());
+        assert_eq!(parse("{}").unwrap(), Tokens::default());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn release_versions() {
+        let parse = |version: &str| serde_yaml::from_str::<ReleaseVersion>(version).unwrap();
+
+        assert_eq!(parse("latest"), ReleaseVersion::Latest);
+        assert_eq!(parse("'*'"), ReleaseVersion::Latest);
+        assert_eq!(parse("latest-0"), ReleaseVersion::Latest);
+        assert_eq!(parse("latest-2"), ReleaseVersion::LatestOffset(2));
+        assert_eq!(parse("latest-x"), ReleaseVersion::Tag("latest-x".into()));
+        assert_eq!(
+            parse("GE-Proton9-1"),
+            ReleaseVersion::Tag("GE-Proton9-1".into())
+        );
+        assert_eq!(ReleaseVersion::LatestOffset(1).to_str(), "latest-1");
+    }
+
+    #[test]
+    fn byte_sizes() {
+        let parse = |size: &str| serde_yaml::from_str::<ByteSize>(size).map(|s| s.0).ok();
+
+        assert_eq!(parse("1000"), Some(1000));
+        assert_eq!(parse("512K"), Some(512 * 1024));
+        assert_eq!(parse("'20 GiB'"), Some(20 * 1024 * 1024 * 1024));
+        assert_eq!(parse("1mb"), Some(1024 * 1024));
+        assert_eq!(parse("2T"), Some(2 << 40));
+        assert_eq!(parse("1.5G"), None);
+        assert_eq!(parse("G"), None);
+        assert_eq!(parse("10X"), None);
+        assert_eq!(parse("99999999999T"), None);
+
+        assert_eq!(ByteSize(100).to_string(), "100 B");
+        assert_eq!(ByteSize(3 << 29).to_string(), "1.50 GiB");
+    }
+
+    #[test]
+    fn validate_priority() {
+        let parse = |priority: &str| {
+            let cfg = format!("units: {{ game: {{ command: game.exe, {priority} }} }}");
+            let mut cfg: Brie = serde_yaml::from_str(&cfg).unwrap();
+            validate(&mut cfg)
+        };
+
+        assert!(parse("nice: -20, ionice: 0").is_ok());
+        assert!(parse("nice: 19, ionice: 7").is_ok());
+        assert!(matches!(
+            parse("nice: 20"),
+            Err(Error::InvalidPriority(unit, "nice", 20, _)) if unit == "game"
+        ));
+        assert!(matches!(
+            parse("ionice: 8"),
+            Err(Error::InvalidPriority(_, "ionice", 8, _))
+        ));
+    }
+
+    #[test]
+    fn library_names() {
+        for library in Library::ALL {
+            let name = serde_yaml::to_string(&library).unwrap();
+            assert_eq!(name.trim(), library.to_str());
+            assert_eq!(library.to_str().parse::<Library>().unwrap(), library);
+        }
+
+        assert!("dxvk-async".parse::<Library>().is_err());
+    }
+
+    #[test]
+    fn library_conflicts() {
+        let none = Library::conflicts(&[Library::Dxvk, Library::DxvkNvapi, Library::Vkd3dProton]);
+        assert!(none.is_empty());
+
+        let dxvk =
+            Library::conflicts(&[Library::DxvkGplAsync, Library::Vkd3dProton, Library::Dxvk]);
+        assert_eq!(dxvk, [[Library::Dxvk, Library::DxvkGplAsync]]);
+    }
+
+    #[test]
+    fn runtime_kinds() {
+        for kind in Runtime::KINDS {
+            let cfg = format!("{{ kind: {kind}, version: latest, path: /opt/wine }}");
+            assert!(serde_yaml::from_str::<Runtime>(&cfg).is_ok(), "{kind}");
+        }
+    }
+
+    #[test]
+    fn defaults() {
+        let parse = |cfg: &str| {
+            let mut cfg: serde_yaml::Value = serde_yaml::from_str(cfg).unwrap();
+            apply_defaults(&mut cfg).map(|()| serde_yaml::from_value::<Brie>(cfg).unwrap())
+        };
+
+        let cfg = parse(
+            "
+            defaults:
+              runtime: { kind: ge-proton, version: '*' }
+              libraries: { dxvk: '*', vkd3d-proton: '*' }
+              winetricks: [vcrun2019]
+              env: { A: '1', B: '1' }
+            units:
+              game:
+                command: game.exe
+                runtime: { kind: tkg, version: '1' }
+                libraries: { dxvk: '2.3' }
+                env: { B: '2' }
+              soft:
+                command: soft.exe
+                winetricks: []
+              native:
+                kind: native
+                command: native
+            ",
+        )
+        .unwrap();
+
+        let Unit::Wine(game) = &cfg.units["game"] else {
+            panic!("Expected a wine unit");
+        };
+        assert_eq!(
+            game.runtime,
+            Runtime::Tkg {
+                version: ReleaseVersion::Tag("1".into())
+            }
+        );
+        assert_eq!(
+            game.libraries.values().collect::<Vec<_>>(),
+            [&ReleaseVersion::Tag("2.3".into()), &ReleaseVersion::Latest]
+        );
+        assert_eq!(game.winetricks, [["vcrun2019"]]);
+        assert_eq!(game.common.env["A"], "1");
+        assert_eq!(game.common.env["B"], "2");
+
+        let Unit::Wine(soft) = &cfg.units["soft"] else {
+            panic!("Expected a wine unit");
+        };
+        assert_eq!(
+            soft.runtime,
+            Runtime::GeProton {
+                version: ReleaseVersion::Latest
+            }
+        );
+        assert!(soft.winetricks.is_empty());
+
+        assert!(cfg.units["native"].common().env.is_empty());
+
+        assert!(matches!(
+            parse("{ defaults: { prefix: shared }, units: {} }"),
+            Err(Error::UnknownDefault(key)) if key == "prefix"
+        ));
+    }
 }
@@ -0,0 +1,123 @@
+use indexmap::IndexMap;
+use schemars::{gen::SchemaGenerator, schema::RootSchema, JsonSchema};
+
+use crate::{Brie, ByteSize, Library, NativeUnit, ReleaseVersion, Runtime, Unit, WineUnit};
+
+/// Returns the JSON Schema of the config file, for editor completion and validation.
+/// Types which are deserialized in a custom way are described by shadow types in this module.
+#[must_use]
+pub fn schema() -> RootSchema {
+    let mut gen = SchemaGenerator::default();
+    let defaults = gen.subschema_for::<Defaults>();
+    let mut schema = gen.into_root_schema_for::<Brie>();
+
+    // `defaults` is applied to the raw yaml before deserialization, so it's not a part of `Brie`
+    schema
+        .schema
+        .object()
+        .properties
+        .insert("defaults".to_owned(), defaults);
+
+    schema
+}
+
+/// A single value or a list of values
+#[derive(JsonSchema)]
+#[serde(untagged)]
+#[allow(dead_code)]
+pub(crate) enum OneOrMany<T> {
+    One(T),
+    Many(Vec<T>),
+}
+
+/// Values applied to each wine unit. Values set in the unit take precedence,
+/// `libraries` and `env` are merged per key
+#[derive(JsonSchema)]
+#[allow(dead_code)]
+struct Defaults {
+    runtime: Option<Runtime>,
+    libraries: Option<IndexMap<Library, ReleaseVersion>>,
+    winetricks: Option<Vec<OneOrMany<String>>>,
+    env: Option<IndexMap<String, String>>,
+}
+
+/// A unit is a wine unit, unless its `kind` is `native`
+#[derive(JsonSchema)]
+#[serde(untagged)]
+#[allow(dead_code)]
+enum UnitSchema {
+    Native(NativeUnitSchema),
+    Wine(WineUnit),
+}
+
+#[derive(JsonSchema)]
+#[serde(tag = "kind", rename_all = "lowercase")]
+#[allow(dead_code)]
+enum NativeUnitSchema {
+    Native(NativeUnit),
+}
+
+impl JsonSchema for Unit {
+    fn schema_name() -> String {
+        "Unit".to_owned()
+    }
+
+    fn json_schema(gen: &mut SchemaGenerator) -> schemars::schema::Schema {
+        UnitSchema::json_schema(gen)
+    }
+}
+
+impl JsonSchema for ReleaseVersion {
+    fn schema_name() -> String {
+        "ReleaseVersion".to_owned()
+    }
+
+    fn json_schema(gen: &mut SchemaGenerator) -> schemars::schema::Schema {
+        let mut schema = String::json_schema(gen).into_object();
+        schema.metadata().description = Some(
+            "`latest` (or `*`), a release before the latest one as `latest-<n>`, or a tag"
+                .to_owned(),
+        );
+        schema.into()
+    }
+}
+
+/// Size in bytes, or a number with a `K`, `M`, `G` or `T` suffix, e.g. `20G`
+#[derive(JsonSchema)]
+#[serde(untagged)]
+#[allow(dead_code)]
+enum ByteSizeSchema {
+    Bytes(u64),
+    Text(String),
+}
+
+impl JsonSchema for ByteSize {
+    fn schema_name() -> String {
+        "ByteSize".to_owned()
+    }
+
+    fn json_schema(gen: &mut SchemaGenerator) -> schemars::schema::Schema {
+        ByteSizeSchema::json_schema(gen)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::schema;
+
+    #[test]
+    fn config_schema() {
+        let schema = serde_json::to_value(schema()).unwrap();
+
+        let properties = &schema["properties"];
+        for property in ["tokens", "units", "defaults", "post_extract"] {
+            assert!(properties.get(property).is_some(), "{property}");
+        }
+
+        let definitions = &schema["definitions"];
+        for definition in ["Unit", "WineUnit", "NativeUnit", "Runtime", "Library"] {
+            assert!(definitions.get(definition).is_some(), "{definition}");
+        }
+        assert_eq!(definitions["ReleaseVersion"]["type"], "string");
+    }
+}
@@ -1,12 +1,21 @@
 use std::{
     borrow::Cow,
     io,
-    sync::{Arc, OnceLock},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, OnceLock, PoisonError, RwLock,
+    },
+    thread,
+    time::Duration,
 };
 
 pub use native_tls::Error as TlsError;
 
-use indicatif::{MultiProgress, ProgressBar, ProgressFinish, ProgressState, ProgressStyle};
+use indicatif::{
+    HumanBytes, MultiProgress, ProgressBar, ProgressBarIter, ProgressDrawTarget, ProgressFinish,
+    ProgressState, ProgressStyle,
+};
+use log::{info, warn};
 
 pub const USER_AGENT_HEADER: &str = "github.com/nikarh/brie";
 
@@ -15,6 +24,27 @@ pub fn mp() -> &'static MultiProgress {
     MP.get_or_init(MultiProgress::new)
 }
 
+/// Whether downloads log a summary line on completion instead of showing live progress bars
+static SUMMARY: AtomicBool = AtomicBool::new(false);
+
+/// Switches all subsequent downloads to [`DownloadStream::progress_summary`]
+pub fn set_progress_summary(enabled: bool) {
+    SUMMARY.store(enabled, Ordering::Relaxed);
+}
+
+/// Headers added to every download
+static HEADERS: RwLock<Vec<(String, String)>> = RwLock::new(Vec::new());
+
+/// Sets headers added to all subsequent downloads. They are set after the `User-Agent`,
+/// which they may override, and before the `Authorization` of a download, which takes precedence
+pub fn set_download_headers<'a>(headers: impl IntoIterator<Item = (&'a String, &'a String)>) {
+    let headers = headers
+        .into_iter()
+        .map(|(name, value)| (name.clone(), value.clone()))
+        .collect();
+    *HEADERS.write().unwrap_or_else(PoisonError::into_inner) = headers;
+}
+
 pub fn ureq() -> Result<&'static ureq::Agent, &'static native_tls::Error> {
     static AGENT: OnceLock<Result<ureq::Agent, native_tls::Error>> = OnceLock::new();
     AGENT
@@ -35,49 +65,330 @@ pub enum Error {
     Ureq(#[from] Box<ureq::Error>),
 }
 
+/// Maximum number of attempts for a rate limited request
+const MAX_ATTEMPTS: u32 = 3;
+/// Delay before retrying a `429` response without a `Retry-After` header
+const RETRY_BACKOFF: Duration = Duration::from_secs(10);
+/// Longest `Retry-After` delay which is waited for, longer ones fail the request
+const MAX_RETRY_AFTER: Duration = Duration::from_secs(300);
+
+/// Sends the request, retrying it if the server rate limits it.
+/// Both `429` and `403` responses with a `Retry-After` header are retried after exactly
+/// the requested delay, `429` responses without it are retried after a fixed backoff.
+pub fn call(request: &ureq::Request) -> Result<ureq::Response, Box<ureq::Error>> {
+    let mut attempt = 1;
+    loop {
+        match request.clone().call() {
+            Err(ureq::Error::Status(status @ (429 | 403), response)) if attempt < MAX_ATTEMPTS => {
+                let wait = match (status, retry_after(&response)) {
+                    (_, Some(wait)) if wait <= MAX_RETRY_AFTER => wait,
+                    (429, None) => RETRY_BACKOFF,
+                    _ => return Err(Box::new(ureq::Error::Status(status, response))),
+                };
+
+                warn!(
+                    "Rate limited by {}, waiting {}s before retrying",
+                    response.get_url(),
+                    wait.as_secs()
+                );
+                thread::sleep(wait);
+                attempt += 1;
+            }
+            result => return result.map_err(Box::new),
+        }
+    }
+}
+
+/// Delay requested by the `Retry-After` header, in seconds only
+pub fn retry_after(response: &ureq::Response) -> Option<Duration> {
+    response
+        .header("Retry-After")
+        .and_then(|h| h.trim().parse::<u64>().ok())
+        .map(Duration::from_secs)
+}
+
 pub fn download_file(
     url: &str,
     authorization: Option<&str>,
 ) -> Result<DownloadStream<impl io::Read>, Error> {
-    let req = match authorization {
-        Some(header) => ureq()?.get(url).set("Authorization", header),
-        None => ureq()?.get(url),
-    };
+    let request = |method: &str| Ok::<_, Error>(request(ureq()?, method, url, authorization));
 
-    let response = req.call().map_err(Box::new)?;
+    let response = call(&request("GET")?)?;
 
-    let len = response
-        .header("Content-Length")
-        .and_then(|h| h.parse::<usize>().ok());
+    // Chunked responses don't have a length, but it might still be known to the server
+    let len = content_length(&response).or_else(|| {
+        let response = request("HEAD").ok()?.call().ok()?;
+        content_length(&response)
+    });
 
     let body = response.into_reader();
 
     Ok(DownloadStream { body, len })
 }
 
+fn request(
+    agent: &ureq::Agent,
+    method: &str,
+    url: &str,
+    authorization: Option<&str>,
+) -> ureq::Request {
+    let headers = HEADERS.read().unwrap_or_else(PoisonError::into_inner);
+    let req = headers
+        .iter()
+        .fold(agent.request(method, url), |req, (name, value)| {
+            req.set(name, value)
+        });
+
+    match authorization {
+        Some(header) => req.set("Authorization", header),
+        None => req,
+    }
+}
+
+fn content_length(response: &ureq::Response) -> Option<usize> {
+    ["Content-Length", "X-Content-Length"]
+        .into_iter()
+        .find_map(|header| response.header(header))
+        .and_then(|h| h.parse::<usize>().ok())
+}
+
 pub struct DownloadStream<R: io::Read> {
     pub body: R,
+    /// Length of the body. `None` if neither the response nor a `HEAD` request provide it,
+    /// in which case the progress only shows the amount of downloaded bytes.
     pub len: Option<usize>,
 }
 
 impl<R: io::Read> DownloadStream<R> {
+    /// Shows a live progress bar of the download, or logs a summary line on completion
+    /// if enabled with [`set_progress_summary`]
     #[allow(clippy::missing_panics_doc)]
-    pub fn progress(self, name: impl Into<Cow<'static, str>>) -> (impl io::Read, ProgressBar) {
-        let pb = match self.len {
-            Some(len) => ProgressBar::new(len as u64),
-            None => ProgressBar::new_spinner(),
+    pub fn progress(self, name: impl Into<Cow<'static, str>>) -> (Progress<R>, ProgressBar) {
+        if SUMMARY.load(Ordering::Relaxed) {
+            let read = self.progress_summary(name);
+            let pb = read.inner.progress.clone();
+            return (read, pb);
+        }
+
+        let (pb, template) = match self.len {
+            Some(len) => (
+                ProgressBar::new(len as u64),
+                "{spinner:.green} [{elapsed_precise}] [{wide_bar:.cyan/blue}] {bytes}/{total_bytes} ({eta}) - {msg:>15}",
+            ),
+            None => (
+                ProgressBar::new_spinner(),
+                "{spinner:.green} [{elapsed_precise}] {bytes} ({bytes_per_sec}) - {msg:>15}",
+            ),
         };
 
         let pb = pb
-        .with_message(name)
-        .with_finish(ProgressFinish::AndLeave)
-        .with_style(ProgressStyle::with_template("{spinner:.green} [{elapsed_precise}] [{wide_bar:.cyan/blue}] {bytes}/{total_bytes} ({eta}) - {msg:>15}")
-        .unwrap()
-        .with_key("eta", |state: &ProgressState, w: &mut dyn std::fmt::Write| write!(w, "{:.1}s", state.eta().as_secs_f64()).unwrap())
-        .progress_chars("#>-"));
+            .with_message(name)
+            .with_finish(ProgressFinish::AndLeave)
+            .with_style(
+                ProgressStyle::with_template(template)
+                    .unwrap()
+                    .with_key(
+                        "eta",
+                        |state: &ProgressState, w: &mut dyn std::fmt::Write| {
+                            write!(w, "{:.1}s", state.eta().as_secs_f64()).unwrap()
+                        },
+                    )
+                    .progress_chars("#>-"),
+            );
 
         let pb = mp().add(pb);
 
-        (pb.wrap_read(self.body), pb)
+        let read = Progress {
+            inner: pb.wrap_read(self.body),
+            summary: None,
+        };
+        (read, pb)
+    }
+
+    /// Logs one line with the size, elapsed time and average speed once the download completes,
+    /// without drawing a progress bar. Useful in logs and for comparing mirror performance.
+    pub fn progress_summary(self, name: impl Into<Cow<'static, str>>) -> Progress<R> {
+        let len = self.len.map(|len| len as u64);
+        let pb = ProgressBar::with_draw_target(len, ProgressDrawTarget::hidden());
+
+        Progress {
+            inner: pb.wrap_read(self.body),
+            summary: Some(name.into()),
+        }
+    }
+}
+
+/// Reader of a download which tracks its progress
+pub struct Progress<R> {
+    inner: ProgressBarIter<R>,
+    /// Name logged in the summary line, `None` once logged or if progress is shown with a bar
+    summary: Option<Cow<'static, str>>,
+}
+
+impl<R> Progress<R> {
+    /// Logs the summary line, once. Archives are often not read to the end of the body,
+    /// e.g. tar stops at its end-of-archive blocks, so this also happens on drop
+    fn log_summary(&mut self) {
+        if let Some(name) = self.summary.take() {
+            info!(
+                "{}",
+                summary(
+                    &name,
+                    self.inner.progress.position(),
+                    self.inner.progress.elapsed()
+                )
+            );
+        }
+    }
+}
+
+impl<R: io::Read> io::Read for Progress<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        if read == 0 && !buf.is_empty() {
+            self.log_summary();
+        }
+
+        Ok(read)
+    }
+}
+
+impl<R> Drop for Progress<R> {
+    fn drop(&mut self) {
+        self.log_summary();
+    }
+}
+
+#[allow(
+    clippy::cast_precision_loss,
+    clippy::cast_possible_truncation,
+    clippy::cast_sign_loss
+)]
+fn summary(name: &str, bytes: u64, elapsed: Duration) -> String {
+    let secs = elapsed.as_secs_f64();
+    let speed = match secs > 0.0 {
+        true => (bytes as f64 / secs) as u64,
+        false => bytes,
+    };
+
+    format!(
+        "Downloaded {name}: {} in {secs:.1}s ({}/s)",
+        HumanBytes(bytes),
+        HumanBytes(speed)
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::Read,
+        sync::{Mutex, OnceLock},
+        time::Duration,
+    };
+
+    use crate::{request, retry_after, set_download_headers, summary, DownloadStream};
+
+    /// Collects logged messages
+    struct Capture(Mutex<Vec<String>>);
+
+    impl log::Log for Capture {
+        fn enabled(&self, _: &log::Metadata) -> bool {
+            true
+        }
+
+        fn log(&self, record: &log::Record) {
+            self.0.lock().unwrap().push(record.args().to_string());
+        }
+
+        fn flush(&self) {}
+    }
+
+    fn logged() -> &'static Mutex<Vec<String>> {
+        static CAPTURE: OnceLock<&'static Capture> = OnceLock::new();
+        let capture = CAPTURE.get_or_init(|| {
+            let capture = Box::leak(Box::new(Capture(Mutex::new(Vec::new()))));
+            log::set_logger(capture).unwrap();
+            log::set_max_level(log::LevelFilter::Info);
+            capture
+        });
+        &capture.0
+    }
+
+    #[test]
+    fn parse_retry_after() {
+        let response = |headers: &str| {
+            format!("HTTP/1.1 429 Too Many Requests\r\n{headers}\r\n")
+                .parse::<ureq::Response>()
+                .unwrap()
+        };
+
+        assert_eq!(
+            retry_after(&response("Retry-After: 30\r\n")),
+            Some(Duration::from_secs(30))
+        );
+        assert_eq!(retry_after(&response("")), None);
+        assert_eq!(
+            retry_after(&response("Retry-After: Wed, 21 Oct 2015 07:28:00 GMT\r\n")),
+            None
+        );
+    }
+
+    #[test]
+    fn download_headers() {
+        let headers = [
+            ("X-Token".to_owned(), "secret".to_owned()),
+            ("Authorization".to_owned(), "Basic proxy".to_owned()),
+        ];
+        set_download_headers(headers.iter().map(|(name, value)| (name, value)));
+
+        let agent = ureq::Agent::new();
+        let req = request(&agent, "GET", "https://example.com", None);
+        assert_eq!(req.header("X-Token"), Some("secret"));
+        assert_eq!(req.header("Authorization"), Some("Basic proxy"));
+
+        let req = request(&agent, "GET", "https://example.com", Some("Bearer token"));
+        assert_eq!(req.header("Authorization"), Some("Bearer token"));
+
+        set_download_headers(std::iter::empty());
+        let req = request(&agent, "GET", "https://example.com", None);
+        assert_eq!(req.header("X-Token"), None);
+    }
+
+    #[test]
+    fn progress_summary() {
+        assert_eq!(
+            summary("dxvk", 3 * 1024 * 1024, Duration::from_secs(2)),
+            "Downloaded dxvk: 3.00 MiB in 2.0s (1.50 MiB/s)"
+        );
+
+        let stream = DownloadStream {
+            body: &[1u8; 100][..],
+            len: Some(100),
+        };
+        let mut read = stream.progress_summary("body");
+        let mut body = Vec::new();
+        read.read_to_end(&mut body).unwrap();
+        assert_eq!(body.len(), 100);
+        assert_eq!(read.inner.progress.position(), 100);
+        assert!(read.summary.is_none());
+    }
+
+    #[test]
+    fn progress_summary_on_drop() {
+        let stream = DownloadStream {
+            body: &[1u8; 100][..],
+            len: Some(100),
+        };
+        let mut read = stream.progress_summary("partial");
+        read.read_exact(&mut [0; 10]).unwrap();
+        drop(read);
+
+        let logged = logged().lock().unwrap();
+        let summaries = logged
+            .iter()
+            .filter(|line| line.starts_with("Downloaded partial: "))
+            .collect::<Vec<_>>();
+        assert_eq!(summaries.len(), 1);
+        assert!(summaries[0].starts_with("Downloaded partial: 10 B in "));
     }
 }
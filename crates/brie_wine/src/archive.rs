@@ -0,0 +1,144 @@
+use std::{
+    fs, io,
+    os::unix,
+    path::{Path, PathBuf},
+};
+
+use log::{debug, info, warn};
+use thiserror::Error;
+use zstd::stream::{Decoder as ZstDecoder, Encoder as ZstEncoder};
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("Wine prefix `{0}` does not exist.")]
+    NoPrefix(PathBuf),
+    #[error("Wine prefix `{0}` already exists, use `--force` to overwrite it.")]
+    Exists(PathBuf),
+    #[error("Unable to export wine prefix. {0}")]
+    Export(io::Error),
+    #[error("Unable to import wine prefix. {0}")]
+    Import(io::Error),
+}
+
+/// Archives the wine prefix into a zstd compressed tarball
+pub fn export_prefix(prefix: &Path, file: &Path) -> Result<(), Error> {
+    if !prefix.is_dir() {
+        return Err(Error::NoPrefix(prefix.to_path_buf()));
+    }
+
+    info!(
+        "Exporting wine prefix {} to {}",
+        prefix.display(),
+        file.display()
+    );
+
+    pack(prefix, file).map_err(Error::Export)
+}
+
+/// Restores the wine prefix from a tarball created by [`export_prefix`].
+/// An existing prefix is only replaced if `force` is set.
+pub fn import_prefix(prefix: &Path, file: &Path, force: bool) -> Result<(), Error> {
+    if prefix.exists() {
+        if !force {
+            return Err(Error::Exists(prefix.to_path_buf()));
+        }
+
+        warn!("Removing existing wine prefix {}", prefix.display());
+        fs::remove_dir_all(prefix).map_err(Error::Import)?;
+    }
+
+    info!(
+        "Importing wine prefix {} from {}",
+        prefix.display(),
+        file.display()
+    );
+
+    unpack(prefix, file).map_err(Error::Import)?;
+    relink_dosdevices(prefix).map_err(Error::Import)
+}
+
+fn pack(prefix: &Path, file: &Path) -> Result<(), io::Error> {
+    let mut tar = tar::Builder::new(ZstEncoder::new(fs::File::create(file)?, 0)?);
+    tar.follow_symlinks(false);
+    tar.append_dir_all(".", prefix)?;
+
+    tar.into_inner()?.finish()?.sync_all()
+}
+
+fn unpack(prefix: &Path, file: &Path) -> Result<(), io::Error> {
+    fs::create_dir_all(prefix)?;
+    let mut tar = tar::Archive::new(ZstDecoder::new(fs::File::open(file)?)?);
+
+    tar.unpack(prefix)
+}
+
+/// Drive links of the exported prefix may point to absolute paths on another machine.
+/// Links into `drive_c` of the exported prefix are made relative, links to paths
+/// which don't exist are removed. Mounts from the config are recreated on launch.
+fn relink_dosdevices(prefix: &Path) -> Result<(), io::Error> {
+    let Ok(entries) = fs::read_dir(prefix.join("dosdevices")) else {
+        return Ok(());
+    };
+
+    for entry in entries {
+        let link = entry?.path();
+        let Ok(target) = fs::read_link(&link) else {
+            continue;
+        };
+
+        if !target.is_absolute() || target.starts_with(prefix) {
+            continue;
+        }
+
+        if let Some(relative) = drive_c_relative(&target) {
+            debug!("Relinking {} to {}", link.display(), relative.display());
+            fs::remove_file(&link)?;
+            unix::fs::symlink(relative, &link)?;
+        } else if !target.exists() {
+            warn!(
+                "Removing {}, its target {} does not exist",
+                link.display(),
+                target.display()
+            );
+            fs::remove_file(&link)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Converts an absolute path into `drive_c` of a prefix to a path relative to `dosdevices`
+fn drive_c_relative(target: &Path) -> Option<PathBuf> {
+    let mut components = target.components();
+    components.by_ref().find(|c| c.as_os_str() == "drive_c")?;
+
+    Some(
+        Path::new("..")
+            .join("drive_c")
+            .components()
+            .chain(components)
+            .collect(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::drive_c_relative;
+
+    #[test]
+    fn relative_drive_c() {
+        let relative = |p: &str| drive_c_relative(Path::new(p));
+
+        assert_eq!(
+            relative("/home/user/.local/share/brie/prefixes/game/drive_c"),
+            Some("../drive_c".into())
+        );
+        assert_eq!(
+            relative("/home/user/.local/share/brie/prefixes/game/drive_c/Games/x"),
+            Some("../drive_c/Games/x".into())
+        );
+        assert_eq!(relative("/mnt/games"), None);
+    }
+}
@@ -6,16 +6,54 @@ use std::{
     process::{Command, ExitStatus, Stdio},
 };
 
-use brie_cfg::Library;
+use brie_cfg::{Library, SearchPath};
 use indexmap::IndexMap;
-use log::debug;
+use log::{debug, info, warn};
 use path_absolutize::Absolutize;
 
 use crate::{dll::mut_env, Paths};
 
+/// Substrings of variable names whose values are hidden when the environment is logged
+const SECRETS: &[&str] = &[
+    "TOKEN",
+    "SECRET",
+    "PASSWORD",
+    "PASSWD",
+    "API_KEY",
+    "AUTH",
+    "CREDENTIAL",
+];
+
+/// Converts a non-zero exit status to an error
+pub(crate) fn check_status(status: ExitStatus) -> Result<(), io::Error> {
+    match status.success() {
+        true => Ok(()),
+        false => Err(io::Error::other(format!("Command failed with {status}"))),
+    }
+}
+
+/// Logs environment variables, hiding values of variables which look like secrets
+pub fn log_env(env: &IndexMap<String, String>) {
+    info!("Environment:");
+    for (key, value) in env {
+        info!("  {key}={}", redact(key, value));
+    }
+}
+
+fn redact<'a>(key: &str, value: &'a str) -> &'a str {
+    let key = key.to_ascii_uppercase();
+    match SECRETS.iter().any(|s| key.contains(s)) {
+        true => "<redacted>",
+        false => value,
+    }
+}
+
 pub struct Runner {
     envs: IndexMap<String, String>,
+    /// Host variables which are not passed to commands
+    unset: Vec<String>,
     prefix: PathBuf,
+    wine: PathBuf,
 }
 
 impl Runner {
@@ -23,26 +61,33 @@ impl Runner {
         paths: &Paths,
         wine: impl AsRef<Path>,
         mut envs: IndexMap<String, String>,
-        prefix: &str,
+        search_path: &SearchPath,
+        prefix: &Path,
         libraries: &IndexMap<Library, PathBuf>,
     ) -> Result<Self, io::Error> {
-        let wine = wine.as_ref();
+        let wine = wine.as_ref().absolutize()?.into_owned();
 
         let wine_path = wine
-            .absolutize()?
             .parent()
             .and_then(|p| p.to_str())
             .map(ToString::to_string);
 
-        let path = env::var_os("PATH")
-            .and_then(|p| p.into_string().ok())
-            .and_then(|rest| wine_path.as_ref().map(|p| format!("{p}:{rest}")))
-            .or(wine_path)
-            .map(|p| format!("{p}:{bin}", bin = paths.libraries.join(".bin").display()));
+        let system_path = env::var_os("PATH").and_then(|p| p.into_string().ok());
+        let bin = paths.libraries.join(".bin");
+        let bin = bin.to_string_lossy();
 
-        if let Some(path) = path {
-            envs.insert("PATH".to_owned(), path);
-        }
+        let path = search_path
+            .prepend
+            .iter()
+            .map(String::as_str)
+            .chain(wine_path.as_deref())
+            .chain(system_path.as_deref())
+            .chain(Some(&*bin))
+            .chain(search_path.append.iter().map(String::as_str))
+            .collect::<Vec<_>>()
+            .join(":");
+
+        envs.insert("PATH".to_owned(), path);
 
         let dll_overrides = envs.entry("WINEDLLOVERRIDES".to_owned()).or_default();
         dll_overrides.push_str(if dll_overrides.is_empty() { "" } else { ";" });
@@ -52,12 +97,21 @@ impl Runner {
             mut_env(library, path, &mut envs);
         }
 
-        let prefix = paths.prefixes.absolutize()?.join(prefix);
+        let prefix = prefix.absolutize()?.into_owned();
 
         let prefix_str = prefix.to_string_lossy();
         envs.insert("WINEPREFIX".to_owned(), prefix_str.to_string());
 
-        Ok(Self { envs, prefix })
+        // Make winetricks use the same wine binary
+        envs.entry("WINE".to_owned())
+            .or_insert_with(|| wine.to_string_lossy().to_string());
+
+        Ok(Self {
+            envs,
+            unset: Vec::new(),
+            prefix,
+            wine,
+        })
     }
 
     pub fn command(&self, command: impl AsRef<OsStr>, args: &[impl AsRef<OsStr>]) -> Command {
@@ -69,6 +123,9 @@ impl Runner {
             .stdout(Stdio::inherit())
             .stderr(Stdio::inherit())
             .envs(&self.envs);
+        for key in &self.unset {
+            command.env_remove(key);
+        }
 
         debug!("Running command: {:?}", command);
 
@@ -83,7 +140,64 @@ impl Runner {
         self.command(command, args).status()
     }
 
+    /// Runs the command, failing if it exits with a non-zero status
+    pub fn run_checked(
+        &self,
+        command: impl AsRef<OsStr>,
+        args: &[impl AsRef<OsStr>],
+    ) -> Result<(), io::Error> {
+        check_status(self.run(command, args)?)
+    }
+
+    /// Waits for wineserver of the prefix to exit. A non-zero exit status is only logged
+    pub fn wait(&self) -> Result<(), io::Error> {
+        let status = self.run("wineserver", &["--wait"])?;
+        if !status.success() {
+            warn!("wineserver exited with {status}");
+        }
+
+        Ok(())
+    }
+
+    /// Returns the output of `wine --version`
+    pub fn wine_version(&self) -> Result<String, io::Error> {
+        let output = self
+            .command(&self.wine, &["--version"])
+            .stdout(Stdio::piped())
+            .output()?;
+
+        Ok(String::from_utf8_lossy(&output.stdout).trim().to_owned())
+    }
+
+    /// Environment variables set for all commands
+    pub fn envs(&self) -> &IndexMap<String, String> {
+        &self.envs
+    }
+
+    /// Removes a host environment variable from all commands
+    pub fn unset_env(&mut self, key: &str) {
+        self.envs.shift_remove(key);
+        self.unset.push(key.to_owned());
+    }
+
+    /// Full path to the wine binary of the runtime
+    pub fn wine(&self) -> &Path {
+        &self.wine
+    }
+
     pub fn wine_prefix(&self) -> &Path {
         &self.prefix
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::redact;
+
+    #[test]
+    fn redact_secrets() {
+        assert_eq!(redact("DXVK_ASYNC", "1"), "1");
+        assert_eq!(redact("GITHUB_TOKEN", "ghp_1"), "<redacted>");
+        assert_eq!(redact("steam_api_key", "abc"), "<redacted>");
+    }
+}
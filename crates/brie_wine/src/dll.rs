@@ -2,14 +2,16 @@ use std::{
     borrow::Cow,
     collections::BTreeSet,
     fs,
-    io::{self, Write},
+    io::{self, Read, Seek, SeekFrom, Write},
+    os::unix,
     path::{Path, PathBuf},
     str::FromStr,
 };
 
-use brie_cfg::Library;
+use brie_cfg::{Library, ReleaseVersion};
 use indexmap::IndexMap;
-use log::{debug, info};
+use log::{debug, info, warn};
+use path_absolutize::Absolutize;
 use thiserror::Error;
 
 use crate::{command::Runner, library::Downloadable, WithContext};
@@ -107,6 +109,15 @@ impl FromStr for Arch {
     }
 }
 
+impl From<brie_cfg::Arch> for Arch {
+    fn from(arch: brie_cfg::Arch) -> Self {
+        match arch {
+            brie_cfg::Arch::X86 => Arch::X86,
+            brie_cfg::Arch::X64 => Arch::X64,
+        }
+    }
+}
+
 impl Arch {
     fn dir(self) -> &'static str {
         match self {
@@ -116,22 +127,135 @@ impl Arch {
     }
 }
 
+/// Dlls installed by a library for a single architecture
+#[derive(Debug, Clone, Copy)]
+pub struct Dlls {
+    /// Directory containing the dlls, relative to the library root
+    pub source: &'static str,
+    pub arch: Arch,
+    pub files: &'static [&'static str],
+}
+
+impl Dlls {
+    const fn new(source: &'static str, arch: Arch, files: &'static [&'static str]) -> Self {
+        Self {
+            source,
+            arch,
+            files,
+        }
+    }
+
+    /// Path of an installed dll, relative to the wine prefix
+    #[must_use]
+    pub fn target(&self, file: &str) -> PathBuf {
+        Path::new("drive_c")
+            .join("windows")
+            .join(self.arch.dir())
+            .join(file.strip_suffix(".so").unwrap_or(file))
+    }
+
+    /// Directory the dlls are installed from. Some builds have no architecture folders,
+    /// and ship dlls of a single architecture in the library root, which is detected by their PE header
+    fn source_dir(&self, path: &Path) -> Option<PathBuf> {
+        let source = path.join(self.source);
+        if source.is_dir() {
+            return Some(source);
+        }
+
+        let flat = self
+            .files
+            .iter()
+            .map(|file| path.join(file))
+            .filter(|file| file.is_file())
+            .collect::<Vec<_>>();
+        let matches = !flat.is_empty() && flat.iter().all(|file| pe_arch(file) == Some(self.arch));
+        matches.then(|| path.to_owned())
+    }
+}
+
+/// Architecture of a windows binary, from the machine type in its PE header
+fn pe_arch(path: &Path) -> Option<Arch> {
+    const MACHINE_X64: u16 = 0x8664;
+    const MACHINE_X86: u16 = 0x14c;
+
+    let mut file = fs::File::open(path).ok()?;
+    let mut dos = [0; 0x40];
+    file.read_exact(&mut dos).ok()?;
+    if !dos.starts_with(b"MZ") {
+        return None;
+    }
+
+    let offset = u32::from_le_bytes([dos[0x3c], dos[0x3d], dos[0x3e], dos[0x3f]]);
+    file.seek(SeekFrom::Start(offset.into())).ok()?;
+    let mut pe = [0; 6];
+    file.read_exact(&mut pe).ok()?;
+    if !pe.starts_with(b"PE\0\0") {
+        return None;
+    }
+
+    match u16::from_le_bytes([pe[4], pe[5]]) {
+        MACHINE_X64 => Some(Arch::X64),
+        MACHINE_X86 => Some(Arch::X86),
+        _ => None,
+    }
+}
+
+/// Returns dlls which are copied to a wine prefix when the library is installed
+#[must_use]
+pub fn library_dlls(library: Library) -> Vec<Dlls> {
+    const DXVK: &[&str] = &["d3d9.dll", "d3d10core.dll", "d3d11.dll", "dxgi.dll"];
+    const VKD3D: &[&str] = &["d3d12.dll", "d3d12core.dll"];
+
+    match library {
+        Library::Dxvk | Library::DxvkGplAsync => vec![
+            Dlls::new("x64", Arch::X64, DXVK),
+            Dlls::new("x32", Arch::X86, DXVK),
+        ],
+        Library::DxvkNvapi => vec![
+            Dlls::new("x64", Arch::X64, &["nvapi64.dll"]),
+            Dlls::new("x32", Arch::X86, &["nvapi.dll"]),
+        ],
+        Library::Vkd3dProton => vec![
+            Dlls::new("x64", Arch::X64, VKD3D),
+            Dlls::new("x86", Arch::X86, VKD3D),
+        ],
+        Library::NvidiaLibs => vec![
+            Dlls::new(
+                "lib64/wine/x86_64-unix",
+                Arch::X64,
+                &["nvcuda.dll.so", "nvoptix.dll.so"],
+            ),
+            Dlls::new("lib/wine/i386-unix", Arch::X86, &["nvcuda.dll.so"]),
+        ],
+    }
+}
+
 #[derive(Debug, Error)]
 pub enum CopyError {
     #[error("Unable to copy dll. {0}")]
     Copy(io::Error),
+    #[error("Unable to remove dll. {0}")]
+    Remove(io::Error),
     #[error("Invalid file name: {0}")]
     FileName(PathBuf),
 }
 
+#[derive(Debug, Error)]
+pub enum OverrideError {
+    #[error("{0}")]
+    Io(#[from] io::Error),
+    #[error("Overrides are missing from the registry after import: {0}")]
+    Mismatch(String),
+}
+
 #[derive(Debug, Error)]
 pub enum Error {
     #[error("Error installing {0} library. {1}")]
     Library(&'static str, CopyError),
     #[error("Unable to override dlls. {0}")]
-    Reg(io::Error),
-    #[error("Unable to create reg file. Wine prefix is an invalid path.")]
-    InvalidPath,
+    Reg(OverrideError),
+    #[error("Dlls of {0} library are missing from the wine prefix after install: {1}")]
+    Missing(&'static str, String),
     #[error("Unable to update state file. {0}")]
     StateWrite(io::Error),
 }
@@ -143,15 +267,8 @@ impl<T> WithContext<Result<T, Error>, &'static str> for Result<T, CopyError> {
 }
 
 impl Runner {
-    fn copy_dll(&self, source: impl AsRef<Path>, arch: Arch) -> Result<(), CopyError> {
-        let dest = self
-            .wine_prefix()
-            .join("drive_c")
-            .join("windows")
-            .join(arch.dir());
-
-        let source = source.as_ref();
-
+    /// Path of a library dll in the wine prefix
+    fn dll_dest(&self, source: &Path, arch: Arch) -> Result<PathBuf, CopyError> {
         let target = match source.extension().is_some_and(|ext| ext == "so") {
             true => Cow::Owned(source.with_extension("")),
             false => Cow::Borrowed(source),
@@ -161,7 +278,17 @@ impl Runner {
             .file_name()
             .ok_or_else(|| CopyError::FileName(source.to_path_buf()))?;
 
-        let dest = dest.join(file_name);
+        Ok(self
+            .wine_prefix()
+            .join("drive_c")
+            .join("windows")
+            .join(arch.dir())
+            .join(file_name))
+    }
+
+    fn copy_dll(&self, source: impl AsRef<Path>, arch: Arch) -> Result<(), CopyError> {
+        let source = source.as_ref();
+        let dest = self.dll_dest(source, arch)?;
 
         debug!("Copying {} to {}", source.display(), dest.display());
 
@@ -176,6 +303,27 @@ impl Runner {
         Ok(())
     }
 
+    /// Symlinks the dll from the libraries directory instead of copying it
+    fn link_dll(&self, source: impl AsRef<Path>, arch: Arch) -> Result<(), CopyError> {
+        let source = source.as_ref().absolutize().map_err(CopyError::Copy)?;
+        let dest = self.dll_dest(&source, arch)?;
+
+        if dest.read_link().is_ok_and(|target| target == source) {
+            return Ok(());
+        }
+
+        debug!("Linking {} to {}", dest.display(), source.display());
+
+        // Either a copied dll or a link to another version
+        if dest.symlink_metadata().is_ok() {
+            fs::remove_file(&dest).map_err(CopyError::Copy)?;
+        }
+
+        unix::fs::symlink(&source, dest).map_err(CopyError::Copy)?;
+
+        Ok(())
+    }
+
     fn install_dlls<'a>(
         &self,
         overrides: &mut Overrides<'a>,
@@ -183,12 +331,24 @@ impl Runner {
         path: &Path,
         arch: Arch,
         dlls: &[&'a str],
+        link: bool,
+        exclude: &[String],
     ) -> Result<(), CopyError> {
-        for dll in dlls {
-            self.copy_dll(path.join(dll), arch)?;
-
-            let dll = dll.strip_suffix(".so").unwrap_or(dll);
+        for file in dlls {
+            let dll = file.strip_suffix(".so").unwrap_or(file);
             let dll = dll.strip_suffix(".dll").unwrap_or(dll);
+            if is_excluded(exclude, dll) {
+                debug!("Skipping excluded dll {dll}");
+                if overrides.all.contains(dll) {
+                    warn!("Excluded dll {dll} was installed before, recreate the prefix to use the builtin one");
+                }
+                continue;
+            }
+
+            match link {
+                true => self.link_dll(path.join(file), arch)?,
+                false => self.copy_dll(path.join(file), arch)?,
+            }
             overrides.insert(dll);
         }
 
@@ -200,82 +360,254 @@ impl Runner {
         overrides: &mut Overrides,
         library: Library,
         path: &Path,
+        arch: &[Arch],
+        link: bool,
+        exclude: &[String],
     ) -> Result<(), CopyError> {
-        let o = overrides;
-        match library {
-            Library::Dxvk | Library::DxvkGplAsync => {
-                let dlls = &["d3d9.dll", "d3d10core.dll", "d3d11.dll", "dxgi.dll"];
-                self.install_dlls(o, &path.join("x64"), Arch::X64, dlls)?;
-                self.install_dlls(o, &path.join("x32"), Arch::X86, dlls)?;
-            }
-            Library::DxvkNvapi => {
-                self.install_dlls(o, &path.join("x64"), Arch::X64, &["nvapi64.dll"])?;
-                self.install_dlls(o, &path.join("x32"), Arch::X86, &["nvapi.dll"])?;
-            }
-            Library::Vkd3dProton => {
-                let dlls = &["d3d12.dll", "d3d12core.dll"];
-                self.install_dlls(o, &path.join("x64"), Arch::X64, dlls)?;
-                self.install_dlls(o, &path.join("x86"), Arch::X86, dlls)?;
+        for dlls in library_dlls(library) {
+            if !arch.contains(&dlls.arch) {
+                continue;
             }
-            Library::NvidiaLibs => {
-                let libs = path.join("lib64").join("wine").join("x86_64-unix");
-                self.install_dlls(o, &libs, Arch::X64, &["nvcuda.dll.so", "nvoptix.dll.so"])?;
-                let libs = path.join("lib").join("wine").join("i386-unix");
-                self.install_dlls(o, &libs, Arch::X86, &["nvcuda.dll.so"])?;
+
+            let Some(source) = dlls.source_dir(path) else {
+                warn!(
+                    "Skipping {} dlls of {} ({}), `{}` does not exist",
+                    dlls.arch,
+                    library.name(),
+                    dlls.files.join(", "),
+                    path.join(dlls.source).display()
+                );
+                continue;
+            };
+            if source == path {
+                info!(
+                    "Installing {} dlls of {} from the library root",
+                    dlls.arch,
+                    library.name()
+                );
             }
+
+            self.install_dlls(overrides, &source, dlls.arch, dlls.files, link, exclude)?;
         }
 
         Ok(())
     }
 
-    pub fn install_libraries(&self, libraries: &IndexMap<Library, PathBuf>) -> Result<(), Error> {
+    /// Copies dlls of the given architectures from downloaded libraries to the wine prefix,
+    /// or symlinks them with `link`. Architectures missing from a library release are skipped with a warning.
+    /// Dlls in `exclude` are neither installed nor overridden, so the wine builtin ones stay in use.
+    /// Installed dlls and their overrides are checked afterwards, missing ones fail the install.
+    pub fn install_libraries(
+        &self,
+        libraries: &IndexMap<Library, PathBuf>,
+        arch: &[brie_cfg::Arch],
+        nvidia: bool,
+        link: bool,
+        exclude: &[String],
+    ) -> Result<(), Error> {
+        let arch = arch.iter().copied().map(Arch::from).collect::<Vec<_>>();
+
         let overrides_file = self.wine_prefix().join(".overrides");
         let overrides = fs::read_to_string(&overrides_file).unwrap_or_default();
         let mut overrides = Overrides::new(&overrides);
 
+        // Prefixes may be shared by several units, each installing the same libraries
+        let installed_file = self.wine_prefix().join(".libraries");
+        let installed = fs::read_to_string(&installed_file).unwrap_or_default();
+        let mut installed = InstalledLibraries::new(&installed);
+
         for (library, path) in libraries {
             let name = library.name();
-            info!("Copying library {name} dlls from {:?}", path.display());
-            self.install_library_dlls(&mut overrides, *library, path)
+            let key = installed_key(*library, path, &arch, link, exclude);
+            if installed.contains(&key) {
+                let missing = self.missing_dlls(*library, path, &arch, exclude);
+                if missing.is_empty() {
+                    debug!(
+                        "Library {name} is already installed from {}",
+                        path.display()
+                    );
+                    continue;
+                }
+                warn!("Dlls of library {name} are missing from the prefix, installing them again: {missing}");
+            }
+
+            info!("Installing library {name} dlls from {:?}", path.display());
+            self.install_library_dlls(&mut overrides, *library, path, &arch, link, exclude)
                 .context(name)?;
+
+            let missing = self.missing_dlls(*library, path, &arch, exclude);
+            if !missing.is_empty() {
+                return Err(Error::Missing(name, missing));
+            }
+            installed.insert(*library, key);
         }
 
         if let Ok(path) = dl::find_dl_path("libGLX_nvidia.so.0") {
             let path = Path::new(&path).join("nvidia").join("wine");
-            if path.exists() {
+            if nvidia && path.exists() && arch.contains(&Arch::X64) {
                 info!("Copying system nvngx dlls");
                 let dlls = &["nvngx.dll", "_nvngx.dll"];
-                self.install_dlls(&mut overrides, &path, Arch::X64, dlls)
+                self.install_dlls(&mut overrides, &path, Arch::X64, dlls, link, exclude)
                     .context("nvngx")?;
             }
         }
 
-        if overrides.new.is_empty() {
-            return Ok(());
+        // Overrides of reinstalled libraries are imported again, in case they were removed from the registry
+        if installed.changed || !overrides.new.is_empty() {
+            debug!("Overriding dlls: {:?}", overrides.installed);
+            self.import_reg("dlls.reg", &overrides.reg())
+                .map_err(|e| Error::Reg(e.into()))?;
+            self.verify_overrides(&overrides.installed)
+                .map_err(Error::Reg)?;
+
+            let mut file = fs::OpenOptions::new()
+                .append(true)
+                .create(true)
+                .open(&overrides_file)
+                .map_err(Error::StateWrite)?;
+
+            for dll in overrides.new {
+                writeln!(file, "{dll}").map_err(Error::StateWrite)?;
+            }
+        }
+
+        // Written last, so that libraries are installed again if importing the overrides failed
+        if installed.changed {
+            fs::write(&installed_file, installed.to_string()).map_err(Error::StateWrite)?;
+        }
+
+        Ok(())
+    }
+
+    /// Removes dlls of the given libraries from the wine prefix and forgets the applied overrides,
+    /// so that the next [`Runner::install_libraries`] copies the dlls and imports the overrides again.
+    /// Dlls of libraries which are no longer configured are left as they are.
+    pub fn uninstall_libraries(
+        &self,
+        libraries: &IndexMap<Library, PathBuf>,
+        arch: &[brie_cfg::Arch],
+    ) -> Result<(), Error> {
+        let arch = arch.iter().copied().map(Arch::from).collect::<Vec<_>>();
+        let prefix = self.wine_prefix();
+
+        for &library in libraries.keys() {
+            info!("Removing library {} dlls", library.name());
+            for dlls in library_dlls(library) {
+                if !arch.contains(&dlls.arch) {
+                    continue;
+                }
+
+                for file in dlls.files {
+                    let target = prefix.join(dlls.target(file));
+                    match fs::remove_file(&target) {
+                        Ok(()) => debug!("Removed {}", target.display()),
+                        Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                        Err(e) => return Err(CopyError::Remove(e)).context(library.name()),
+                    }
+                }
+            }
         }
 
-        debug!("Overriding dlls: {:?}", overrides.new);
-        let reg = self.wine_prefix().join("dlls.reg");
-        let reg = reg.to_str().ok_or(Error::InvalidPath)?;
-        fs::write(reg, overrides.reg()).map_err(Error::Reg)?;
-        self.command("wine", &["regedit", reg])
-            .status()
-            .map_err(Error::Reg)?;
-        let _ = fs::remove_file(reg).map_err(Error::Reg);
-
-        let mut file = fs::OpenOptions::new()
-            .write(true)
-            .create(true)
-            .truncate(false)
-            .open(&overrides_file)
-            .map_err(Error::StateWrite)?;
-
-        for dll in overrides.new {
-            writeln!(file, "{dll}").map_err(Error::StateWrite)?;
+        for file in [".overrides", ".libraries"] {
+            match fs::remove_file(prefix.join(file)) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(Error::StateWrite(e)),
+                _ => {}
+            }
         }
 
         Ok(())
     }
+
+    /// Lists dlls of the library which should be in the prefix but are not, e.g. removed by `wineboot -u`
+    /// or linked to a removed library version. Architectures missing from the library release are not expected.
+    fn missing_dlls(
+        &self,
+        library: Library,
+        path: &Path,
+        arch: &[Arch],
+        exclude: &[String],
+    ) -> String {
+        let mut missing = Vec::new();
+        for dlls in library_dlls(library) {
+            if !arch.contains(&dlls.arch) || dlls.source_dir(path).is_none() {
+                continue;
+            }
+
+            for file in dlls.files {
+                let target = dlls.target(file);
+                let dll = target.file_stem().unwrap_or_default().to_string_lossy();
+                // Broken symlinks don't exist either
+                if !is_excluded(exclude, &dll) && !self.wine_prefix().join(&target).exists() {
+                    missing.push(target.display().to_string());
+                }
+            }
+        }
+
+        missing.join(", ")
+    }
+
+    /// Reads the overrides back from the registry, since a failed import is not always reported
+    fn verify_overrides(&self, dlls: &BTreeSet<&str>) -> Result<(), OverrideError> {
+        let values = self.query_reg(OVERRIDES_KEY)?;
+        let missing = dlls
+            .iter()
+            .filter(|dll| {
+                !values
+                    .iter()
+                    .any(|(name, data)| name.eq_ignore_ascii_case(dll) && data == "native")
+            })
+            .copied()
+            .collect::<Vec<_>>();
+
+        match missing.is_empty() {
+            true => Ok(()),
+            false => Err(OverrideError::Mismatch(missing.join(", "))),
+        }
+    }
+}
+
+/// Directory a shared library is loaded from by the dynamic loader, if it can be loaded
+#[must_use]
+pub fn find_shared_library(library: &str) -> Option<String> {
+    dl::find_dl_path(library).ok()
+}
+
+const DRM: &str = "/sys/class/drm";
+const NVIDIA_VENDOR: &str = "0x10de";
+
+/// Whether an NVIDIA GPU is present, based on PCI vendors of DRM devices.
+/// Falls back to checking if the NVIDIA driver is installed when no devices are visible.
+pub(crate) fn has_nvidia_gpu() -> bool {
+    let vendors = gpu_vendors(Path::new(DRM));
+    if vendors.is_empty() {
+        return dl::find_dl_path("libGLX_nvidia.so.0").is_ok();
+    }
+
+    vendors.iter().any(|v| v == NVIDIA_VENDOR)
+}
+
+fn gpu_vendors(drm: &Path) -> Vec<String> {
+    let Ok(entries) = fs::read_dir(drm) else {
+        return Vec::new();
+    };
+
+    entries
+        .filter_map(Result::ok)
+        .filter(|e| e.file_name().to_string_lossy().starts_with("card"))
+        .filter_map(|e| fs::read_to_string(e.path().join("device").join("vendor")).ok())
+        .map(|vendor| vendor.trim().to_owned())
+        .collect()
+}
+
+/// Removes libraries which are only useful with an NVIDIA GPU
+pub(crate) fn remove_nvidia_libraries(libraries: &mut IndexMap<Library, ReleaseVersion>) {
+    libraries.retain(|&library, _| {
+        let nvidia = matches!(library, Library::DxvkNvapi | Library::NvidiaLibs);
+        if nvidia {
+            info!("No NVIDIA GPU detected, skipping {}", library.name());
+        }
+        !nvidia
+    });
 }
 
 pub fn mut_env(library: Library, path: &Path, env: &mut IndexMap<String, String>) {
@@ -296,9 +628,87 @@ pub fn mut_env(library: Library, path: &Path, env: &mut IndexMap<String, String>
     }
 }
 
+const OVERRIDES_KEY: &str = "HKEY_CURRENT_USER\\Software\\Wine\\DllOverrides";
+
+/// Whether a dll, given without an extension, is in the excluded list,
+/// which may name dlls with or without the `.dll` extension
+fn is_excluded(exclude: &[String], dll: &str) -> bool {
+    exclude.iter().any(|e| {
+        e.strip_suffix(".dll")
+            .unwrap_or(e)
+            .eq_ignore_ascii_case(dll)
+    })
+}
+
+/// Identifies an installed library version, along with the options it was installed with.
+/// `path` is resolved, so that an updated `latest` version is installed again.
+fn installed_key(
+    library: Library,
+    path: &Path,
+    arch: &[Arch],
+    link: bool,
+    exclude: &[String],
+) -> String {
+    let path = fs::canonicalize(path).unwrap_or_else(|_| path.to_owned());
+    let arch = arch.iter().map(ToString::to_string).collect::<Vec<_>>();
+    let mode = match link {
+        true => "link",
+        false => "copy",
+    };
+    let exclude = match exclude.is_empty() {
+        true => "-".to_owned(),
+        false => exclude.join(","),
+    };
+
+    format!(
+        "{} {} {mode} {exclude} {}",
+        library.name(),
+        arch.join(","),
+        path.display()
+    )
+}
+
+/// Libraries installed into a prefix, one key per line in `.libraries`
+struct InstalledLibraries<'a> {
+    keys: Vec<Cow<'a, str>>,
+    changed: bool,
+}
+
+impl<'a> InstalledLibraries<'a> {
+    fn new(existing: &'a str) -> Self {
+        Self {
+            keys: existing.lines().map(Cow::Borrowed).collect(),
+            changed: false,
+        }
+    }
+
+    fn contains(&self, key: &str) -> bool {
+        self.keys.iter().any(|k| k == key)
+    }
+
+    /// Replaces the key of another version of the library
+    fn insert(&mut self, library: Library, key: String) {
+        let name = library.name();
+        self.keys.retain(|k| k.split(' ').next() != Some(name));
+        self.keys.push(Cow::Owned(key));
+        self.changed = true;
+    }
+}
+
+impl std::fmt::Display for InstalledLibraries<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for key in &self.keys {
+            writeln!(f, "{key}")?;
+        }
+        Ok(())
+    }
+}
+
 struct Overrides<'a> {
     all: BTreeSet<&'a str>,
     new: BTreeSet<&'a str>,
+    /// Dlls installed during this run, including the ones overridden before
+    installed: BTreeSet<&'a str>,
 }
 
 impl<'a> Overrides<'a> {
@@ -306,10 +716,12 @@ impl<'a> Overrides<'a> {
         Self {
             all: existing.lines().collect(),
             new: BTreeSet::new(),
+            installed: BTreeSet::new(),
         }
     }
 
     fn insert(&mut self, dll: &'a str) {
+        self.installed.insert(dll);
         if !self.all.contains(dll) {
             self.all.insert(dll);
             self.new.insert(dll);
@@ -317,12 +729,9 @@ impl<'a> Overrides<'a> {
     }
 
     fn reg(&self) -> String {
-        let mut reg = String::from(
-            "Windows Registry Editor Version 5.00\n\n\
-            [HKEY_CURRENT_USER\\Software\\Wine\\DllOverrides]\n",
-        );
+        let mut reg = format!("Windows Registry Editor Version 5.00\n\n[{OVERRIDES_KEY}]\n");
 
-        for dll in &self.new {
+        for dll in &self.installed {
             reg.push('"');
             reg.push_str(dll);
             reg.push_str("\"=\"native\"\n");
@@ -330,3 +739,141 @@ impl<'a> Overrides<'a> {
         reg
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, path::Path};
+
+    use brie_cfg::{Library, SearchPath};
+    use indexmap::IndexMap;
+
+    use crate::{command::Runner, Paths};
+
+    use super::{
+        gpu_vendors, installed_key, is_excluded, library_dlls, pe_arch, Arch, InstalledLibraries,
+    };
+
+    /// A minimal PE header with the given machine type
+    fn pe(machine: u16) -> Vec<u8> {
+        let mut pe = vec![0; 0x80];
+        pe[..2].copy_from_slice(b"MZ");
+        pe[0x3c] = 0x40;
+        pe[0x40..0x44].copy_from_slice(b"PE\0\0");
+        pe[0x44..0x46].copy_from_slice(&machine.to_le_bytes());
+        pe
+    }
+
+    #[test]
+    fn flat_library() {
+        let dir = Path::new(".tmp").join("flat_library");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("d3d11.dll"), pe(0x8664)).unwrap();
+        fs::write(dir.join("dxgi.dll"), pe(0x8664)).unwrap();
+        fs::write(dir.join("not-pe.dll"), "MZ").unwrap();
+
+        assert_eq!(pe_arch(&dir.join("d3d11.dll")), Some(Arch::X64));
+        assert_eq!(pe_arch(&dir.join("not-pe.dll")), None);
+
+        let dlls = library_dlls(Library::Dxvk);
+        let [x64, x86] = dlls.as_slice() else {
+            panic!("dxvk has dlls for two architectures");
+        };
+        assert_eq!(x64.source_dir(&dir), Some(dir.clone()));
+        assert_eq!(x86.source_dir(&dir), None);
+
+        // Architecture folders take precedence
+        fs::create_dir_all(dir.join("x32")).unwrap();
+        assert_eq!(x86.source_dir(&dir), Some(dir.join("x32")));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn missing_dlls() {
+        let dir = Path::new(".tmp").join("missing_dlls");
+        let _ = fs::remove_dir_all(&dir);
+        let prefix = dir.join("prefix");
+        let system32 = prefix.join("drive_c").join("windows").join("system32");
+        fs::create_dir_all(&system32).unwrap();
+        // The release has no x32 dlls, so they are not expected
+        let library = dir.join("dxvk");
+        fs::create_dir_all(library.join("x64")).unwrap();
+
+        let paths = Paths::new(&dir);
+        let runner = Runner::new(
+            &paths,
+            "/usr/bin/wine",
+            IndexMap::new(),
+            &SearchPath::default(),
+            &prefix,
+            &IndexMap::new(),
+        )
+        .unwrap();
+
+        let arch = [Arch::X64, Arch::X86];
+        let exclude = ["d3d9".to_owned(), "d3d10core".to_owned()];
+        fs::write(system32.join("d3d11.dll"), "").unwrap();
+        let missing = runner.missing_dlls(Library::Dxvk, &library, &arch, &exclude);
+        assert_eq!(missing, "drive_c/windows/system32/dxgi.dll");
+
+        fs::write(system32.join("dxgi.dll"), "").unwrap();
+        assert!(runner
+            .missing_dlls(Library::Dxvk, &library, &arch, &exclude)
+            .is_empty());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn installed_libraries() {
+        let path = Path::new("/nonexistent/dxvk/v2.4");
+        let key = installed_key(Library::Dxvk, path, &[Arch::X64], false, &[]);
+        assert_eq!(key, "dxvk X64 copy - /nonexistent/dxvk/v2.4");
+        let linked = installed_key(Library::Dxvk, path, &[Arch::X64], true, &[]);
+        assert_ne!(key, linked);
+        let excluded = installed_key(Library::Dxvk, path, &[Arch::X64], false, &["d3d9".into()]);
+        assert_eq!(excluded, "dxvk X64 copy d3d9 /nonexistent/dxvk/v2.4");
+
+        let existing = format!("vkd3d-proton X64 copy - /vkd3d\n{key}\n");
+        let mut installed = InstalledLibraries::new(&existing);
+        assert!(installed.contains(&key));
+        assert!(!installed.changed);
+
+        let updated = Path::new("/nonexistent/dxvk/v2.5");
+        let updated = installed_key(Library::Dxvk, updated, &[Arch::X64], false, &[]);
+        assert!(!installed.contains(&updated));
+        installed.insert(Library::Dxvk, updated.clone());
+        assert_eq!(
+            installed.to_string(),
+            format!("vkd3d-proton X64 copy - /vkd3d\n{updated}\n")
+        );
+    }
+
+    #[test]
+    fn excluded_dlls() {
+        let exclude = ["d3d9".to_owned(), "DXGI.dll".to_owned()];
+        assert!(is_excluded(&exclude, "d3d9"));
+        assert!(is_excluded(&exclude, "dxgi"));
+        assert!(!is_excluded(&exclude, "d3d11"));
+        assert!(!is_excluded(&[], "d3d9"));
+    }
+
+    #[test]
+    fn vendors() {
+        let drm = Path::new(".tmp").join("gpu_vendors");
+        let _ = fs::remove_dir_all(&drm);
+        for (card, vendor) in [("card0", "0x1002\n"), ("card1", "0x10de\n")] {
+            fs::create_dir_all(drm.join(card).join("device")).unwrap();
+            fs::write(drm.join(card).join("device").join("vendor"), vendor).unwrap();
+        }
+        fs::create_dir_all(drm.join("renderD128")).unwrap();
+
+        let mut vendors = gpu_vendors(&drm);
+        vendors.sort();
+        assert_eq!(vendors, ["0x1002", "0x10de"]);
+        assert!(gpu_vendors(&drm.join("missing")).is_empty());
+
+        fs::remove_dir_all(&drm).unwrap();
+    }
+}
@@ -1,4 +1,4 @@
-use brie_download::ureq;
+use brie_download::{call, ureq};
 use log::info;
 use serde::Deserialize;
 
@@ -17,6 +17,10 @@ pub struct GhAsset {
 struct GhRelease {
     tag_name: String,
     assets: Vec<GhAsset>,
+    #[serde(default)]
+    draft: bool,
+    #[serde(default)]
+    prerelease: bool,
 }
 
 #[derive(Deserialize)]
@@ -44,6 +48,15 @@ impl<'a> Client<'a> {
         Self { token }
     }
 
+    fn get(&self, url: &str) -> Result<ureq::Request, Error> {
+        let mut req = ureq()?.get(url).set("Accept", ACCEPT_HEADER);
+        if let Some(token) = self.token {
+            req = req.set("Authorization", &format!("Bearer {token}"));
+        }
+
+        Ok(req)
+    }
+
     pub fn release(
         &self,
         repo: GitRepo<'_>,
@@ -54,6 +67,9 @@ impl<'a> Client<'a> {
             ReleaseVersion::Latest => {
                 format!("https://api.github.com/repos/{repo}/releases/latest")
             }
+            ReleaseVersion::LatestOffset(_) => {
+                format!("https://api.github.com/repos/{repo}/releases?per_page=100")
+            }
             ReleaseVersion::Tag(tag) => {
                 format!("https://api.github.com/repos/{repo}/releases/tags/{tag}")
             }
@@ -61,12 +77,17 @@ impl<'a> Client<'a> {
 
         info!("Downloading {version:?} release metadata from {}", url);
 
-        let mut req = ureq()?.get(&url).set("Accept", ACCEPT_HEADER);
-        if let Some(token) = self.token {
-            req = req.set("Authorization", &format!("Bearer {token}"));
-        }
-
-        let release: GhRelease = req.call().map_err(Box::new)?.into_json()?;
+        let req = self.get(&url)?;
+        let release: GhRelease = match version {
+            // Releases are listed newest first. Like `latest`, skip drafts and pre-releases
+            ReleaseVersion::LatestOffset(offset) => call(&req)?
+                .into_json::<Vec<GhRelease>>()?
+                .into_iter()
+                .filter(|r| !r.draft && !r.prerelease)
+                .nth(*offset as usize)
+                .ok_or(Error::NoMatchingAsset)?,
+            _ => call(&req)?.into_json()?,
+        };
 
         let asset = release
             .assets
@@ -88,37 +109,29 @@ impl<'a> Client<'a> {
         workflow_id: u64,
         matcher: impl Fn(&GhAsset) -> bool,
     ) -> Result<Release, Error> {
-        let run_id = match version {
-            ReleaseVersion::Latest => {
-                let url = format!("https://api.github.com/repos/{repo}/actions/workflows/{workflow_id}/runs?status=success&per_page=1");
+        let run_id = match version.latest_offset() {
+            Some(offset) => {
+                let offset = offset as usize;
+                let url = format!("https://api.github.com/repos/{repo}/actions/workflows/{workflow_id}/runs?status=success&per_page={}", offset + 1);
                 info!("Getting workflow run data from {}", url);
-                let mut req = ureq()?.get(&url).set("Accept", ACCEPT_HEADER);
-                if let Some(token) = self.token {
-                    req = req.set("Authorization", &format!("Bearer {token}"));
-                }
 
-                let response: GhWorkflowRuns = req.call().map_err(Box::new)?.into_json()?;
+                let response: GhWorkflowRuns = call(&self.get(&url)?)?.into_json()?;
                 let id = response
                     .workflow_runs
-                    .first()
+                    .get(offset)
                     .ok_or(Error::NoMatchingAsset)?
                     .id;
 
                 format!("{id}")
             }
-            ReleaseVersion::Tag(tag) => tag.to_string(),
+            None => version.to_str().into_owned(),
         };
 
         // Get the workflow run
         let url = format!("https://api.github.com/repos/{repo}/actions/runs/{run_id}/artifacts");
 
         info!("Downloading {run_id:?} workflow run metadata from {}", url);
-        let mut req = ureq()?.get(&url).set("Accept", ACCEPT_HEADER);
-        if let Some(token) = self.token {
-            req = req.set("Authorization", &format!("Bearer {token}"));
-        }
-
-        let response: GhArtifacts = req.call().map_err(Box::new)?.into_json()?;
+        let response: GhArtifacts = call(&self.get(&url)?)?.into_json()?;
 
         let asset = response
             .artifacts
@@ -1,4 +1,4 @@
-use brie_download::ureq;
+use brie_download::{call, ureq};
 use log::info;
 use serde::Deserialize;
 
@@ -29,12 +29,13 @@ impl Client {
 
         info!("Downloading {version:?} release metadata from {}", url);
 
-        let mut releases: Vec<GlFile> = ureq()?.get(&url).call().map_err(Box::new)?.into_json()?;
+        let mut releases: Vec<GlFile> = call(&ureq()?.get(&url))?.into_json()?;
 
         let release = match version {
-            ReleaseVersion::Latest => {
+            ReleaseVersion::Latest | ReleaseVersion::LatestOffset(_) => {
+                let offset = version.latest_offset().unwrap_or_default();
                 releases.sort_by(|a, b| a.name.cmp(&b.name));
-                releases.into_iter().last()
+                releases.into_iter().rev().nth(offset as usize)
             }
             ReleaseVersion::Tag(tag) => {
                 let sub = format!("{repo}-{tag}.", repo = repo.repo);
@@ -0,0 +1,101 @@
+use std::{io, path::PathBuf};
+
+use rayon::iter::{IntoParallelIterator, ParallelIterator};
+use serde::Serialize;
+
+use crate::{
+    prefixes::{usage, visible_dirs},
+    Paths,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum UsageKind {
+    Prefix,
+    Library,
+}
+
+/// Disk usage of a wine prefix or a downloaded runtime or library version
+#[derive(Debug, Serialize)]
+pub struct DiskUsage {
+    pub kind: UsageKind,
+    /// Prefix name, or `<library>/<version>` for downloaded versions
+    pub name: String,
+    pub path: PathBuf,
+    /// Total size of the files in bytes. Symlinks are not followed
+    pub size: u64,
+}
+
+/// Computes sizes of wine prefixes and downloaded versions, largest first.
+/// Hidden entries and `latest` links to versions are skipped, missing directories have no entries.
+pub fn disk_usage(paths: &Paths) -> Result<Vec<DiskUsage>, io::Error> {
+    let mut entries = Vec::new();
+    for (name, path) in visible_dirs(&paths.prefixes)? {
+        entries.push((UsageKind::Prefix, name, path));
+    }
+    for (library, path) in visible_dirs(&paths.libraries)? {
+        for (version, path) in visible_dirs(&path)? {
+            entries.push((UsageKind::Library, format!("{library}/{version}"), path));
+        }
+    }
+
+    let mut report = entries
+        .into_par_iter()
+        .map(|(kind, name, path)| DiskUsage {
+            kind,
+            name,
+            size: usage(&path).0,
+            path,
+        })
+        .collect::<Vec<_>>();
+
+    report.sort_by(|a, b| b.size.cmp(&a.size).then_with(|| a.name.cmp(&b.name)));
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, os::unix, path::Path};
+
+    use crate::Paths;
+
+    use super::{disk_usage, UsageKind};
+
+    #[test]
+    fn usage() {
+        let dir = Path::new(".tmp").join("disk_usage");
+        let _ = fs::remove_dir_all(&dir);
+        let paths = Paths::new(&dir);
+        assert!(disk_usage(&paths).unwrap().is_empty());
+
+        let game = paths.prefixes.join("game");
+        fs::create_dir_all(game.join("drive_c").join("Games")).unwrap();
+        fs::write(
+            game.join("drive_c").join("Games").join("game.exe"),
+            [0; 300],
+        )
+        .unwrap();
+        fs::create_dir_all(paths.prefixes.join(".hidden")).unwrap();
+
+        let dxvk = paths.libraries.join("dxvk");
+        fs::create_dir_all(dxvk.join("v2")).unwrap();
+        fs::write(dxvk.join("v2").join("d3d11.dll"), [0; 200]).unwrap();
+        unix::fs::symlink("v2", dxvk.join("latest")).unwrap();
+        fs::create_dir_all(paths.libraries.join(".bin")).unwrap();
+
+        let usage = disk_usage(&paths).unwrap();
+        let usage = usage
+            .iter()
+            .map(|u| (u.kind, u.name.as_str(), u.size))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            usage,
+            [
+                (UsageKind::Prefix, "game", 300),
+                (UsageKind::Library, "dxvk/v2", 200)
+            ]
+        );
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
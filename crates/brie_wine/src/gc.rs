@@ -0,0 +1,192 @@
+use std::{
+    collections::HashSet,
+    fs, io,
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+use brie_cfg::{Brie, ReleaseVersion, Runtime, Unit};
+use log::{debug, info};
+
+use crate::{
+    library::{Downloadable, WineGe, WineTkg},
+    lock,
+};
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("IO error. {0}")]
+    Io(#[from] io::Error),
+    #[error("Lock error. {0}")]
+    Lock(#[from] lock::Error),
+}
+
+/// Removes old downloaded versions of runtimes and libraries, keeping the `keep` most recently
+/// downloaded versions of each one. Versions which `latest` links point to and versions pinned
+/// by a unit in the config are never removed.
+///
+/// In a dry run nothing is removed. Returns the removed version directories.
+pub fn prune_libraries(
+    libraries: &Path,
+    config: &Brie,
+    keep: usize,
+    dry_run: bool,
+) -> Result<Vec<PathBuf>, Error> {
+    if !libraries.exists() {
+        return Ok(Vec::new());
+    }
+
+    // Don't remove versions while another brie instance is downloading them
+    let lock = match dry_run {
+        true => None,
+        false => Some(lock::acquire(
+            &libraries.join(".brie.lock"),
+            "libraries",
+            lock::Wait::Forever,
+        )?),
+    };
+
+    let pinned = pinned_versions(config);
+    let stale = stale_versions(libraries, &pinned, keep)?;
+
+    for path in &stale {
+        match dry_run {
+            true => info!("Would remove {}", path.display()),
+            false => {
+                info!("Removing {}", path.display());
+                fs::remove_dir_all(path)?;
+            }
+        }
+    }
+
+    drop(lock);
+    Ok(stale)
+}
+
+/// Directory names of versions pinned by tag in wine units, by library directory name
+fn pinned_versions(config: &Brie) -> HashSet<(&'static str, String)> {
+    let tag = |name: &'static str, version: &ReleaseVersion| match version {
+        ReleaseVersion::Tag(tag) => Some((name, tag.clone())),
+        ReleaseVersion::Latest | ReleaseVersion::LatestOffset(_) => None,
+    };
+
+    let mut pinned = HashSet::new();
+    for unit in config.units.values() {
+        let Unit::Wine(unit) = unit else { continue };
+
+        let runtime = match &unit.runtime {
+            Runtime::GeProton { version } => tag(WineGe.name(), version),
+            Runtime::Tkg { version } => tag(WineTkg.name(), version),
+            Runtime::System { .. } | Runtime::Proton { .. } => None,
+        };
+        pinned.extend(runtime);
+        pinned.extend(unit.libraries.iter().filter_map(|(l, v)| tag(l.name(), v)));
+    }
+
+    pinned
+}
+
+/// Finds version directories which are neither pinned, linked, nor among the `keep` newest ones.
+/// Hidden entries, like helper tools in `.bin`, are skipped.
+fn stale_versions(
+    libraries: &Path,
+    pinned: &HashSet<(&str, String)>,
+    keep: usize,
+) -> Result<Vec<PathBuf>, io::Error> {
+    let mut stale = Vec::new();
+    for library in visible_entries(libraries)? {
+        if !library.file_type()?.is_dir() {
+            continue;
+        }
+        let name = library.file_name().to_string_lossy().to_string();
+
+        let mut linked = HashSet::new();
+        let mut versions = Vec::new();
+        for entry in visible_entries(&library.path())? {
+            let file_type = entry.file_type()?;
+            if file_type.is_symlink() {
+                if let Some(target) = fs::read_link(entry.path())?.file_name() {
+                    linked.insert(target.to_owned());
+                }
+            } else if file_type.is_dir() {
+                let modified = entry
+                    .metadata()?
+                    .modified()
+                    .unwrap_or(SystemTime::UNIX_EPOCH);
+                versions.push((modified, entry));
+            }
+        }
+
+        // Newest first
+        versions.sort_by(|a, b| b.0.cmp(&a.0));
+        for (_, version) in versions.into_iter().skip(keep) {
+            let file_name = version.file_name();
+            let is_pinned =
+                pinned.contains(&(name.as_str(), file_name.to_string_lossy().to_string()));
+            if is_pinned || linked.contains(&file_name) {
+                debug!("Keeping {}", version.path().display());
+                continue;
+            }
+            stale.push(version.path());
+        }
+    }
+
+    stale.sort();
+    Ok(stale)
+}
+
+fn visible_entries(dir: &Path) -> Result<Vec<fs::DirEntry>, io::Error> {
+    Ok(fs::read_dir(dir)?
+        .filter_map(Result::ok)
+        .filter(|e| !e.file_name().to_string_lossy().starts_with('.'))
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::HashSet,
+        fs,
+        os::unix,
+        path::Path,
+        time::{Duration, SystemTime},
+    };
+
+    use super::stale_versions;
+
+    #[test]
+    fn stale() {
+        let dir = Path::new(".tmp").join("stale_versions");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join(".bin")).unwrap();
+
+        let dxvk = dir.join("dxvk");
+        let now = SystemTime::now();
+        for (age, version) in (1..=5).rev().zip(["v1", "v2", "v3", "v4", "v5"]) {
+            fs::create_dir_all(dxvk.join(version)).unwrap();
+            let modified = now - Duration::from_secs(3600 * age);
+            fs::File::open(dxvk.join(version))
+                .unwrap()
+                .set_modified(modified)
+                .unwrap();
+        }
+        unix::fs::symlink("v1", dxvk.join("latest-4")).unwrap();
+
+        let pinned = HashSet::from([("dxvk", "v2".to_owned())]);
+        let stale = stale_versions(&dir, &pinned, 2).unwrap();
+        assert_eq!(stale, [dxvk.join("v3")]);
+
+        let stale = stale_versions(&dir, &HashSet::new(), 0).unwrap();
+        assert_eq!(
+            stale,
+            [
+                dxvk.join("v2"),
+                dxvk.join("v3"),
+                dxvk.join("v4"),
+                dxvk.join("v5")
+            ]
+        );
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
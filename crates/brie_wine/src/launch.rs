@@ -1,22 +1,36 @@
-use std::{borrow::Cow, env::VarError, fs, io, path::Path};
+use std::{
+    borrow::Cow,
+    env::VarError,
+    fs, io,
+    os::unix::process::CommandExt,
+    path::{Path, PathBuf},
+    time::Duration,
+};
 
-use brie_cfg::Tokens;
-use fslock::LockFile;
+use brie_cfg::{
+    Arch, ByteSize, DisplayBackend, Library, ReleaseVersion, Runtime, SearchPath, Tokens, Tools,
+};
 use indexmap::IndexMap;
-use log::info;
+use log::{info, warn};
 use rayon::iter::{IntoParallelRefIterator, ParallelIterator};
+use serde::Serialize;
 
 use crate::{
-    command::Runner,
+    command::{log_env, Runner},
     library::{
         ensure_cabextract_exists, ensure_library_exists, ensure_winetricks_exists, Downloadable,
+        LastCheck, PostExtract,
     },
-    runtime, state, WithContext,
+    lock, prefixes, priority, registry, runtime, state,
+    template::Template,
+    WithContext,
 };
 use crate::{dll, library};
 use crate::{join, runtime::ensure_runtime_exists};
 use crate::{
-    prepare::{BeforeError, MountsError, WinePrefixError, WinetricksError},
+    prepare::{
+        BeforeError, DriveError, InstallError, MountsError, WinePrefixError, WinetricksError,
+    },
     Paths, Unit,
 };
 
@@ -34,10 +48,16 @@ pub enum Error {
     Tricks(#[from] WinetricksError),
     #[error("Unable to symlink mounts. {0}")]
     Mounts(#[from] MountsError),
+    #[error("Unable to set drive c: volume information. {0}")]
+    Drive(#[from] DriveError),
+    #[error("Unable to apply registry values. {0}")]
+    Registry(#[from] registry::Error),
+    #[error("Installer error. {0}")]
+    Install(#[from] InstallError),
     #[error("Before command error. {0}")]
     Before(#[from] BeforeError),
     #[error("Lock error. {0}")]
-    Lock(#[source] io::Error),
+    Lock(#[from] lock::Error),
     #[error("Unable to write state file. {0}")]
     StateWrite(#[source] io::Error),
     #[error("Unable to create libraries folder. {0}")]
@@ -46,10 +66,18 @@ pub enum Error {
     Runner(#[source] io::Error),
     #[error("Wineserver wait error. {0}")]
     Wait(#[source] io::Error),
+    #[error("Wine prefix {0} does not exist.")]
+    PrefixNotFound(PathBuf),
     #[error("Run error. {0}")]
     Run(#[source] io::Error),
+    #[error("Unable to prepare the runtime backend. {0}")]
+    Backend(#[source] io::Error),
     #[error("Unable to expand path. {0}")]
     Expand(#[from] shellexpand::LookupError<VarError>),
+    #[error("Unable to create shader cache directory. {0}")]
+    ShaderCache(#[source] io::Error),
+    #[error("Not enough free space for the wine prefix, {0} is available and {1} is required.")]
+    LowSpace(ByteSize, ByteSize),
 }
 
 impl<T> WithContext<Result<T, Error>, &'static str> for Result<T, library::Error> {
@@ -58,14 +86,339 @@ impl<T> WithContext<Result<T, Error>, &'static str> for Result<T, library::Error
     }
 }
 
-pub fn launch(paths: &Paths, tokens: &Tokens, unit: Unit) -> Result<(), Error> {
+/// Launch settings which are not a part of the unit configuration
+#[derive(Debug, Default)]
+pub struct Options {
+    /// Fail instead of waiting when a lock is held by another brie instance
+    pub no_wait: bool,
+    /// Give up waiting for a lock held by another brie instance after this duration
+    pub wait_timeout: Option<Duration>,
+    /// Use only downloaded versions, without checking for updates
+    pub offline: bool,
+    /// Helper tool versions and settings
+    pub tools: Tools,
+    /// Only prepare the wine prefix, without running the unit command
+    pub prefix_only: bool,
+    /// Remove copied library dlls and tracked overrides, and install them from scratch
+    pub reinstall_libraries: bool,
+    /// Commands run in the directory of a library after it is downloaded and extracted
+    pub post_extract: PostExtract,
+    /// Number of registry backups kept when a prefix is updated to another wine version
+    pub prefix_backups: usize,
+    /// Time between checks for new `latest` releases, a day if not set. Zero never checks
+    pub latest_check_interval: Option<Duration>,
+}
+
+impl Options {
+    fn wait(&self) -> lock::Wait {
+        match (self.no_wait, self.wait_timeout) {
+            (true, _) => lock::Wait::No,
+            (false, Some(timeout)) => lock::Wait::Timeout(timeout),
+            (false, None) => lock::Wait::Forever,
+        }
+    }
+}
+
+pub fn launch(
+    paths: &Paths,
+    tokens: &Tokens,
+    mut unit: Unit,
+    options: &Options,
+) -> Result<(), Error> {
     info!("Preparing to launch unit: {unit:#?}");
     info!("Paths: {paths:?}");
 
+    // Resolved once, since detection is also needed when installing libraries
+    let nvidia = *unit.nvidia.get_or_insert_with(dll::has_nvidia_gpu);
+    if !nvidia {
+        dll::remove_nvidia_libraries(&mut unit.libraries);
+    }
+
+    // DPI is applied along with the other registry values, and tracked in the same state file
+    if let Some(dpi) = unit.dpi {
+        unit.registry.push(registry::dpi(dpi));
+    }
+
+    check_free_space(&paths.prefixes.join(&unit.prefix), &unit)?;
+
+    let (wine, libraries) =
+        download_dependencies(paths, tokens, &unit.runtime, &unit.libraries, options)?;
+
+    let search_path = SearchPath {
+        prepend: expand_all(&unit.path.prepend)?,
+        append: expand_all(&unit.path.append)?,
+    };
+
+    if let Some(dir) = &unit.shader_cache {
+        fs::create_dir_all(shader_cache_dir(paths, dir)?).map_err(Error::ShaderCache)?;
+    }
+
+    let (env, unset) = unit_env(&mut unit, paths)?;
+    let prefix = paths.prefixes.join(&unit.prefix);
+    let mut runner =
+        Runner::new(paths, wine, env, &search_path, &prefix, &libraries).map_err(Error::Runner)?;
+    for key in unset {
+        runner.unset_env(key);
+    }
+
+    let wine_version = match runner.wine_version() {
+        Ok(version) if !version.is_empty() => {
+            info!("Wine version: {version}");
+            Some(version)
+        }
+        Ok(_) => {
+            warn!("Unable to get wine version, it is empty");
+            None
+        }
+        Err(err) => {
+            warn!("Unable to get wine version. {err}");
+            None
+        }
+    };
+
+    prepare_prefix(
+        &runner,
+        paths,
+        &unit,
+        &libraries,
+        wine_version.as_deref(),
+        options,
+    )?;
+
+    if options.prefix_only {
+        info!("Wine prefix is prepared, skipping launch");
+        return Ok(());
+    }
+
+    let background = unit.background;
+    if unit.command.is_empty() {
+        warn!("The unit has no command, the wine prefix is prepared without running anything");
+    } else {
+        run(&runner, unit)?;
+    }
+
+    // Prefix and library locks are already released at this point
+    if background {
+        info!("Running in background, not waiting for wineserver to exit");
+        return Ok(());
+    }
+
+    info!("Waiting for wineserver to exit");
+    runner.wait().map_err(Error::Wait)?;
+
+    Ok(())
+}
+
+/// Runs the unit command in a prepared prefix, with the program and env of the runtime backend
+fn run(runner: &Runner, unit: Unit) -> Result<(), Error> {
+    let template = Template::new(runner.wine_prefix(), &unit.mounts);
+    let cd = working_dir(unit.cd.as_deref(), runner.wine_prefix(), &template)?;
+    let backend = runtime::backend(&unit.runtime);
+
+    info!("Running: {:?} in {}", unit.command, cd.display());
+    let unit_command = unit_command(&unit, &template);
+    let mut command = unit.wrapper;
+    command.extend(backend.command(runner.wine()));
+    command.extend(unit_command);
+
+    // Variables set in the unit take precedence over the ones required by the backend
+    let backend_env = backend
+        .env(runner.wine_prefix())
+        .map_err(Error::Backend)?
+        .into_iter()
+        .filter(|(key, _)| !runner.envs().contains_key(*key))
+        .collect::<Vec<_>>();
+
+    if unit.debug {
+        log_env(runner.envs());
+        info!("Backend env: {backend_env:?}");
+        info!("Command: {command:?}");
+    }
+
+    let mut command = runner.command(&command[0], &command[1..]);
+    command.envs(backend_env);
+    command.current_dir(cd);
+    priority::set_priority(&mut command, unit.nice, unit.ionice);
+
+    if unit.background {
+        // A separate process group isn't interrupted along with brie, e.g. by Ctrl+C in the terminal
+        command.process_group(0);
+        let child = command.spawn().map_err(Error::Run)?;
+        info!("Started in background with pid {}", child.id());
+        return Ok(());
+    }
+
+    let status = command.status().map_err(Error::Run)?;
+    if !status.success() {
+        warn!("Command exited with {status}");
+    }
+
+    Ok(())
+}
+
+/// A unit as it would be launched, after defaults, config merges and overrides are applied
+#[derive(Debug, Serialize)]
+pub struct Resolved {
+    pub runtime: Runtime,
+    /// Release the runtime version was last resolved to, or the tag itself
+    pub runtime_version: Option<String>,
+    /// Libraries which are installed, NVIDIA specific ones only with an NVIDIA GPU
+    pub libraries: IndexMap<Library, ResolvedVersion>,
+    pub prefix: PathBuf,
+    /// Unit env, with variables derived from `user`, `library_paths`, `vulkan_device`, `shader_cache` and `display`
+    pub env: IndexMap<String, String>,
+    /// Host variables removed from the environment
+    pub unset: Vec<&'static str>,
+    pub cd: PathBuf,
+    pub wrapper: Vec<String>,
+    /// Command with templates applied, run with the runtime wine
+    pub command: Vec<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ResolvedVersion {
+    pub version: ReleaseVersion,
+    /// Release the version was last resolved to, unknown until it is downloaded
+    pub resolved: Option<String>,
+}
+
+/// Resolves the unit the same way [`launch`] does, using only the cached state of downloads.
+/// Nothing is downloaded and the wine prefix is not touched.
+pub fn resolve(paths: &Paths, mut unit: Unit) -> Result<Resolved, Error> {
+    let state = state::read(&paths.libraries);
+    let resolved = |name: &str, version: &ReleaseVersion| match version {
+        ReleaseVersion::Tag(tag) => Some(tag.clone()),
+        ReleaseVersion::Latest | ReleaseVersion::LatestOffset(_) => {
+            state.resolved(name, version).map(str::to_owned)
+        }
+    };
+
+    let runtime_version =
+        runtime::downloaded(&unit.runtime).and_then(|(name, version)| resolved(name, version));
+
+    if !*unit.nvidia.get_or_insert_with(dll::has_nvidia_gpu) {
+        dll::remove_nvidia_libraries(&mut unit.libraries);
+    }
+    let libraries = std::mem::take(&mut unit.libraries)
+        .into_iter()
+        .map(|(library, version)| {
+            let resolved = resolved(library.name(), &version);
+            (library, ResolvedVersion { version, resolved })
+        })
+        .collect();
+
+    let (env, unset) = unit_env(&mut unit, paths)?;
+    let prefix = paths.prefixes.join(&unit.prefix);
+    let template = Template::new(&prefix, &unit.mounts);
+    let cd = working_dir(unit.cd.as_deref(), &prefix, &template)?;
+    let command = unit_command(&unit, &template);
+
+    Ok(Resolved {
+        runtime: unit.runtime,
+        runtime_version,
+        libraries,
+        prefix,
+        env,
+        unset,
+        cd,
+        wrapper: unit.wrapper,
+        command,
+    })
+}
+
+/// Installs library dlls and their dll overrides into an existing wine prefix which is not
+/// managed by brie, e.g. a Steam Proton prefix. `runtime` is only used to import the overrides,
+/// it should match the wine version the prefix is used with.
+pub fn install_libraries(
+    paths: &Paths,
+    tokens: &Tokens,
+    prefix: &Path,
+    runtime: &Runtime,
+    mut libraries: IndexMap<Library, ReleaseVersion>,
+    options: &Options,
+) -> Result<(), Error> {
+    if !prefix.is_dir() {
+        return Err(Error::PrefixNotFound(prefix.to_owned()));
+    }
+
+    let nvidia = dll::has_nvidia_gpu();
+    if !nvidia {
+        dll::remove_nvidia_libraries(&mut libraries);
+    }
+
+    let (wine, libraries) = download_dependencies(paths, tokens, runtime, &libraries, options)?;
+    let runner = Runner::new(
+        paths,
+        wine,
+        IndexMap::new(),
+        &SearchPath::default(),
+        prefix,
+        &libraries,
+    )
+    .map_err(Error::Runner)?;
+
+    info!("Obtaining a lock on wineprefix");
+    let lock = lock::acquire(&prefix.join(".brie.lock"), "wine prefix", options.wait())?;
+    let arch = [Arch::X64, Arch::X86];
+    if options.reinstall_libraries {
+        runner.uninstall_libraries(&libraries, &arch)?;
+    }
+    runner.install_libraries(&libraries, &arch, nvidia, false, &[])?;
+    runner.wait().map_err(Error::Wait)?;
+    drop(lock);
+
+    Ok(())
+}
+
+/// Downloads runtimes, helper tools and libraries of the units without preparing prefixes,
+/// e.g. to fill the cache before going offline. NVIDIA specific libraries are selected as on launch.
+pub fn prefetch(
+    paths: &Paths,
+    tokens: &Tokens,
+    units: &[Unit],
+    options: &Options,
+) -> Result<(), Error> {
+    let detected = dll::has_nvidia_gpu();
+    let mut done = Vec::<(&Runtime, IndexMap<Library, ReleaseVersion>)>::new();
+
+    for unit in units {
+        let mut libraries = unit.libraries.clone();
+        if !unit.nvidia.unwrap_or(detected) {
+            dll::remove_nvidia_libraries(&mut libraries);
+        }
+
+        // Units often share the same runtime and libraries
+        if done
+            .iter()
+            .any(|(runtime, l)| **runtime == unit.runtime && *l == libraries)
+        {
+            continue;
+        }
+
+        info!("Prefetching dependencies of {}", unit.prefix);
+        download_dependencies(paths, tokens, &unit.runtime, &libraries, options)?;
+        done.push((&unit.runtime, libraries));
+    }
+
+    Ok(())
+}
+
+/// Downloads the runtime, helper tools and libraries.
+/// Returns paths to the wine binary and to the libraries.
+fn download_dependencies(
+    paths: &Paths,
+    tokens: &Tokens,
+    runtime: &Runtime,
+    versions: &IndexMap<Library, ReleaseVersion>,
+    options: &Options,
+) -> Result<(PathBuf, IndexMap<Library, PathBuf>), Error> {
     info!("Obtaining a lock on dependency download");
     fs::create_dir_all(&paths.libraries).map_err(Error::Libraries)?;
-    let mut lock = LockFile::open(&paths.libraries.join(".brie.lock")).map_err(Error::Lock)?;
-    lock.lock_with_pid().map_err(Error::Lock)?;
+    let lock = lock::acquire(
+        &paths.libraries.join(".brie.lock"),
+        "libraries",
+        options.wait(),
+    )?;
 
     let mut state = state::read(&paths.libraries);
 
@@ -74,13 +427,30 @@ pub fn launch(paths: &Paths, tokens: &Tokens, unit: Unit) -> Result<(), Error> {
         || ensure_runtime_exists(
             tokens,
             &paths.libraries,
-            &unit.runtime,
-            state.wine.and_then(|t| t.elapsed().ok())
+            runtime,
+            &LastCheck {
+                elapsed: state.wine.and_then(|t| t.elapsed().ok()),
+                version: runtime::downloaded(runtime)
+                    .and_then(|(name, version)| state.resolved(name, version)),
+                interval: options.latest_check_interval,
+            },
+            &options.post_extract,
+            options.offline,
         ),
-        || ensure_winetricks_exists(&paths.libraries).context("winetricks"),
-        || ensure_cabextract_exists(&paths.libraries).context("cabextract"),
+        || ensure_winetricks_exists(
+            &paths.libraries,
+            options.tools.winetricks.as_deref(),
+            options.offline
+        )
+        .context("winetricks"),
+        || ensure_cabextract_exists(
+            &paths.libraries,
+            options.tools.cabextract.as_deref(),
+            options.offline
+        )
+        .context("cabextract"),
         || {
-            unit.libraries
+            versions
                 .par_iter()
                 .map(|(l, version)| {
                     ensure_library_exists(
@@ -88,7 +458,13 @@ pub fn launch(paths: &Paths, tokens: &Tokens, unit: Unit) -> Result<(), Error> {
                         &paths.libraries,
                         tokens,
                         version,
-                        state.libraries.get(l).and_then(|t| t.elapsed().ok()),
+                        &LastCheck {
+                            elapsed: state.libraries.get(l).and_then(|t| t.elapsed().ok()),
+                            version: state.resolved(l.name(), version),
+                            interval: options.latest_check_interval,
+                        },
+                        &options.post_extract,
+                        options.offline,
                     )
                     .map(|path| (*l, path))
                     .context(l.name())
@@ -107,11 +483,25 @@ pub fn launch(paths: &Paths, tokens: &Tokens, unit: Unit) -> Result<(), Error> {
     if wine.updated {
         state.wine = Some(std::time::SystemTime::now());
     }
+    if let (Some(resolved), Some((name, version))) = (&wine.version, runtime::downloaded(runtime)) {
+        state.set_resolved(name, version, resolved.clone());
+    }
+
+    // Downloaded runtimes may be referenced via a `latest` symlink, resolve it to show the version
+    let resolved = fs::canonicalize(&wine.path);
+    info!(
+        "Using wine binary {} for runtime {:?}",
+        resolved.as_ref().unwrap_or(&wine.path).display(),
+        runtime
+    );
 
     for (&l, s) in &libraries {
         if s.updated {
             state.libraries.insert(l, std::time::SystemTime::now());
         }
+        if let Some(resolved) = &s.version {
+            state.set_resolved(l.name(), &versions[&l], resolved.clone());
+        }
     }
 
     state::write(&paths.libraries, &state).map_err(Error::StateWrite)?;
@@ -121,55 +511,410 @@ pub fn launch(paths: &Paths, tokens: &Tokens, unit: Unit) -> Result<(), Error> {
         .map(|(l, path)| (l, path.path))
         .collect::<IndexMap<_, _>>();
 
-    let runner =
-        Runner::new(paths, wine.path, unit.env, &unit.prefix, &libraries).map_err(Error::Runner)?;
-    runner.prepare_wine_prefix()?;
+    Ok((wine.path, libraries))
+}
+
+/// Prepares the wine prefix while holding its lock. Only the prefix directory
+/// is created before the lock is acquired, since the lock may be kept in it
+fn prepare_prefix(
+    runner: &Runner,
+    paths: &Paths,
+    unit: &Unit,
+    libraries: &IndexMap<Library, PathBuf>,
+    wine_version: Option<&str>,
+    options: &Options,
+) -> Result<(), Error> {
+    fs::create_dir_all(runner.wine_prefix()).map_err(WinePrefixError::Mkdir)?;
 
     info!("Obtaining a lock on wineprefix");
-    let mut lock = LockFile::open(&runner.wine_prefix().join(".brie.lock")).map_err(Error::Lock)?;
-    lock.lock_with_pid().map_err(Error::Lock)?;
-    runner.winetricks(&unit.winetricks)?;
+    let lock = lock::acquire(
+        &runner.wine_prefix().join(".brie.lock"),
+        "wine prefix",
+        options.wait(),
+    )?;
+    runner.prepare_wine_prefix(
+        unit.user.as_deref(),
+        &unit.link_home_dirs,
+        wine_version,
+        options.prefix_backups,
+    )?;
+    let winetricks_cache = paths.libraries.join(".winetricks-cache");
+    let winetricks_cache = options
+        .tools
+        .shared_winetricks_cache
+        .then_some(&*winetricks_cache);
+    runner.before(&unit.before_winetricks)?;
+    runner.winetricks(&unit.winetricks, winetricks_cache)?;
+    runner.winetricks_settings(&unit.winetricks_settings)?;
     runner.mounts(&unit.mounts)?;
-    runner.install_libraries(&libraries)?;
+    if let Some(drive_c) = &unit.drive_c {
+        runner.drive_info('c', drive_c)?;
+    }
+    if options.reinstall_libraries {
+        runner.uninstall_libraries(libraries, &unit.arch)?;
+    }
+    let nvidia = unit.nvidia.unwrap_or(true);
+    runner.install_libraries(
+        libraries,
+        &unit.arch,
+        nvidia,
+        unit.link_dlls,
+        &unit.exclude_dlls,
+    )?;
+    runner.registry(&unit.registry)?;
+    if let Some(installer) = &unit.install {
+        runner.install(installer)?;
+    }
     runner.before(&unit.before)?;
-    runner.run("wineserver", &["--wait"]).map_err(Error::Wait)?;
+    runner.wait().map_err(Error::Wait)?;
     drop(lock);
 
-    if !unit.command.is_empty() {
-        let cd = unit.cd.as_ref().map(shellexpand::full).transpose()?;
-        let cd = cd.as_deref().map_or_else(
-            || Cow::Owned(runner.wine_prefix().join("drive_c")),
-            |p| Cow::Borrowed(Path::new(p)),
-        );
+    Ok(())
+}
+
+fn expand_all(paths: &[String]) -> Result<Vec<String>, shellexpand::LookupError<VarError>> {
+    paths
+        .iter()
+        .map(|p| shellexpand::full(p).map(Cow::into_owned))
+        .collect()
+}
 
-        info!("Running: {:?} in {}", unit.command, cd.display());
-        let mut command = unit.wrapper;
-        command.push("wine".into());
-        command.extend(unit.command);
+/// Resolves the directory a unit command is run in.
+/// `~` and environment variables are expanded, followed by `{prefix}`-like templates.
+/// Absolute paths are used as-is.
+/// Windows paths with a drive letter (e.g. `d:/bin`) are resolved in the prefix `dosdevices`,
+/// other relative paths are relative to `drive_c`, which is also the default.
+fn working_dir(cd: Option<&str>, prefix: &Path, template: &Template) -> Result<PathBuf, Error> {
+    let Some(cd) = cd else {
+        return Ok(prefix.join("drive_c"));
+    };
 
-        let mut command = runner.command(&command[0], &command[1..]);
-        command.current_dir(cd);
-        command.status().map_err(Error::Run)?;
+    let cd = template.apply(&shellexpand::full(cd)?);
+    let path = Path::new(&cd);
+    if path.is_absolute() {
+        return Ok(path.to_owned());
     }
 
-    info!("Waiting for wineserver to exit");
-    runner.run("wineserver", &["--wait"]).map_err(Error::Wait)?;
+    let mut chars = cd.chars();
+    match (chars.next(), chars.next(), chars.next()) {
+        (Some(drive), Some(':'), None | Some('/' | '\\')) if drive.is_ascii_alphabetic() => {
+            let rest = cd[2..].replace('\\', "/");
+            let drive = format!("{}:", drive.to_ascii_lowercase());
+            Ok(prefix
+                .join("dosdevices")
+                .join(drive)
+                .join(rest.trim_start_matches('/')))
+        }
+        _ => Ok(prefix.join("drive_c").join(path)),
+    }
+}
 
-    Ok(())
+/// Applies templates to the unit command. A program given as a host path under a mount
+/// is run by its path on the mounted drive, the same way it is seen from inside the prefix
+fn unit_command(unit: &Unit, template: &Template) -> Vec<String> {
+    let mut command = unit
+        .command
+        .iter()
+        .map(|arg| template.apply(arg))
+        .collect::<Vec<_>>();
+
+    if let Some(program) = command.first_mut() {
+        if let Some(path) = drive_path(&unit.mounts, program) {
+            info!("Running {program} from the mounted drive as {path}");
+            *program = path;
+        }
+    }
+
+    command
+}
+
+/// Converts an absolute host path under a mount target to a windows path on the mounted drive,
+/// e.g. `/mnt/games/bin/game.exe` with `d: /mnt/games` to `d:\bin\game.exe`.
+/// With nested mount targets the innermost one is used
+fn drive_path(mounts: &IndexMap<char, String>, path: &str) -> Option<String> {
+    let path = Path::new(path);
+    if !path.is_absolute() {
+        return None;
+    }
+
+    mounts
+        .iter()
+        .filter_map(|(drive, target)| {
+            let target = shellexpand::full(target).ok()?;
+            let rest = path.strip_prefix(&*target).ok()?;
+            Some((drive, rest))
+        })
+        .min_by_key(|(_, rest)| rest.components().count())
+        .map(|(drive, rest)| {
+            let rest = rest.to_string_lossy().replace('/', "\\");
+            format!("{drive}:\\{rest}")
+        })
+}
+
+/// Builds the unit environment. Variables derived from unit settings don't override
+/// explicitly configured ones. Returns the environment and host variables to unset.
+fn unit_env(
+    unit: &mut Unit,
+    paths: &Paths,
+) -> Result<(IndexMap<String, String>, Vec<&'static str>), Error> {
+    let mut env = std::mem::take(&mut unit.env);
+    if let Some(user) = &unit.user {
+        // Wine names the user profile folder after `USER` when the prefix is created
+        env.insert("USER".to_owned(), user.clone());
+    }
+    append_library_paths(&mut env, &expand_all(&unit.library_paths)?);
+    if let Some(device) = &unit.vulkan_device {
+        for (key, value) in vulkan_device_env(device) {
+            env.entry(key.to_owned()).or_insert(value);
+        }
+    }
+    if let Some(dir) = &unit.shader_cache {
+        for (key, value) in shader_cache_env(&shader_cache_dir(paths, dir)?) {
+            env.entry(key.to_owned()).or_insert(value);
+        }
+    }
+
+    let mut unset = Vec::new();
+    if let Some(backend) = unit.display {
+        let wayland_session = std::env::var_os("WAYLAND_DISPLAY").is_some();
+        let (set, remove) = display_env(backend, wayland_session);
+        for &(key, value) in set {
+            if !env.contains_key(key) {
+                info!("Setting {key}={value} for {backend:?} display backend");
+                env.insert(key.to_owned(), value.to_owned());
+            }
+        }
+        for &key in remove.iter().filter(|key| !env.contains_key(**key)) {
+            info!("Unsetting {key} for {backend:?} display backend");
+            unset.push(key);
+        }
+    }
+
+    Ok((env, unset))
+}
+
+/// Environment changes selecting a display backend, as variables to set and variables to unset.
+/// Wine and SDL use X11 when `WAYLAND_DISPLAY` is not set, and Wayland when `DISPLAY` is not set,
+/// so nothing changes outside of a Wayland session.
+fn display_env(
+    backend: DisplayBackend,
+    wayland_session: bool,
+) -> (
+    &'static [(&'static str, &'static str)],
+    &'static [&'static str],
+) {
+    match (backend, wayland_session) {
+        (DisplayBackend::X11, true) => (&[("SDL_VIDEODRIVER", "x11")], &["WAYLAND_DISPLAY"]),
+        (DisplayBackend::Wayland, true) => (&[("SDL_VIDEODRIVER", "wayland")], &["DISPLAY"]),
+        (DisplayBackend::Wayland, false) => {
+            warn!("Wayland display backend requested outside of a Wayland session, ignoring");
+            (&[], &[])
+        }
+        (DisplayBackend::Auto | DisplayBackend::X11, _) => (&[], &[]),
+    }
+}
+
+/// Appends host library directories to `LD_LIBRARY_PATH` from the unit env, or the host one
+fn append_library_paths(env: &mut IndexMap<String, String>, paths: &[String]) {
+    if paths.is_empty() {
+        return;
+    }
+
+    let current = match env.get("LD_LIBRARY_PATH") {
+        Some(current) => Some(current.clone()),
+        None => std::env::var("LD_LIBRARY_PATH").ok(),
+    };
+    let value = current
+        .iter()
+        .filter(|p| !p.is_empty())
+        .chain(paths)
+        .map(String::as_str)
+        .collect::<Vec<_>>()
+        .join(":");
+
+    env.insert("LD_LIBRARY_PATH".to_owned(), value);
+}
+
+/// Environment variables selecting a vulkan device.
+/// `vendorID:deviceID` pairs (e.g. `10de:2684`) go to the mesa device select layer, which reorders
+/// devices for any vulkan application. Anything else is a device name filter for DXVK and VKD3D-Proton.
+fn vulkan_device_env(device: &str) -> Vec<(&'static str, String)> {
+    let is_id = |id: &str| id.len() == 4 && id.chars().all(|c| c.is_ascii_hexdigit());
+    match device.split_once(':') {
+        Some((vendor, id)) if is_id(vendor) && is_id(id) => {
+            vec![("MESA_VK_DEVICE_SELECT", device.to_owned())]
+        }
+        _ => vec![
+            ("DXVK_FILTER_DEVICE_NAME", device.to_owned()),
+            ("VKD3D_FILTER_DEVICE_NAME", device.to_owned()),
+        ],
+    }
+}
+
+/// Warns, or fails with `refuse_low_space`, when the prefix filesystem has less than `min_free_space` free.
+/// The check is skipped if free space can't be determined
+fn check_free_space(prefix: &Path, unit: &Unit) -> Result<(), Error> {
+    let Some(required) = unit.min_free_space else {
+        return Ok(());
+    };
+
+    let available = match prefixes::free_space(prefix) {
+        Ok(available) => ByteSize(available),
+        Err(err) => {
+            warn!("Unable to check free space of {}. {err}", prefix.display());
+            return Ok(());
+        }
+    };
+
+    if available >= required {
+        return Ok(());
+    }
+
+    match unit.refuse_low_space {
+        true => Err(Error::LowSpace(available, required)),
+        false => {
+            warn!("Only {available} is free for the wine prefix, while {required} is required");
+            Ok(())
+        }
+    }
+}
+
+/// Shader cache directory of a unit, `~` and environment variables are expanded
+fn shader_cache_dir(paths: &Paths, dir: &str) -> Result<PathBuf, Error> {
+    Ok(paths.shader_cache.join(&*shellexpand::full(dir)?))
+}
+
+/// Environment variables pointing shader caches of NVIDIA, DXVK and mesa drivers to a directory
+fn shader_cache_env(dir: &Path) -> [(&'static str, String); 4] {
+    let dir = dir.to_string_lossy();
+    [
+        ("__GL_SHADER_DISK_CACHE", "1".to_owned()),
+        ("__GL_SHADER_DISK_CACHE_PATH", dir.to_string()),
+        ("DXVK_STATE_CACHE_PATH", dir.to_string()),
+        ("MESA_SHADER_CACHE_DIR", dir.to_string()),
+    ]
 }
 
 #[cfg(test)]
 mod tests {
     use std::path::Path;
 
-    use brie_cfg::{Library, ReleaseVersion, Runtime, Tokens};
+    use brie_cfg::{Arch, DisplayBackend, Library, ReleaseVersion, Runtime, SearchPath, Tokens};
     use brie_download::mp;
     use indexmap::IndexMap;
     use indicatif_log_bridge::LogWrapper;
 
-    use crate::{Paths, Unit};
+    use crate::{template::Template, Paths, Unit};
+
+    use super::{
+        append_library_paths, display_env, drive_path, launch, shader_cache_dir, shader_cache_env,
+        vulkan_device_env, working_dir, Options,
+    };
+
+    #[test]
+    fn display() {
+        let none: (&[(&str, &str)], &[&str]) = (&[], &[]);
+        assert_eq!(
+            display_env(DisplayBackend::X11, true),
+            (&[("SDL_VIDEODRIVER", "x11")][..], &["WAYLAND_DISPLAY"][..])
+        );
+        assert_eq!(
+            display_env(DisplayBackend::Wayland, true),
+            (&[("SDL_VIDEODRIVER", "wayland")][..], &["DISPLAY"][..])
+        );
+        assert_eq!(display_env(DisplayBackend::X11, false), none);
+        assert_eq!(display_env(DisplayBackend::Auto, true), none);
+    }
+
+    #[test]
+    fn library_paths() {
+        let mut env = IndexMap::from([("LD_LIBRARY_PATH".to_owned(), "/opt/lib".to_owned())]);
+        append_library_paths(&mut env, &["/sdk/lib".to_owned(), "/sdk/lib64".to_owned()]);
+        assert_eq!(env["LD_LIBRARY_PATH"], "/opt/lib:/sdk/lib:/sdk/lib64");
+
+        let mut env = IndexMap::from([("LD_LIBRARY_PATH".to_owned(), String::new())]);
+        append_library_paths(&mut env, &["/sdk/lib".to_owned()]);
+        assert_eq!(env["LD_LIBRARY_PATH"], "/sdk/lib");
 
-    use super::launch;
+        let mut env = IndexMap::new();
+        append_library_paths(&mut env, &[]);
+        assert!(env.is_empty());
+    }
+
+    #[test]
+    fn shader_cache() {
+        let paths = Paths::new(Path::new("/data"));
+        let dir = shader_cache_dir(&paths, "witcher3").unwrap();
+        assert_eq!(dir, Path::new("/data/shader-cache/witcher3"));
+        let dir = shader_cache_dir(&paths, "/games/cache").unwrap();
+        assert_eq!(dir, Path::new("/games/cache"));
+
+        let env = shader_cache_env(&dir);
+        assert_eq!(env[0], ("__GL_SHADER_DISK_CACHE", "1".to_owned()));
+        assert!(env[1..].iter().all(|(_, value)| value == "/games/cache"));
+    }
+
+    #[test]
+    fn vulkan_device() {
+        assert_eq!(
+            vulkan_device_env("10de:2684"),
+            [("MESA_VK_DEVICE_SELECT", "10de:2684".to_owned())]
+        );
+        assert_eq!(
+            vulkan_device_env("RTX 4090"),
+            [
+                ("DXVK_FILTER_DEVICE_NAME", "RTX 4090".to_owned()),
+                ("VKD3D_FILTER_DEVICE_NAME", "RTX 4090".to_owned()),
+            ]
+        );
+    }
+
+    #[test]
+    fn mounted_command() {
+        let mounts = [
+            ('d', "/mnt/games".to_owned()),
+            ('e', "/mnt/games/ssd".to_owned()),
+        ]
+        .into();
+        let path = |path| drive_path(&mounts, path);
+
+        assert_eq!(
+            path("/mnt/games/bin/game.exe").as_deref(),
+            Some("d:\\bin\\game.exe")
+        );
+        assert_eq!(
+            path("/mnt/games/ssd/game.exe").as_deref(),
+            Some("e:\\game.exe")
+        );
+        assert_eq!(path("/mnt/games-old/game.exe"), None);
+        assert_eq!(path("d:/bin/game.exe"), None);
+        assert_eq!(path("game.exe"), None);
+    }
+
+    #[test]
+    fn cd() {
+        let prefix = Path::new("/prefixes/game");
+        let template = Template::new(prefix, &[('d', "/mnt/games".to_owned())].into());
+        let cd = |cd| working_dir(cd, prefix, &template).unwrap();
+
+        assert_eq!(cd(None), prefix.join("drive_c"));
+        assert_eq!(cd(Some("/mnt/games/bin")), Path::new("/mnt/games/bin"));
+        assert_eq!(cd(Some("Games/bin")), prefix.join("drive_c/Games/bin"));
+        assert_eq!(cd(Some("d:/bin/x64")), prefix.join("dosdevices/d:/bin/x64"));
+        assert_eq!(cd(Some("D:\\bin")), prefix.join("dosdevices/d:/bin"));
+        assert_eq!(cd(Some("d:")), prefix.join("dosdevices/d:"));
+
+        let home = std::env::var("HOME").unwrap();
+        assert_eq!(cd(Some("$HOME/games")), Path::new(&home).join("games"));
+        let home = shellexpand::tilde("~").to_string();
+        assert_eq!(cd(Some("~/games")), Path::new(&home).join("games"));
+
+        assert_eq!(cd(Some("{drive_c}/bin")), prefix.join("drive_c/bin"));
+        assert_eq!(
+            cd(Some("{game_drive}/bin")),
+            prefix.join("dosdevices/d:/bin")
+        );
+    }
 
     #[test]
     #[ignore]
@@ -185,9 +930,10 @@ mod tests {
             &Paths {
                 libraries: Path::new(".tmp").join("libraries"),
                 prefixes: Path::new(".tmp").join("prefixes"),
+                shader_cache: Path::new(".tmp").join("shader-cache"),
             },
             &Tokens {
-                steamgriddb: None,
+                steamgriddb: vec![],
                 github: None,
             },
             Unit {
@@ -200,8 +946,15 @@ mod tests {
                     (Library::Vkd3dProton, ReleaseVersion::Latest),
                 ]
                 .into(),
+                arch: vec![Arch::X64, Arch::X86],
+                link_dlls: false,
+                exclude_dlls: vec![],
                 env: IndexMap::default(),
+                path: SearchPath::default(),
+                library_paths: vec![],
                 prefix: "TEST_PREFIX".into(),
+                link_home_dirs: vec![],
+                user: None,
 
                 cd: None,
                 command: vec![
@@ -212,9 +965,26 @@ mod tests {
                 ],
                 mounts: [('r', "/etc".into())].into(),
                 before: vec![],
+                before_winetricks: vec![],
                 winetricks: vec![],
+                winetricks_settings: vec![],
+                registry: vec![],
+                dpi: None,
+                install: None,
+                nvidia: None,
+                vulkan_device: None,
+                shader_cache: None,
+                display: None,
+                drive_c: None,
+                background: false,
+                min_free_space: None,
+                refuse_low_space: false,
                 wrapper: vec![],
+                nice: None,
+                ionice: None,
+                debug: false,
             },
+            &Options::default(),
         )
         .unwrap();
 
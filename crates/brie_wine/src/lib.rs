@@ -1,25 +1,55 @@
-use std::path::{Path, PathBuf};
+use std::{
+    env::VarError,
+    path::{Path, PathBuf},
+};
 
-use brie_cfg::{Library, ReleaseVersion, Runtime};
+use brie_cfg::{
+    Arch, ByteSize, DisplayBackend, DriveInfo, Installer, Library, RegEntry, ReleaseVersion,
+    Runtime, SearchPath,
+};
 use indexmap::IndexMap;
 
-pub use launch::{launch, Error};
+pub use archive::{export_prefix, import_prefix, Error as ArchiveError};
+pub use launch::{
+    install_libraries, launch, prefetch, resolve, Error, Options, Resolved, ResolvedVersion,
+};
 
-pub use brie_download::mp;
-pub use dll::{CopyError, Error as DllError};
+pub use brie_download::{mp, set_download_headers, set_progress_summary};
+pub use command::log_env;
+pub use dll::{
+    find_shared_library, library_dlls, Arch as DllArch, CopyError, Dlls, Error as DllError,
+    OverrideError,
+};
 pub use downloader::Error as DownloadError;
-pub use prepare::{BeforeError, MountsError, WinePrefixError, WinetricksError};
+pub use du::{disk_usage, DiskUsage, UsageKind};
+pub use gc::{prune_libraries, Error as GcError};
+pub use library::ARCHIVE_FORMATS;
+pub use lock::Error as LockError;
+pub use prefixes::{free_space, list_prefixes, PrefixInfo};
+pub use prepare::{
+    BeforeError, DriveError, InstallError, MountsError, WinePrefixError, WinetricksError,
+};
+pub use priority::set_priority;
+pub use registry::Error as RegistryError;
 pub use runtime::Error as RuntimeError;
 
+mod archive;
 mod command;
 mod dll;
 mod downloader;
+mod du;
+mod gc;
 mod launch;
 mod library;
+mod lock;
+mod prefixes;
 mod prepare;
+mod priority;
 mod rayon_join;
+mod registry;
 mod runtime;
 mod state;
+mod template;
 
 trait WithContext<Target, Context> {
     fn context(self, context: Context) -> Target;
@@ -29,23 +59,63 @@ trait WithContext<Target, Context> {
 pub struct Unit {
     pub runtime: Runtime,
     pub libraries: IndexMap<Library, ReleaseVersion>,
+    pub arch: Vec<Arch>,
+    /// Symlink library dlls instead of copying them
+    pub link_dlls: bool,
+    /// Library dlls which are neither installed nor overridden
+    pub exclude_dlls: Vec<String>,
 
     pub env: IndexMap<String, String>,
+    pub path: SearchPath,
+    /// Host library directories appended to `LD_LIBRARY_PATH`
+    pub library_paths: Vec<String>,
     pub prefix: String,
+    pub link_home_dirs: Vec<String>,
+    pub user: Option<String>,
 
     pub mounts: IndexMap<char, String>,
     pub before: Vec<Vec<String>>,
-    pub winetricks: Vec<String>,
+    /// Commands run before winetricks, e.g. to pre-seed files winetricks needs
+    pub before_winetricks: Vec<Vec<String>>,
+    pub winetricks: Vec<Vec<String>>,
+    /// Winetricks settings, applied when they change
+    pub winetricks_settings: Vec<String>,
+    pub registry: Vec<RegEntry>,
+    /// Wine DPI, applied as a registry value
+    pub dpi: Option<u32>,
+    pub install: Option<Installer>,
+    /// Whether NVIDIA specific libraries are installed, detected from the GPU if not set
+    pub nvidia: Option<bool>,
+    /// Vulkan device selection, translated to environment variables at launch
+    pub vulkan_device: Option<String>,
+    /// Shader cache directory, relative to [`Paths::shader_cache`]
+    pub shader_cache: Option<String>,
+    /// Display server backend, applied on a Wayland session
+    pub display: Option<DisplayBackend>,
+    /// Volume label and serial of `c:`
+    pub drive_c: Option<DriveInfo>,
+    /// Don't wait for the command and wineserver to exit
+    pub background: bool,
+    /// Free space expected on the prefix filesystem before launch
+    pub min_free_space: Option<ByteSize>,
+    /// Fail instead of warning when there is less than `min_free_space` free
+    pub refuse_low_space: bool,
 
     pub cd: Option<String>,
     pub command: Vec<String>,
     pub wrapper: Vec<String>,
+    pub nice: Option<i32>,
+    pub ionice: Option<u8>,
+    /// Log the composed environment and command before running it
+    pub debug: bool,
 }
 
 #[derive(Debug)]
 pub struct Paths {
     pub libraries: PathBuf,
     pub prefixes: PathBuf,
+    /// Base directory of unit shader caches
+    pub shader_cache: PathBuf,
 }
 
 impl Paths {
@@ -54,6 +124,26 @@ impl Paths {
         Self {
             libraries: data_home.join("libraries"),
             prefixes: data_home.join("prefixes"),
+            shader_cache: data_home.join("shader-cache"),
         }
     }
+
+    /// Uses directories set in the config instead of the defaults in `data_home`.
+    /// `~` and environment variables are expanded
+    pub fn from_config(
+        data_home: &Path,
+        paths: &brie_cfg::Paths,
+    ) -> Result<Self, shellexpand::LookupError<VarError>> {
+        let defaults = Self::new(data_home);
+        let path = |path: &Option<String>, default: PathBuf| match path {
+            Some(path) => shellexpand::full(path).map(|p| PathBuf::from(&*p)),
+            None => Ok(default),
+        };
+
+        Ok(Self {
+            libraries: path(&paths.libraries, defaults.libraries)?,
+            prefixes: path(&paths.prefixes, defaults.prefixes)?,
+            shader_cache: defaults.shader_cache,
+        })
+    }
 }
@@ -3,13 +3,15 @@ use std::{
     io::{self, Cursor, Read},
     os::unix::{self, fs::PermissionsExt},
     path::{Path, PathBuf},
+    process::{Command, ExitStatus},
     time::Duration,
 };
 
 use brie_cfg::{Library, ReleaseVersion, Tokens};
 use brie_download::download_file;
 use flate2::read::GzDecoder;
-use log::{debug, error, info};
+use indexmap::IndexMap;
+use log::{debug, error, info, warn};
 use tar::Archive;
 use thiserror::Error;
 use xz2::read::XzDecoder;
@@ -36,8 +38,15 @@ pub enum Error {
     Zip(#[from] zip::result::ZipError),
     #[error("Unknown library archive format for file {0}.")]
     UnknownFormat(String),
+    #[error("Post-extract command `{0}` failed with {1}.")]
+    PostExtract(String, ExitStatus),
+    #[error("`{0}` {1} is not downloaded, and can't be downloaded in offline mode.")]
+    Offline(&'static str, String),
 }
 
+/// Commands run in the directory of a library after extraction, keyed by the library name
+pub type PostExtract = IndexMap<String, Vec<Vec<String>>>;
+
 pub trait Downloadable {
     /// Folder name where the artifact will be saved to
     fn name(&self) -> &'static str;
@@ -98,14 +107,7 @@ impl Downloadable for WineGe {
         let (lib, pb) =
             download_file(&release.url, authorization.as_deref())?.progress(self.name());
 
-        match &release.filename {
-            n if n.ends_with(".tar.gz") => untar(GzDecoder::new(lib), dest)?,
-            n if n.ends_with(".tar.xz") => untar(XzDecoder::new(lib), dest)?,
-            n if n.ends_with(".tar.zst") => untar(ZstDecoder::new(lib)?, dest)?,
-            _ => {
-                return Err(Error::UnknownFormat(release.filename.to_string()));
-            }
-        }
+        unpack(&release.filename, lib, dest)?;
 
         pb.finish();
 
@@ -227,14 +229,7 @@ impl Downloadable for Library {
         let (lib, pb) =
             download_file(&release.url, authorization.as_deref())?.progress(self.name());
 
-        match &release.filename {
-            n if n.ends_with(".tar.gz") => untar(GzDecoder::new(lib), dest)?,
-            n if n.ends_with(".tar.xz") => untar(XzDecoder::new(lib), dest)?,
-            n if n.ends_with(".tar.zst") => untar(ZstDecoder::new(lib)?, dest)?,
-            _ => {
-                return Err(Error::UnknownFormat(release.filename.to_string()));
-            }
-        }
+        unpack(&release.filename, lib, dest)?;
 
         pb.finish();
 
@@ -242,6 +237,33 @@ impl Downloadable for Library {
     }
 }
 
+/// Unpacks an archive to the destination, detecting the archive format by the file name
+/// Archive formats which downloaded libraries and runtimes can be packed in, must match [`unpack`]
+pub const ARCHIVE_FORMATS: [&str; 4] = [".tar.gz", ".tar.xz", ".tar.zst", ".zip"];
+
+fn unpack(filename: &str, archive: impl io::Read, destination: &Path) -> Result<(), Error> {
+    match filename {
+        n if n.ends_with(".tar.gz") => untar(GzDecoder::new(archive), destination)?,
+        n if n.ends_with(".tar.xz") => untar(XzDecoder::new(archive), destination)?,
+        n if n.ends_with(".tar.zst") => untar(ZstDecoder::new(archive)?, destination)?,
+        n if n.ends_with(".zip") => unzip(archive, destination)?,
+        _ => {
+            return Err(Error::UnknownFormat(filename.to_string()));
+        }
+    }
+
+    Ok(())
+}
+
+fn unzip(mut zip: impl io::Read, destination: &Path) -> Result<(), Error> {
+    // Zip archives can't be read as a stream, so the whole archive is buffered in memory
+    let mut buf = Vec::new();
+    zip.read_to_end(&mut buf)?;
+    zip::ZipArchive::new(Cursor::new(buf))?.extract(destination)?;
+
+    Ok(())
+}
+
 fn untar(tar: impl io::Read, destination: impl AsRef<Path>) -> Result<(), io::Error> {
     let destination = destination.as_ref();
 
@@ -274,44 +296,14 @@ fn contains_single_directory_with_substring(
     }
 }
 
-fn move_paths_to_parent_directory(target_path: &Path) -> Result<(), std::io::Error> {
-    let parent = target_path
-        .parent()
-        .ok_or_else(|| io::Error::new(io::ErrorKind::Other, "Directory has no parent"))?;
-
-    let temp_path = parent.join(uuid::Uuid::new_v4().to_string());
-
-    fs::rename(target_path, &temp_path)?;
-    let _guard = DirGuard::new(&temp_path);
-
-    for entry in fs::read_dir(&temp_path)? {
-        let entry = entry?;
-        let entry_path = entry.path();
-
-        let new_path = parent.join(entry.file_name());
-        fs::rename(&entry_path, &new_path)?;
-    }
-
-    Ok(())
-}
-
-struct DirGuard<'a> {
-    path: &'a Path,
-    success: bool,
-}
-
-impl<'a> DirGuard<'a> {
-    fn new(path: &'a Path) -> Self {
-        let success = false;
-        Self { path, success }
-    }
-}
+/// Removes the directory when dropped, if it still exists
+struct DirGuard<'a>(&'a Path);
 
 impl<'a> Drop for DirGuard<'a> {
     fn drop(&mut self) {
-        if !self.success {
-            info!("Removing {path}", path = self.path.display());
-            let _ = fs::remove_dir_all(self.path);
+        if self.0.exists() {
+            debug!("Removing {path}", path = self.0.display());
+            let _ = fs::remove_dir_all(self.0);
         }
     }
 }
@@ -322,48 +314,130 @@ fn download_library(
     release: &downloader::Release,
     library_dir: &Path,
     tokens: &Tokens,
+    post_extract: &PostExtract,
 ) -> Result<(), Error> {
     let name = library.name();
 
-    info!("Downloading library {name} {version:?}: {release:?}");
     let dest = library_dir.join(&release.version);
+    match dest.exists() {
+        true => info!("Library {name} {} is already downloaded", release.version),
+        false => {
+            info!("Downloading library {name} {version:?}: {release:?}");
+            extract_library(library, release, library_dir, &dest, tokens, post_extract)?;
+        }
+    }
 
-    fs::create_dir_all(&dest)?;
-
-    // Auto-delete directory if extraction fails mid-way
-    let mut guard = DirGuard::new(&dest);
-
-    library.download(tokens, release, &dest)?;
-
-    if let Some(dest) = contains_single_directory_with_substring(&dest, library.substring())? {
-        move_paths_to_parent_directory(&dest)?;
+    // `latest` and `latest-<n>` are symlinks to the release they currently resolve to
+    if version.is_latest() {
+        link_version(&library_dir.join(&*version.to_str()), &release.version)?;
     }
 
-    if version == &ReleaseVersion::Latest {
-        let dir = library_dir.join("latest");
+    Ok(())
+}
 
-        _ = fs::remove_file(&dir);
-        unix::fs::symlink(&release.version, &dir)?;
+/// Downloads and extracts a release into a hidden temporary directory next to `dest`,
+/// runs post-extract commands in it, and renames it to `dest`, so that the version directory
+/// appears only once it is complete.
+fn extract_library(
+    library: &impl Downloadable,
+    release: &downloader::Release,
+    library_dir: &Path,
+    dest: &Path,
+    tokens: &Tokens,
+    post_extract: &PostExtract,
+) -> Result<(), Error> {
+    let name = library.name();
+    let temp = library_dir.join(format!(".{}-{}", release.version, uuid::Uuid::new_v4()));
+    fs::create_dir_all(&temp)?;
+
+    // Auto-delete the temporary directory, whether extraction fails mid-way or succeeds
+    let _guard = DirGuard(&temp);
+
+    library.download(tokens, release, &temp)?;
+
+    // Archives usually contain a single top level directory, which becomes the version directory
+    let root = contains_single_directory_with_substring(&temp, library.substring())?
+        .unwrap_or_else(|| temp.clone());
+
+    let commands = post_extract.get(name).map_or(&[][..], Vec::as_slice);
+    for command in commands.iter().filter(|c| !c.is_empty()) {
+        info!("Running post-extract command for {name}: {command:?}");
+        let status = Command::new(&command[0])
+            .args(&command[1..])
+            .current_dir(&root)
+            .status()?;
+        if !status.success() {
+            return Err(Error::PostExtract(command.join(" "), status));
+        }
     }
 
-    guard.success = true;
+    fs::rename(&root, dest)?;
 
     Ok(())
 }
 
+/// Points a `latest` symlink to a version directory next to it.
+/// The link is relative, so the libraries directory can be moved.
+fn link_version(link: &Path, version: &str) -> Result<(), io::Error> {
+    let target = Path::new(version)
+        .file_name()
+        .ok_or_else(|| io::Error::other(format!("Invalid release version `{version}`")))?;
+
+    match link.symlink_metadata() {
+        Ok(meta) if meta.is_dir() => fs::remove_dir_all(link)?,
+        Ok(_) => fs::remove_file(link)?,
+        Err(_) => {}
+    }
+
+    unix::fs::symlink(target, link)
+}
+
 pub struct State {
     pub path: PathBuf,
     pub updated: bool,
+    /// Release version `latest` was resolved to, if it was checked
+    pub version: Option<String>,
 }
 
 impl State {
     pub fn new(path: PathBuf, updated: bool) -> Self {
-        Self { path, updated }
+        Self {
+            path,
+            updated,
+            version: None,
+        }
     }
 
     pub fn untouched(path: PathBuf) -> Self {
         Self::new(path, false)
     }
+
+    pub fn resolved(path: PathBuf, version: String) -> Self {
+        Self {
+            path,
+            updated: true,
+            version: Some(version),
+        }
+    }
+}
+
+/// How often `latest` releases are checked when no interval is configured
+const CHECK_INTERVAL: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// The last check of the `latest` release, as stored in the state file
+#[derive(Default)]
+pub struct LastCheck<'a> {
+    pub elapsed: Option<Duration>,
+    pub version: Option<&'a str>,
+    /// Time between checks, a day if not set. Zero never checks a downloaded version
+    pub interval: Option<Duration>,
+}
+
+impl LastCheck<'_> {
+    fn is_due(&self) -> bool {
+        let interval = self.interval.unwrap_or(CHECK_INTERVAL);
+        !interval.is_zero() && self.elapsed.map_or(true, |d| d > interval)
+    }
 }
 
 pub fn ensure_library_exists(
@@ -371,19 +445,27 @@ pub fn ensure_library_exists(
     library_dir: impl AsRef<Path>,
     tokens: &Tokens,
     version: &ReleaseVersion,
-    time_since_update: Option<Duration>,
+    last_check: &LastCheck,
+    post_extract: &PostExtract,
+    offline: bool,
 ) -> Result<State, Error> {
     let name = library.name();
     let library_dir = library_dir.as_ref();
 
-    info!("Checking library {name} {version:?}");
+    match last_check.version {
+        Some(cached) => info!("Checking library {name} {version:?}, last resolved to {cached}"),
+        None => info!("Checking library {name} {version:?}"),
+    }
     let library_dir = library_dir.join(name);
-    let version_dir = library_dir.join(version.to_str());
+    let version_dir = library_dir.join(&*version.to_str());
 
     if version_dir.exists() {
-        if matches!(version, ReleaseVersion::Latest)
-            && time_since_update.map_or(true, |d| d > Duration::from_secs(86400))
-        {
+        if offline {
+            debug!("Offline, using downloaded {name} {version:?}");
+            return Ok(State::untouched(version_dir));
+        }
+
+        if version.is_latest() && last_check.is_due() {
             info!("Checking latest release for {name} {version:?}");
             let release = match library.get_meta(tokens, version) {
                 Ok(release) => release,
@@ -393,62 +475,123 @@ pub fn ensure_library_exists(
                 }
             };
 
+            // The link was pointed at the cached version when it was resolved
+            if last_check.version == Some(&*release.version) {
+                debug!("Latest version for {name} is still {}", &release.version);
+                return Ok(State::resolved(version_dir, release.version));
+            }
+
             // Check symlink of the "latest" folder
-            let latest_version = version_dir.read_link()?;
-            let latest_version = latest_version.file_name().unwrap_or_default();
+            let latest_version = version_dir.read_link().ok();
+            let latest_version = latest_version.as_deref().and_then(Path::file_name);
 
-            if latest_version == &*release.version {
+            if latest_version.is_some_and(|v| v == &*release.version) {
                 debug!("Latest version for {name} is still {}", &release.version);
-                return Ok(State::new(version_dir, true));
+                if version_dir.read_link().ok() != Some(PathBuf::from(&release.version)) {
+                    // Links created by older versions may be absolute
+                    link_version(&version_dir, &release.version)?;
+                }
+                return Ok(State::resolved(version_dir, release.version));
             }
 
             info!("Updating {name} to {}", release.version);
-            if let Err(err) = download_library(library, version, &release, &library_dir, tokens) {
-                error!("Unable to update {name}: {err}");
+            match download_library(
+                library,
+                version,
+                &release,
+                &library_dir,
+                tokens,
+                post_extract,
+            ) {
+                Ok(()) => return Ok(State::resolved(version_dir, release.version)),
+                Err(err) => {
+                    // The release was checked, so the next check waits for another interval
+                    error!("Unable to update {name}: {err}");
+                    return Ok(State::new(version_dir, true));
+                }
             }
         }
 
-        return Ok(State::new(version_dir, true));
+        return Ok(State::untouched(version_dir));
+    }
+
+    if offline {
+        return Err(Error::Offline(name, version.to_str().into_owned()));
+    }
+
+    // `exists` follows symlinks, so a link to a deleted version is downloaded again
+    if version_dir.symlink_metadata().is_ok() {
+        warn!(
+            "{} points to a missing version, downloading it again",
+            version_dir.display()
+        );
     }
 
     debug!("Checking release for {name} {version:?}");
     let release = library.get_meta(tokens, version)?;
-    download_library(library, version, &release, &library_dir, tokens)?;
-
-    Ok(State::new(
-        version_dir,
-        matches!(version, ReleaseVersion::Latest),
-    ))
+    download_library(
+        library,
+        version,
+        &release,
+        &library_dir,
+        tokens,
+        post_extract,
+    )?;
+
+    match version.is_latest() {
+        true => Ok(State::resolved(version_dir, release.version)),
+        false => Ok(State::untouched(version_dir)),
+    }
 }
 
-pub fn ensure_winetricks_exists(cache_dir: impl AsRef<Path>) -> Result<(), Error> {
+pub fn ensure_winetricks_exists(
+    cache_dir: impl AsRef<Path>,
+    version: Option<&str>,
+    offline: bool,
+) -> Result<(), Error> {
     let target = cache_dir.as_ref().join(".bin").join("winetricks");
-    if target.exists() {
+    if is_up_to_date(&target, version)
+        || is_usable_offline(&target, "winetricks", version, offline)?
+    {
         return Ok(());
     }
 
-    info!("Downloading winetricks");
-    let url = "https://raw.githubusercontent.com/Winetricks/winetricks/master/src/winetricks";
-    let (mut read, pb) = download_file(url, None)?.progress("winetricks");
+    let git_ref = version.unwrap_or("master");
+    info!("Downloading winetricks {git_ref}");
+    let url =
+        format!("https://raw.githubusercontent.com/Winetricks/winetricks/{git_ref}/src/winetricks");
+    let (mut read, pb) = download_file(&url, None)?.progress("winetricks");
 
     let _ = fs::create_dir_all(cache_dir.as_ref().join(".bin"));
-    let mut file = File::create(target)?;
+    let mut file = File::create(&target)?;
     file.set_permissions(Permissions::from_mode(0o755))?;
     io::copy(&mut read, &mut file)?;
+    write_version(&target, version)?;
 
     pb.finish();
     Ok(())
 }
 
-pub fn ensure_cabextract_exists(cache_dir: impl AsRef<Path>) -> Result<(), Error> {
+pub fn ensure_cabextract_exists(
+    cache_dir: impl AsRef<Path>,
+    version: Option<&str>,
+    offline: bool,
+) -> Result<(), Error> {
     let target = cache_dir.as_ref().join(".bin").join("cabextract");
-    if target.exists() {
+    if is_up_to_date(&target, version)
+        || is_usable_offline(&target, "cabextract", version, offline)?
+    {
         return Ok(());
     }
 
-    info!("Downloading cabextract");
-    let url = "https://archlinux.org/packages/extra/x86_64/cabextract/download/";
-    let (read, pb) = download_file(url, None)?.progress("cabextract");
+    info!("Downloading cabextract {}", version.unwrap_or("latest"));
+    let url = match version {
+        Some(version) => format!(
+            "https://archive.archlinux.org/packages/c/cabextract/cabextract-{version}-x86_64.pkg.tar.zst"
+        ),
+        None => "https://archlinux.org/packages/extra/x86_64/cabextract/download/".to_owned(),
+    };
+    let (read, pb) = download_file(&url, None)?.progress("cabextract");
 
     let _ = fs::create_dir_all(cache_dir.as_ref().join(".bin"));
     let mut tar = Archive::new(ZstDecoder::new(read)?);
@@ -456,25 +599,178 @@ pub fn ensure_cabextract_exists(cache_dir: impl AsRef<Path>) -> Result<(), Error
         let mut e = e?;
 
         if e.path()?.file_name().unwrap_or_default() == "cabextract" {
-            let mut file = File::create(target)?;
+            let mut file = File::create(&target)?;
             file.set_permissions(Permissions::from_mode(0o755))?;
             io::copy(&mut e, &mut file)?;
             break;
         }
     }
+    write_version(&target, version)?;
 
     pb.finish();
     Ok(())
 }
 
+/// Checks if a tool exists, and if a version is pinned, that the pinned version was downloaded.
+/// The downloaded version is stored next to the tool in a `.version` file.
+fn is_up_to_date(target: &Path, version: Option<&str>) -> bool {
+    if !target.exists() {
+        return false;
+    }
+
+    match version {
+        Some(version) => fs::read_to_string(target.with_extension("version"))
+            .is_ok_and(|downloaded| downloaded.trim() == version),
+        None => true,
+    }
+}
+
+/// In offline mode a downloaded tool is used even if it's not the pinned version,
+/// and a missing one is an error
+fn is_usable_offline(
+    target: &Path,
+    name: &'static str,
+    version: Option<&str>,
+    offline: bool,
+) -> Result<bool, Error> {
+    match (offline, target.exists()) {
+        (false, _) => Ok(false),
+        (true, true) => {
+            warn!("Offline, using the downloaded {name} instead of {version:?}");
+            Ok(true)
+        }
+        (true, false) => Err(Error::Offline(name, version.unwrap_or("latest").to_owned())),
+    }
+}
+
+fn write_version(target: &Path, version: Option<&str>) -> Result<(), io::Error> {
+    let path = target.with_extension("version");
+    match version {
+        Some(version) => fs::write(path, version),
+        None => {
+            let _ = fs::remove_file(path);
+            Ok(())
+        }
+    }
+}
+
 #[cfg(test)]
 mod test {
-    use std::path::Path;
+    use std::{fs, os::unix, path::Path, time::Duration};
 
     use brie_cfg::{Library, ReleaseVersion, Runtime, Tokens};
     use rayon::iter::{IntoParallelRefIterator, ParallelIterator};
 
-    use crate::{library::ensure_library_exists, runtime::ensure_runtime_exists};
+    use crate::{
+        library::{ensure_library_exists, link_version, Error, LastCheck, PostExtract},
+        runtime::ensure_runtime_exists,
+    };
+
+    #[test]
+    fn check_interval() {
+        let check = |elapsed: Option<u64>, interval: Option<u64>| LastCheck {
+            elapsed: elapsed.map(Duration::from_secs),
+            version: None,
+            interval: interval.map(Duration::from_secs),
+        };
+
+        assert!(check(None, None).is_due());
+        assert!(!check(Some(60), None).is_due());
+        assert!(check(Some(2 * 86400), None).is_due());
+        assert!(check(Some(60), Some(30)).is_due());
+        // Zero disables checks, even if the last one is unknown
+        assert!(!check(None, Some(0)).is_due());
+    }
+
+    #[test]
+    fn check_not_due() {
+        let dir = Path::new(".tmp").join("check_not_due");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("dxvk").join("v2.4")).unwrap();
+        unix::fs::symlink("v2.4", dir.join("dxvk").join("latest")).unwrap();
+
+        let last_check = LastCheck {
+            elapsed: Some(Duration::from_secs(60)),
+            version: Some("v2.4"),
+            interval: Some(Duration::from_secs(3600)),
+        };
+        let state = ensure_library_exists(
+            &Library::Dxvk,
+            &dir,
+            &Tokens::default(),
+            &ReleaseVersion::Latest,
+            &last_check,
+            &PostExtract::default(),
+            false,
+        )
+        .unwrap();
+
+        // The time of the last check is kept, so that the next one is still due after the interval
+        assert!(!state.updated);
+        assert_eq!(state.path, dir.join("dxvk").join("latest"));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn latest_link() {
+        let dir = Path::new(".tmp").join("latest_link");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("v1")).unwrap();
+        fs::create_dir_all(dir.join("v2")).unwrap();
+        let latest = dir.join("latest");
+
+        // Absolute links are replaced with relative ones
+        unix::fs::symlink(dir.join("v1").canonicalize().unwrap(), &latest).unwrap();
+        link_version(&latest, "v2").unwrap();
+        assert_eq!(latest.read_link().unwrap(), Path::new("v2"));
+
+        // Links to deleted versions are dangling, but can be replaced
+        fs::remove_dir(dir.join("v2")).unwrap();
+        assert!(!latest.exists());
+        link_version(&latest, "v1").unwrap();
+        assert!(latest.exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn offline() {
+        let dir = Path::new(".tmp").join("offline");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("dxvk").join("v2.4")).unwrap();
+        unix::fs::symlink("v2.4", dir.join("dxvk").join("latest")).unwrap();
+
+        let tokens = Tokens::default();
+        let ensure = |library: &Library, version: &ReleaseVersion| {
+            ensure_library_exists(
+                library,
+                &dir,
+                &tokens,
+                version,
+                &LastCheck::default(),
+                &PostExtract::default(),
+                true,
+            )
+        };
+
+        // The outdated `latest` is used without checking for a newer release
+        let state = ensure(&Library::Dxvk, &ReleaseVersion::Latest).unwrap();
+        assert_eq!(state.path, dir.join("dxvk").join("latest"));
+        assert!(!state.updated);
+
+        let tag = ReleaseVersion::Tag("v2.5".to_owned());
+        assert!(matches!(
+            ensure(&Library::Dxvk, &tag),
+            Err(Error::Offline("dxvk", version)) if version == "v2.5"
+        ));
+        assert!(matches!(
+            ensure(&Library::Vkd3dProton, &ReleaseVersion::Latest),
+            Err(Error::Offline("vkd3d-proton", _))
+        ));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
 
     #[test]
     #[ignore]
@@ -503,7 +799,9 @@ mod test {
                     &Runtime::GeProton {
                         version: ReleaseVersion::Latest,
                     },
-                    None,
+                    &LastCheck::default(),
+                    &PostExtract::default(),
+                    false,
                 )
                 .unwrap();
             });
@@ -515,13 +813,24 @@ mod test {
                     &Runtime::Tkg {
                         version: ReleaseVersion::Latest,
                     },
-                    None,
+                    &LastCheck::default(),
+                    &PostExtract::default(),
+                    false,
                 )
                 .unwrap();
             });
 
             libraries.par_iter().for_each(|l| {
-                ensure_library_exists(l, cache_dir, &tokens, &version, None).unwrap();
+                ensure_library_exists(
+                    l,
+                    cache_dir,
+                    &tokens,
+                    &version,
+                    &LastCheck::default(),
+                    &PostExtract::default(),
+                    false,
+                )
+                .unwrap();
             });
         });
 
@@ -0,0 +1,176 @@
+use std::{
+    env, fs, io,
+    os::unix::ffi::OsStrExt,
+    path::{Path, PathBuf},
+    thread,
+    time::{Duration, Instant},
+};
+
+use fslock::LockFile;
+use log::{debug, info, warn};
+use path_absolutize::Absolutize;
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("IO error. {0}")]
+    Io(#[from] io::Error),
+    #[error("The {0} lock is held by another brie instance ({1}).")]
+    Held(&'static str, Holder),
+    #[error("Gave up waiting for the {0} lock after {}s, it is still held by another brie instance ({1}).", .2.as_secs())]
+    Timeout(&'static str, Holder, Duration),
+}
+
+/// A process holding a lock, as recorded in the lock file
+#[derive(Debug)]
+pub struct Holder {
+    pid: Option<libc::pid_t>,
+    /// Command line of the process, which includes the launched unit
+    command: Option<String>,
+}
+
+impl std::fmt::Display for Holder {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match (self.pid, &self.command) {
+            (Some(pid), Some(command)) => write!(f, "pid {pid}: {command}"),
+            (Some(pid), None) => write!(f, "pid {pid}"),
+            (None, _) => f.write_str("unknown pid"),
+        }
+    }
+}
+
+/// What to do when a lock is held by another process
+#[derive(Clone, Copy, Debug)]
+pub enum Wait {
+    /// Fail immediately
+    No,
+    /// Block until the lock is released
+    Forever,
+    /// Fail if the lock is not released within the given duration
+    Timeout(Duration),
+}
+
+const POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Locks the file at the given path, recording the pid of the current process in it.
+/// If the lock is held by another process, waits for it to be released according to `wait`.
+pub fn acquire(path: &Path, name: &'static str, wait: Wait) -> Result<LockFile, Error> {
+    let path = &lock_path(path);
+    let mut lock = LockFile::open(path)?;
+    if lock.try_lock_with_pid()? {
+        return Ok(lock);
+    }
+
+    let holder = holder(path);
+    if matches!(wait, Wait::No) {
+        return Err(Error::Held(name, holder));
+    }
+
+    match holder.pid {
+        Some(pid) if !is_alive(pid) => warn!(
+            "The {name} lock is held, but the recorded pid {pid} is no longer running. \
+            The lock may be held by a process it spawned. Waiting..."
+        ),
+        _ => info!("Another brie instance ({holder}) holds the {name} lock, waiting..."),
+    }
+
+    let Wait::Timeout(timeout) = wait else {
+        lock.lock_with_pid()?;
+        return Ok(lock);
+    };
+
+    // fslock has no timed lock, so poll until the lock is released or the timeout passes
+    let start = Instant::now();
+    while !lock.try_lock_with_pid()? {
+        if start.elapsed() >= timeout {
+            return Err(Error::Timeout(name, self::holder(path), timeout));
+        }
+        thread::sleep(POLL_INTERVAL);
+    }
+
+    Ok(lock)
+}
+
+/// File locks may be unreliable on network filesystems, so locks are placed in
+/// `$XDG_RUNTIME_DIR/brie`, which is usually a local tmpfs, and named by a hash of the given path.
+/// Falls back to the given path if `XDG_RUNTIME_DIR` is not set or not writable.
+fn lock_path(path: &Path) -> PathBuf {
+    let Some(runtime_dir) = env::var_os("XDG_RUNTIME_DIR").filter(|d| !d.is_empty()) else {
+        return path.to_path_buf();
+    };
+
+    let dir = Path::new(&runtime_dir).join("brie");
+    let path = path.absolutize().unwrap_or(path.into());
+    if let Err(err) = fs::create_dir_all(&dir) {
+        warn!(
+            "Unable to create {}, locking in place. {err}",
+            dir.display()
+        );
+        return path.into_owned();
+    }
+
+    let lock = dir.join(format!("{:016x}.lock", fnv1a(path.as_os_str().as_bytes())));
+    debug!("Using {} as a lock for {}", lock.display(), path.display());
+
+    lock
+}
+
+/// 64-bit FNV-1a hash, which unlike `DefaultHasher` is stable across builds,
+/// so that different brie builds agree on lock file names
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, &b| {
+        (hash ^ u64::from(b)).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+fn holder(path: &Path) -> Holder {
+    let pid = fs::read_to_string(path)
+        .ok()
+        .and_then(|pid| pid.trim().parse().ok());
+    let command = pid.and_then(|pid| fs::read(format!("/proc/{pid}/cmdline")).ok());
+
+    Holder {
+        pid,
+        command: command.as_deref().and_then(cmdline),
+    }
+}
+
+/// Formats a nul separated `/proc/<pid>/cmdline` as a space separated command line
+fn cmdline(bytes: &[u8]) -> Option<String> {
+    let args = bytes
+        .split(|&b| b == 0)
+        .filter(|arg| !arg.is_empty())
+        .map(String::from_utf8_lossy)
+        .collect::<Vec<_>>();
+
+    match args.is_empty() {
+        true => None,
+        false => Some(args.join(" ")),
+    }
+}
+
+fn is_alive(pid: libc::pid_t) -> bool {
+    // Signal 0 only checks whether the process exists and can be signaled
+    let result = unsafe { libc::kill(pid, 0) };
+    result == 0 || io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{cmdline, fnv1a};
+
+    #[test]
+    fn stable_hash() {
+        assert_eq!(fnv1a(b""), 0xcbf2_9ce4_8422_2325);
+        assert_eq!(fnv1a(b"a"), 0xaf63_dc4c_8601_ec8c);
+        assert_ne!(fnv1a(b"/data/prefixes/a"), fnv1a(b"/data/prefixes/b"));
+    }
+
+    #[test]
+    fn holder_cmdline() {
+        assert_eq!(
+            cmdline(b"brie\0--no-wait\0witcher3\0").as_deref(),
+            Some("brie --no-wait witcher3")
+        );
+        assert_eq!(cmdline(b""), None);
+    }
+}
@@ -0,0 +1,180 @@
+use std::{
+    ffi::CString,
+    fs, io,
+    mem::MaybeUninit,
+    os::unix::ffi::OsStrExt,
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+use rayon::iter::{ParallelBridge, ParallelIterator};
+use serde::Serialize;
+
+use crate::Paths;
+
+/// A wine prefix in the prefixes directory, with the state brie keeps in it
+#[derive(Debug, Serialize)]
+pub struct PrefixInfo {
+    /// Directory name, which is the unit key or `prefix` of the units using it
+    pub name: String,
+    pub path: PathBuf,
+    /// Total size of the files in bytes. Symlinks, e.g. to home directories, are not followed
+    pub size: u64,
+    /// Latest modification time of any entry in the prefix
+    pub modified: Option<SystemTime>,
+    /// Installed winetricks verbs, as tracked in `.winetricks`
+    pub winetricks: Vec<String>,
+    /// Dlls overridden by installed libraries, as tracked in `.overrides`
+    pub overrides: Vec<String>,
+}
+
+/// Lists wine prefixes, sorted by name. Hidden entries are skipped,
+/// and a missing prefixes directory has no prefixes.
+pub fn list_prefixes(paths: &Paths) -> Result<Vec<PrefixInfo>, io::Error> {
+    let mut prefixes = Vec::new();
+    for (name, path) in visible_dirs(&paths.prefixes)? {
+        let (size, modified) = usage(&path);
+        prefixes.push(PrefixInfo {
+            name,
+            size,
+            modified,
+            winetricks: lines(&path.join(".winetricks")),
+            overrides: lines(&path.join(".overrides")),
+            path,
+        });
+    }
+
+    prefixes.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(prefixes)
+}
+
+/// Directories in a directory, without hidden entries and symlinks.
+/// A missing directory has no entries
+pub(crate) fn visible_dirs(dir: &Path) -> Result<Vec<(String, PathBuf)>, io::Error> {
+    if !dir.exists() {
+        return Ok(Vec::new());
+    }
+
+    let mut dirs = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().to_string();
+        if !name.starts_with('.') && entry.file_type()?.is_dir() {
+            dirs.push((name, entry.path()));
+        }
+    }
+
+    Ok(dirs)
+}
+
+/// Sums file sizes and finds the latest modification time in a directory tree,
+/// walking subdirectories in parallel. Symlinks are not followed.
+/// Entries which can't be read are skipped, since they only affect the totals.
+pub(crate) fn usage(dir: &Path) -> (u64, Option<SystemTime>) {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return (0, None);
+    };
+
+    entries
+        .filter_map(Result::ok)
+        .par_bridge()
+        .map(|entry| {
+            let Ok(meta) = entry.metadata() else {
+                return (0, None);
+            };
+            let modified = meta.modified().ok();
+            match meta.is_dir() {
+                true => {
+                    let (size, latest) = usage(&entry.path());
+                    (size, modified.max(latest))
+                }
+                false if meta.is_file() => (meta.len(), modified),
+                false => (0, modified),
+            }
+        })
+        .reduce(|| (0, None), |a, b| (a.0 + b.0, a.1.max(b.1)))
+}
+
+/// Free space available to unprivileged users on the filesystem of a path.
+/// Missing paths are resolved to their closest existing ancestor, e.g. for prefixes not created yet
+pub fn free_space(path: &Path) -> Result<u64, io::Error> {
+    let existing = path
+        .ancestors()
+        .find(|p| p.exists())
+        .unwrap_or_else(|| Path::new("."));
+    let existing = CString::new(existing.as_os_str().as_bytes())
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+
+    let mut stat = MaybeUninit::<libc::statvfs>::uninit();
+    // The path is nul-terminated, and stat is only read after statvfs has filled it
+    let stat = unsafe {
+        if libc::statvfs(existing.as_ptr(), stat.as_mut_ptr()) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        stat.assume_init()
+    };
+
+    #[allow(clippy::useless_conversion)]
+    Ok(u64::from(stat.f_bavail) * u64::from(stat.f_frsize))
+}
+
+fn lines(path: &Path) -> Vec<String> {
+    fs::read_to_string(path)
+        .unwrap_or_default()
+        .lines()
+        .filter(|line| !line.is_empty())
+        .map(str::to_owned)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, os::unix, path::Path};
+
+    use crate::Paths;
+
+    use super::{free_space, list_prefixes};
+
+    #[test]
+    fn prefixes_info() {
+        let dir = Path::new(".tmp").join("prefixes_info");
+        let _ = fs::remove_dir_all(&dir);
+        let paths = Paths::new(&dir);
+        assert!(list_prefixes(&paths).unwrap().is_empty());
+
+        let game = paths.prefixes.join("game");
+        fs::create_dir_all(game.join("drive_c")).unwrap();
+        fs::create_dir_all(paths.prefixes.join("app")).unwrap();
+        fs::create_dir_all(paths.prefixes.join(".hidden")).unwrap();
+        fs::write(game.join(".winetricks"), "vcrun2019\nfonts corefonts\n").unwrap();
+        fs::write(game.join(".overrides"), "d3d11\ndxgi\n").unwrap();
+        fs::write(game.join("drive_c").join("file"), [0; 100]).unwrap();
+        // Symlinked directories are not counted
+        unix::fs::symlink("/usr", game.join("drive_c").join("usr")).unwrap();
+
+        let prefixes = list_prefixes(&paths).unwrap();
+        let names = prefixes.iter().map(|p| p.name.as_str()).collect::<Vec<_>>();
+        assert_eq!(names, ["app", "game"]);
+
+        let app = &prefixes[0];
+        assert_eq!(app.size, 0);
+        assert!(app.winetricks.is_empty());
+
+        let game = &prefixes[1];
+        assert_eq!(game.size, 100 + 26 + 11);
+        assert!(game.modified.is_some());
+        assert_eq!(game.winetricks, ["vcrun2019", "fonts corefonts"]);
+        assert_eq!(game.overrides, ["d3d11", "dxgi"]);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn missing_path_free_space() {
+        let existing = free_space(Path::new(".")).unwrap();
+        assert!(existing > 0);
+
+        let missing = free_space(&Path::new(".tmp").join("missing").join("prefix")).unwrap();
+        assert!(missing > 0);
+    }
+}
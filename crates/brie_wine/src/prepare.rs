@@ -1,27 +1,35 @@
 use std::{
     collections::HashSet,
+    env::VarError,
     fs::{self},
     io::{self, Write},
     os::unix,
-    path::PathBuf,
+    path::{Path, PathBuf},
+    process::ExitStatus,
+    time::SystemTime,
 };
 
+use brie_cfg::{DriveInfo, Installer};
 use indexmap::IndexMap;
-use log::{debug, info};
+use log::{debug, info, warn};
 use thiserror::Error;
 
-use crate::command::Runner;
+use crate::command::{check_status, Runner};
 
 #[derive(Debug, Error)]
 pub enum WinePrefixError {
     #[error("Wine error. {0}")]
     Wine(io::Error),
-    #[error("Unable to read drive_c/users. {0}")]
-    Read(io::Error),
+    #[error("`{0}` failed to initialize the wine prefix with {1}.")]
+    Init(&'static str, ExitStatus),
     #[error("Unable to remove symlink. {0}")]
     Rm(io::Error),
     #[error("Unable to create directory. {0}")]
     Mkdir(io::Error),
+    #[error("Unable to write the wine version of the prefix. {0}")]
+    Version(io::Error),
+    #[error("Unable to back up the wine prefix. {0}")]
+    Backup(io::Error),
 }
 
 #[derive(Debug, Error)]
@@ -42,15 +50,46 @@ pub enum MountsError {
     Link(PathBuf, io::Error),
 }
 
+#[derive(Debug, Error)]
+#[error("Unable to write `{0}`. {1}")]
+pub struct DriveError(PathBuf, io::Error);
+
 #[derive(Debug, Error)]
 #[error(transparent)]
 pub struct BeforeError(#[from] io::Error);
 
+#[derive(Debug, Error)]
+pub enum InstallError {
+    #[error("Unable to expand installer path. {0}")]
+    Expand(#[from] shellexpand::LookupError<VarError>),
+    #[error("Unable to run installer `{0}`. {1}")]
+    Run(String, io::Error),
+    #[error("Installer `{0}` failed with {1}.")]
+    Failed(String, ExitStatus),
+    #[error("Unable to update state file. {0}")]
+    StateWrite(io::Error),
+}
+
 impl Runner {
-    pub fn prepare_wine_prefix(&self) -> Result<(), WinePrefixError> {
+    /// Creates the wine prefix if it isn't initialized, replacing symlinks to `$HOME` folders
+    /// with directories, except for the ones listed in `link_home_dirs`.
+    /// If `user` is set, only the folder of that user is processed.
+    ///
+    /// `wine_version` is tracked in `.wine-version`, and an existing prefix
+    /// last initialized by a different wine version is updated with `wineboot -u`.
+    /// With `backups` above 0, registry hives are backed up before the update.
+    /// Expected to be called with the prefix lock held.
+    pub fn prepare_wine_prefix(
+        &self,
+        user: Option<&str>,
+        link_home_dirs: &[String],
+        wine_version: Option<&str>,
+        backups: usize,
+    ) -> Result<(), WinePrefixError> {
         let prefix = self.wine_prefix();
-        if prefix.exists() {
-            return Ok(());
+        // The directory may already exist to hold the prefix lock
+        if prefix.join("system.reg").exists() {
+            return self.update_wine_prefix(wine_version, backups);
         }
 
         info!("Creating wine prefix");
@@ -59,22 +98,55 @@ impl Runner {
             let _ = fs::create_dir_all(parent);
         }
 
-        self.run("wineboot", &["-u"])
-            .map_err(WinePrefixError::Wine)?;
-        self.run("wineserver", &["--wait"])
-            .map_err(WinePrefixError::Wine)?;
+        for (command, args) in [("wineboot", ["-u"]), ("wineserver", ["--wait"])] {
+            let status = self.run(command, &args).map_err(WinePrefixError::Wine)?;
+            if !status.success() {
+                // A partially created prefix would be treated as ready on the next launch
+                warn!(
+                    "Removing partially created wine prefix {}",
+                    prefix.display()
+                );
+                let _ = fs::remove_dir_all(prefix);
+                return Err(WinePrefixError::Init(command, status));
+            }
+        }
 
         info!("Replacing symlinks to $HOME with directories");
 
-        let symlinks = fs::read_dir(prefix.join("drive_c").join("users"))
-            .map_err(WinePrefixError::Read)?
-            .filter_map(Result::ok)
-            .filter(|e| e.file_type().map(|t| t.is_dir()).unwrap_or(false))
-            .map(|e| e.path())
+        // Only hardening, a prefix without user folders is still usable
+        let users = prefix.join("drive_c").join("users");
+        let users = match fs::read_dir(&users) {
+            Ok(entries) => entries
+                .filter_map(Result::ok)
+                .filter(|e| e.file_type().map(|t| t.is_dir()).unwrap_or(false))
+                .filter(|e| user.is_none() || user.is_some_and(|u| e.file_name() == u))
+                .map(|e| e.path())
+                .collect::<Vec<_>>(),
+            Err(e) => {
+                debug!("Unable to read {}. {e}", users.display());
+                Vec::new()
+            }
+        };
+        if users.is_empty() {
+            warn!("No user folders found in the wine prefix, keeping $HOME symlinks");
+        }
+
+        let symlinks = users
+            .into_iter()
             .filter_map(|p| fs::read_dir(p).ok())
             .flatten()
             .filter_map(Result::ok)
             .filter(|e| e.file_type().map(|t| t.is_symlink()).unwrap_or(false))
+            .filter(|e| {
+                let name = e.file_name();
+                let keep = link_home_dirs
+                    .iter()
+                    .any(|dir| name.to_str().is_some_and(|n| n.eq_ignore_ascii_case(dir)));
+                if keep {
+                    debug!("Keeping symlink {}", e.path().display());
+                }
+                !keep
+            })
             .map(|e| e.path());
 
         for symlink in symlinks {
@@ -82,10 +154,54 @@ impl Runner {
             fs::create_dir(&symlink).map_err(WinePrefixError::Mkdir)?;
         }
 
-        Ok(())
+        write_wine_version(prefix, wine_version)
+    }
+
+    /// Runs `wineboot -u` when the prefix was last initialized by a different wine version,
+    /// e.g. after a runtime update. Prefixes without `.wine-version` are updated once.
+    fn update_wine_prefix(
+        &self,
+        wine_version: Option<&str>,
+        backups: usize,
+    ) -> Result<(), WinePrefixError> {
+        let Some(version) = wine_version else {
+            return Ok(());
+        };
+
+        let prefix = self.wine_prefix();
+        let previous = fs::read_to_string(prefix.join(".wine-version")).ok();
+        if previous.as_deref().map(str::trim) == Some(version) {
+            return Ok(());
+        }
+
+        match previous {
+            Some(previous) => info!("Updating wine prefix from {} to {version}", previous.trim()),
+            None => info!("Updating wine prefix to {version}"),
+        }
+        if backups > 0 {
+            let backup = backup_prefix(prefix, backups).map_err(WinePrefixError::Backup)?;
+            info!(
+                "Registry of the wine prefix is backed up to {}",
+                backup.display()
+            );
+        }
+        for (command, args) in [("wineboot", ["-u"]), ("wineserver", ["--wait"])] {
+            let status = self.run(command, &args).map_err(WinePrefixError::Wine)?;
+            if !status.success() {
+                return Err(WinePrefixError::Init(command, status));
+            }
+        }
+
+        write_wine_version(prefix, wine_version)
     }
 
-    pub fn winetricks(&self, packages: &[impl AsRef<str>]) -> Result<(), WinetricksError> {
+    /// Installs winetricks verbs which were not installed before.
+    /// Downloaded components are cached in `cache` if set, otherwise in the winetricks default location.
+    pub fn winetricks(
+        &self,
+        verbs: &[Vec<String>],
+        cache: Option<&Path>,
+    ) -> Result<(), WinetricksError> {
         info!("Checking winetricks");
 
         let file = self.wine_prefix().join(".winetricks");
@@ -93,23 +209,32 @@ impl Runner {
         let installed = fs::read_to_string(&file).ok().unwrap_or_default();
         let installed = installed.lines().collect::<HashSet<_>>();
 
-        let mut new = Vec::with_capacity(packages.len());
+        let mut new = Vec::with_capacity(verbs.len());
 
-        for package in packages
-            .iter()
-            .map(AsRef::as_ref)
-            .filter(|p| !installed.contains(p))
-        {
-            info!("Installing `{package}` with winetricks");
-            self.run("winetricks", &["-q", package])
-                .map_err(|e| WinetricksError::Winetricks(package.to_string(), e))?;
-            new.push(package);
+        for verb in verbs.iter().filter(|v| !v.is_empty()) {
+            // Installed verbs are tracked by joined arguments, so a single verb is tracked by its name
+            let key = verb.join(" ");
+            if installed.contains(key.as_str()) || new.contains(&key) {
+                continue;
+            }
+
+            info!("Installing `{key}` with winetricks");
+            let mut args = vec!["-q"];
+            args.extend(verb.iter().map(String::as_str));
+            let mut command = self.command("winetricks", &args);
+            if let Some(cache) = cache {
+                command.env("W_CACHE", cache);
+            }
+            command
+                .status()
+                .and_then(check_status)
+                .map_err(|e| WinetricksError::Winetricks(key.clone(), e))?;
+            new.push(key);
         }
 
         let mut file = fs::OpenOptions::new()
-            .write(true)
             .create(true)
-            .truncate(false)
+            .append(true)
             .open(&file)
             .map_err(WinetricksError::Lock)?;
 
@@ -120,6 +245,33 @@ impl Runner {
         Ok(())
     }
 
+    /// Applies winetricks settings which were not applied the last time, tracked in `.winetricks-settings`.
+    /// Settings are tracked as the last applied list, so switching back to a previous value applies it again.
+    pub fn winetricks_settings(&self, settings: &[String]) -> Result<(), WinetricksError> {
+        let file = self.wine_prefix().join(".winetricks-settings");
+        let applied = fs::read_to_string(&file).unwrap_or_default();
+        let current = settings
+            .iter()
+            .map(|s| format!("{s}\n"))
+            .collect::<String>();
+        if current == applied {
+            return Ok(());
+        }
+
+        let pending = pending_settings(settings, &applied);
+        if !pending.is_empty() {
+            let key = pending.join(" ");
+            info!("Applying winetricks settings `{key}`");
+            let mut args = vec!["-q", "settings"];
+            args.extend(pending);
+            self.run("winetricks", &args)
+                .and_then(check_status)
+                .map_err(|e| WinetricksError::Winetricks(key, e))?;
+        }
+
+        fs::write(&file, current).map_err(WinetricksError::Lock)
+    }
+
     pub fn mounts(&self, mounts: &IndexMap<char, String>) -> Result<(), MountsError> {
         info!("Checking drive mounts");
         // Iterate over mounts, check if there exists a symlink, if target is different, remove it,
@@ -153,6 +305,67 @@ impl Runner {
         Ok(())
     }
 
+    /// Sets the volume label and serial number of a drive.
+    /// Wine reads them from `.windows-label` and `.windows-serial` files in the drive root,
+    /// the files are only written when their contents change.
+    pub fn drive_info(&self, drive: char, info: &DriveInfo) -> Result<(), DriveError> {
+        let root = self
+            .wine_prefix()
+            .join("dosdevices")
+            .join(format!("{drive}:"));
+
+        for (file, value) in [
+            (".windows-label", &info.label),
+            (".windows-serial", &info.serial),
+        ] {
+            let Some(value) = value else { continue };
+            let path = root.join(file);
+            if fs::read_to_string(&path).is_ok_and(|v| v.trim_end() == value) {
+                continue;
+            }
+
+            info!("Setting `{file}` of `{drive}:` to `{value}`");
+            fs::write(&path, format!("{value}\n")).map_err(|e| DriveError(path, e))?;
+        }
+
+        Ok(())
+    }
+
+    /// Runs the installer, unless it was already run in this prefix.
+    /// Successful installers are tracked in the `.installed` file in the wine prefix.
+    pub fn install(&self, installer: &Installer) -> Result<(), InstallError> {
+        let file = self.wine_prefix().join(".installed");
+
+        let installed = fs::read_to_string(&file).unwrap_or_default();
+        if installed.lines().any(|l| l == installer.exe) {
+            return Ok(());
+        }
+
+        let exe = shellexpand::full(&installer.exe)?;
+        info!("Running installer `{exe}`");
+
+        let mut args = vec![&*exe];
+        args.extend(installer.silent_args.iter().map(String::as_str));
+        let status = self
+            .run(self.wine(), &args)
+            .map_err(|e| InstallError::Run(installer.exe.clone(), e))?;
+        if !status.success() {
+            return Err(InstallError::Failed(installer.exe.clone(), status));
+        }
+
+        self.wait()
+            .map_err(|e| InstallError::Run(installer.exe.clone(), e))?;
+
+        let mut file = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&file)
+            .map_err(InstallError::StateWrite)?;
+        writeln!(file, "{}", installer.exe).map_err(InstallError::StateWrite)?;
+
+        Ok(())
+    }
+
     pub fn before(&self, commands: &[Vec<String>]) -> Result<(), BeforeError> {
         for line in commands {
             if line.is_empty() {
@@ -160,9 +373,118 @@ impl Runner {
             }
 
             info!("Running before-script: {line:?}");
-            self.run(&line[0], &line[1..])?;
+            self.run_checked(&line[0], &line[1..])?;
         }
 
         Ok(())
     }
 }
+
+fn write_wine_version(prefix: &Path, wine_version: Option<&str>) -> Result<(), WinePrefixError> {
+    match wine_version {
+        Some(version) => {
+            fs::write(prefix.join(".wine-version"), version).map_err(WinePrefixError::Version)
+        }
+        None => Ok(()),
+    }
+}
+
+/// Settings which are not in the list of settings applied last time, in the configured order
+fn pending_settings<'a>(settings: &'a [String], applied: &str) -> Vec<&'a str> {
+    let applied = applied.lines().collect::<HashSet<_>>();
+    let mut pending = Vec::new();
+    for setting in settings.iter().filter(|s| !s.is_empty()) {
+        if !applied.contains(setting.as_str()) && !pending.contains(&setting.as_str()) {
+            pending.push(setting.as_str());
+        }
+    }
+    pending
+}
+
+/// Registry hives and the wine version, restoring them undoes `wineboot -u` in most cases
+const BACKUP_FILES: [&str; 4] = ["system.reg", "user.reg", "userdef.reg", ".wine-version"];
+
+/// Copies registry hives of the prefix to `.backups/<unix time>`, and removes the oldest backups
+/// so that at most `keep` of them are left. Returns the path of the new backup.
+fn backup_prefix(prefix: &Path, keep: usize) -> Result<PathBuf, io::Error> {
+    let backups = prefix.join(".backups");
+    let time = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let backup = backups.join(time.to_string());
+    fs::create_dir_all(&backup)?;
+
+    for file in BACKUP_FILES {
+        let source = prefix.join(file);
+        if source.exists() {
+            fs::copy(&source, backup.join(file))?;
+        }
+    }
+
+    let mut existing = fs::read_dir(&backups)?
+        .filter_map(Result::ok)
+        .filter_map(|e| e.file_name().to_str()?.parse::<u64>().ok())
+        .collect::<Vec<_>>();
+    existing.sort_unstable();
+    for old in existing.iter().rev().skip(keep) {
+        debug!("Removing old prefix backup {old}");
+        fs::remove_dir_all(backups.join(old.to_string()))?;
+    }
+
+    Ok(backup)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, path::Path};
+
+    use super::{backup_prefix, pending_settings};
+
+    #[test]
+    fn winetricks_settings() {
+        let settings = ["win10".to_owned(), "fontsmooth=rgb".to_owned()];
+        assert_eq!(pending_settings(&settings, ""), ["win10", "fontsmooth=rgb"]);
+        assert!(pending_settings(&settings, "win10\nfontsmooth=rgb\n").is_empty());
+
+        // Switching back to a setting applied before applies it again
+        let settings = ["win7".to_owned(), "fontsmooth=rgb".to_owned()];
+        assert_eq!(
+            pending_settings(&settings, "win10\nfontsmooth=rgb\n"),
+            ["win7"]
+        );
+    }
+
+    #[test]
+    fn prefix_backups() {
+        let prefix = Path::new(".tmp").join("prefix_backups");
+        let _ = fs::remove_dir_all(&prefix);
+        let backups = prefix.join(".backups");
+        for old in ["100", "200", "300"] {
+            fs::create_dir_all(backups.join(old)).unwrap();
+        }
+        fs::write(prefix.join("user.reg"), "WINE REGISTRY").unwrap();
+        fs::write(prefix.join(".wine-version"), "wine-9.0").unwrap();
+
+        let backup = backup_prefix(&prefix, 2).unwrap();
+        assert_eq!(
+            fs::read_to_string(backup.join("user.reg")).unwrap(),
+            "WINE REGISTRY"
+        );
+        assert_eq!(
+            fs::read_to_string(backup.join(".wine-version")).unwrap(),
+            "wine-9.0"
+        );
+        assert!(!backup.join("system.reg").exists());
+
+        // The two most recent backups are kept
+        let left = fs::read_dir(&backups)
+            .unwrap()
+            .map(|e| e.unwrap().path())
+            .collect::<Vec<_>>();
+        assert_eq!(left.len(), 2);
+        assert!(left.contains(&backups.join("300")) && left.contains(&backup));
+
+        fs::remove_dir_all(&prefix).unwrap();
+    }
+}
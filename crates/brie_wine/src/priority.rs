@@ -0,0 +1,33 @@
+use std::{io, os::unix::process::CommandExt, process::Command};
+
+const IOPRIO_WHO_PROCESS: libc::c_int = 1;
+const IOPRIO_CLASS_BE: libc::c_int = 2;
+const IOPRIO_CLASS_SHIFT: libc::c_int = 13;
+
+/// Sets the scheduling and IO priority of the process spawned by the command.
+/// Priorities are inherited by all child processes, e.g. the whole wine session.
+pub fn set_priority(command: &mut Command, nice: Option<i32>, ionice: Option<u8>) {
+    if nice.is_none() && ionice.is_none() {
+        return;
+    }
+
+    // SAFETY: the hook only makes async-signal-safe syscalls
+    unsafe {
+        command.pre_exec(move || {
+            if let Some(nice) = nice {
+                if libc::setpriority(libc::PRIO_PROCESS, 0, nice) != 0 {
+                    return Err(io::Error::last_os_error());
+                }
+            }
+
+            if let Some(level) = ionice {
+                let prio = (IOPRIO_CLASS_BE << IOPRIO_CLASS_SHIFT) | libc::c_int::from(level);
+                if libc::syscall(libc::SYS_ioprio_set, IOPRIO_WHO_PROCESS, 0, prio) != 0 {
+                    return Err(io::Error::last_os_error());
+                }
+            }
+
+            Ok(())
+        });
+    }
+}
@@ -0,0 +1,301 @@
+use std::{
+    borrow::Cow,
+    ffi::OsStr,
+    fs,
+    io::{self, Write},
+    process::Stdio,
+};
+
+use brie_cfg::{RegEntry, RegType};
+use log::{debug, info};
+use thiserror::Error;
+
+use crate::command::{check_status, Runner};
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("Invalid `{1}` data `{2}` for registry key `{0}`.")]
+    InvalidData(String, &'static str, String),
+    #[error("Unable to import registry file. {0}")]
+    Import(io::Error),
+    #[error("Unable to update state file. {0}")]
+    StateWrite(io::Error),
+}
+
+const REG_HEADER: &str = "Windows Registry Editor Version 5.00\n";
+
+/// Registry value which sets the DPI wine scales fonts and windows to, 96 being 100%
+pub fn dpi(dpi: u32) -> RegEntry {
+    RegEntry {
+        key: "HKCU\\Control Panel\\Desktop".to_owned(),
+        name: Some("LogPixels".to_owned()),
+        kind: RegType::Dword,
+        data: dpi.to_string(),
+    }
+}
+
+impl Runner {
+    /// Writes a `.reg` file to the wine prefix and imports it with `regedit`.
+    /// The import is silent, so `regedit` never opens its window instead of importing the file.
+    pub(crate) fn import_reg(&self, file_name: &str, reg: &str) -> Result<(), io::Error> {
+        let path = self.wine_prefix().join(file_name);
+        fs::write(&path, reg)?;
+
+        let args = [OsStr::new("regedit"), OsStr::new("/S"), path.as_os_str()];
+        let status = self.command(self.wine(), &args).status();
+        let _ = fs::remove_file(&path);
+
+        check_status(status?)
+    }
+
+    /// Reads names and data of all values of the key with `reg query`
+    pub(crate) fn query_reg(&self, key: &str) -> Result<Vec<(String, String)>, io::Error> {
+        let output = self
+            .command(self.wine(), &["reg", "query", key])
+            .stdout(Stdio::piped())
+            .output()?;
+        if !output.status.success() {
+            let status = output.status;
+            return Err(io::Error::other(format!("reg query failed with {status}")));
+        }
+
+        Ok(parse_query(&String::from_utf8_lossy(&output.stdout)))
+    }
+
+    /// Applies registry values which differ from the ones last applied to the prefix.
+    /// Applied values are tracked in the `.registry` file in the wine prefix.
+    pub fn registry(&self, entries: &[RegEntry]) -> Result<(), Error> {
+        info!("Checking registry values");
+
+        let file = self.wine_prefix().join(".registry");
+
+        let applied = fs::read_to_string(&file).unwrap_or_default();
+        let applied = applied.lines().map(str::to_owned).collect::<Vec<_>>();
+
+        let mut reg = String::from(REG_HEADER);
+        let mut new = Vec::with_capacity(entries.len());
+
+        for entry in entries {
+            let (key, value) = format_value(entry)?;
+            let line = format!("[{key}]{value}");
+            let target = format!("[{key}]{}=", value_name(entry));
+            if last_applied(applied.iter().chain(&new), &target) == Some(line.as_str()) {
+                continue;
+            }
+
+            info!("Setting registry value {value} in `{key}`");
+            reg.push_str(&format!("\n[{key}]\n{value}\n"));
+            new.push(line);
+        }
+
+        if new.is_empty() {
+            return Ok(());
+        }
+
+        debug!("Importing registry values: {reg}");
+        self.import_reg("registry.reg", &reg)
+            .map_err(Error::Import)?;
+
+        let mut file = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&file)
+            .map_err(Error::StateWrite)?;
+
+        for line in new {
+            writeln!(file, "{line}").map_err(Error::StateWrite)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Parses value lines of `reg query` output, e.g. `    d3d11    REG_SZ    native`
+fn parse_query(output: &str) -> Vec<(String, String)> {
+    output
+        .lines()
+        .filter_map(|line| {
+            let mut parts = line.trim().splitn(3, "    ");
+            let name = parts.next()?;
+            let kind = parts.next()?;
+            kind.starts_with("REG_").then(|| {
+                let data = parts.next().unwrap_or_default().trim();
+                (name.to_owned(), data.to_owned())
+            })
+        })
+        .collect()
+}
+
+/// Finds the last applied line which sets the value `target` (`[key]name=`)
+fn last_applied<'a>(
+    applied: impl DoubleEndedIterator<Item = &'a String>,
+    target: &str,
+) -> Option<&'a str> {
+    applied
+        .rev()
+        .find(|line| line.starts_with(target))
+        .map(String::as_str)
+}
+
+/// Formats the value name as in a `.reg` file, `@` being the default value of the key
+fn value_name(entry: &RegEntry) -> String {
+    match &entry.name {
+        Some(name) => format!("\"{}\"", escape(name)),
+        None => "@".to_owned(),
+    }
+}
+
+/// Formats the entry as a full key name and a `.reg` file value line
+fn format_value(entry: &RegEntry) -> Result<(Cow<'_, str>, String), Error> {
+    let key = expand_hive(&entry.key);
+    let invalid = |kind| Error::InvalidData(entry.key.clone(), kind, entry.data.clone());
+
+    let name = value_name(entry);
+
+    let data = match entry.kind {
+        RegType::String => format!("\"{}\"", escape(&entry.data)),
+        RegType::Dword => {
+            let dword = match entry.data.strip_prefix("0x") {
+                Some(hex) => u32::from_str_radix(hex, 16).ok(),
+                None => entry.data.parse::<u32>().ok(),
+            };
+            format!("dword:{:08x}", dword.ok_or_else(|| invalid("dword"))?)
+        }
+        RegType::Binary => {
+            let hex = entry
+                .data
+                .chars()
+                .filter(|c| !matches!(c, ',' | ' '))
+                .collect::<String>();
+            if hex.len() % 2 != 0 || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+                return Err(invalid("binary"));
+            }
+
+            let bytes = hex
+                .to_ascii_lowercase()
+                .as_bytes()
+                .chunks(2)
+                .map(String::from_utf8_lossy)
+                .collect::<Vec<_>>()
+                .join(",");
+            format!("hex:{bytes}")
+        }
+        RegType::Delete => "-".to_owned(),
+    };
+
+    Ok((key, format!("{name}={data}")))
+}
+
+/// Replaces abbreviated hive names (e.g. `HKCU`) with full names
+fn expand_hive(key: &str) -> Cow<'_, str> {
+    const HIVES: &[(&str, &str)] = &[
+        ("HKCR", "HKEY_CLASSES_ROOT"),
+        ("HKCU", "HKEY_CURRENT_USER"),
+        ("HKLM", "HKEY_LOCAL_MACHINE"),
+        ("HKU", "HKEY_USERS"),
+        ("HKCC", "HKEY_CURRENT_CONFIG"),
+    ];
+
+    let (hive, rest) = key.split_once('\\').unwrap_or((key, ""));
+    match HIVES
+        .iter()
+        .find(|(short, _)| short.eq_ignore_ascii_case(hive))
+    {
+        Some((_, full)) if rest.is_empty() => Cow::Borrowed(*full),
+        Some((_, full)) => Cow::Owned(format!("{full}\\{rest}")),
+        None => Cow::Borrowed(key),
+    }
+}
+
+fn escape(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+#[cfg(test)]
+mod tests {
+    use brie_cfg::{RegEntry, RegType};
+
+    use super::{dpi, format_value, last_applied, parse_query};
+
+    fn entry(name: Option<&str>, kind: RegType, data: &str) -> RegEntry {
+        RegEntry {
+            key: "HKCU\\Software\\Wine\\Direct3D".into(),
+            name: name.map(Into::into),
+            kind,
+            data: data.into(),
+        }
+    }
+
+    #[test]
+    fn values() {
+        let (key, value) =
+            format_value(&entry(Some("renderer"), RegType::String, "vulkan")).unwrap();
+        assert_eq!(key, "HKEY_CURRENT_USER\\Software\\Wine\\Direct3D");
+        assert_eq!(value, "\"renderer\"=\"vulkan\"");
+
+        let (_, value) = format_value(&entry(None, RegType::String, "C:\\\"a\"")).unwrap();
+        assert_eq!(value, "@=\"C:\\\\\\\"a\\\"\"");
+
+        let (_, value) = format_value(&entry(Some("a"), RegType::Dword, "0x1F")).unwrap();
+        assert_eq!(value, "\"a\"=dword:0000001f");
+        let (_, value) = format_value(&entry(Some("a"), RegType::Dword, "10")).unwrap();
+        assert_eq!(value, "\"a\"=dword:0000000a");
+        assert!(format_value(&entry(Some("a"), RegType::Dword, "-1")).is_err());
+
+        let (_, value) = format_value(&entry(Some("a"), RegType::Binary, "DE,ad be")).unwrap();
+        assert_eq!(value, "\"a\"=hex:de,ad,be");
+        assert!(format_value(&entry(Some("a"), RegType::Binary, "abc")).is_err());
+
+        let (_, value) = format_value(&entry(Some("a"), RegType::Delete, "")).unwrap();
+        assert_eq!(value, "\"a\"=-");
+    }
+
+    #[test]
+    fn reapplied() {
+        let applied = [
+            "[HKEY_CURRENT_USER\\Software]\"a\"=dword:00000001",
+            "[HKEY_CURRENT_USER\\Software]\"ab\"=dword:00000003",
+            "[HKEY_CURRENT_USER\\Software]\"a\"=dword:00000002",
+        ]
+        .map(str::to_owned);
+        let target = "[HKEY_CURRENT_USER\\Software]\"a\"=";
+
+        // Switching back to the value applied before applies it again
+        assert_eq!(
+            last_applied(applied.iter(), target),
+            Some(applied[2].as_str())
+        );
+        assert_eq!(
+            last_applied(applied[..2].iter(), target),
+            Some(applied[0].as_str())
+        );
+        assert_eq!(
+            last_applied(applied.iter(), "[HKEY_CURRENT_USER\\Software]@="),
+            None
+        );
+    }
+
+    #[test]
+    fn dpi_value() {
+        let (key, value) = format_value(&dpi(144)).unwrap();
+        assert_eq!(key, "HKEY_CURRENT_USER\\Control Panel\\Desktop");
+        assert_eq!(value, "\"LogPixels\"=dword:00000090");
+    }
+
+    #[test]
+    fn query() {
+        let output = "\r\nHKEY_CURRENT_USER\\Software\\Wine\\DllOverrides\r\n    \
+            d3d11    REG_SZ    native\r\n    \
+            (Default)    REG_SZ    \r\n    \
+            nvapi64    REG_SZ    native,builtin\r\n\r\n";
+
+        assert_eq!(
+            parse_query(output),
+            [
+                ("d3d11".to_owned(), "native".to_owned()),
+                ("(Default)".to_owned(), String::new()),
+                ("nvapi64".to_owned(), "native,builtin".to_owned()),
+            ]
+        );
+    }
+}
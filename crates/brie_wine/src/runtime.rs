@@ -1,8 +1,15 @@
-use std::{path::Path, time::Duration};
+use std::{
+    env, fs, io,
+    os::unix::fs::{symlink, PermissionsExt},
+    path::{Path, PathBuf},
+    time::Duration,
+};
 
-use brie_cfg::{Runtime, Tokens};
+use brie_cfg::{ReleaseVersion, Runtime, Tokens};
 
-use crate::library::{self, ensure_library_exists, WineGe, WineTkg};
+use crate::library::{
+    self, ensure_library_exists, Downloadable, LastCheck, PostExtract, WineGe, WineTkg,
+};
 
 #[derive(thiserror::Error, Debug)]
 pub enum Error {
@@ -10,39 +17,253 @@ pub enum Error {
     Which(#[from] which::Error),
     #[error("Download error. {0}")]
     Library(#[from] library::Error),
+    #[error(
+        "Unexpected runtime archive layout, `{}` is missing or not executable.",
+        expected_path.display()
+    )]
+    LayoutMismatch { expected_path: PathBuf },
 }
 
+/// Names of the wine binary in the order of preference, since some builds only ship `wine64`
+const WINE_BINARIES: [&str; 2] = ["wine", "wine64"];
+
+/// Name of the launcher script in the root of a Proton installation
+const PROTON_SCRIPT: &str = "proton";
+
 /// This function checks if a requested runtime exists, and downloads it if it doesn't.
 /// In case native runtime was requested, simply checks that wine binary
 /// is either accessible by it's optional path or is in $PATH env.
+/// A Proton installation is checked to contain the `proton` script and a wine binary.
 ///
-/// In case of success returns a full path to wine binary.
+/// In case of success returns a full path to wine (or `wine64` if there is no `wine`) binary.
 pub fn ensure_runtime_exists(
     tokens: &Tokens,
     library_dir: impl AsRef<Path>,
     runtime: &Runtime,
-    time_since_update: Option<Duration>,
+    last_check: &LastCheck,
+    post_extract: &PostExtract,
+    offline: bool,
 ) -> Result<library::State, Error> {
     Ok(match runtime {
-        Runtime::System { path: None } => library::State::untouched(which::which("wine")?),
+        Runtime::System { path: None } => library::State::untouched(which_wine(PathBuf::from)?),
         Runtime::System { path: Some(path) } => {
-            library::State::untouched(which::which(path.join("wine"))?)
+            library::State::untouched(which_wine(|binary| path.join(binary))?)
         }
         Runtime::Tkg { version } => {
-            let state =
-                ensure_library_exists(&WineTkg, library_dir, tokens, version, time_since_update)?;
+            let state = ensure_library_exists(
+                &WineTkg,
+                library_dir,
+                tokens,
+                version,
+                last_check,
+                post_extract,
+                offline,
+            )?;
             library::State {
-                path: state.path.join("usr").join("bin").join("wine"),
+                path: find_wine(&state.path.join("usr").join("bin"))?,
                 updated: state.updated,
+                version: state.version,
+            }
+        }
+        Runtime::Proton { path } => {
+            let script = path.join(PROTON_SCRIPT);
+            if !is_executable(&script) {
+                return Err(Error::LayoutMismatch {
+                    expected_path: script,
+                });
             }
+
+            let files = find_wine(&path.join("files").join("bin"));
+            library::State::untouched(
+                files.or_else(|e| find_wine(&path.join("dist").join("bin")).map_err(|_| e))?,
+            )
         }
         Runtime::GeProton { version } => {
-            let state =
-                ensure_library_exists(&WineGe, library_dir, tokens, version, time_since_update)?;
+            let state = ensure_library_exists(
+                &WineGe,
+                library_dir,
+                tokens,
+                version,
+                last_check,
+                post_extract,
+                offline,
+            )?;
             library::State {
-                path: state.path.join("bin").join("wine"),
+                path: find_wine(&state.path.join("bin"))?,
                 updated: state.updated,
+                version: state.version,
             }
         }
     })
 }
+
+/// Library directory name and version of a runtime which is downloaded
+pub fn downloaded(runtime: &Runtime) -> Option<(&'static str, &ReleaseVersion)> {
+    match runtime {
+        Runtime::GeProton { version } => Some((WineGe.name(), version)),
+        Runtime::Tkg { version } => Some((WineTkg.name(), version)),
+        Runtime::System { .. } | Runtime::Proton { .. } => None,
+    }
+}
+
+/// How a unit command is run with a runtime
+pub trait RuntimeBackend {
+    /// Program and leading arguments which the unit command is appended to
+    fn command(&self, wine: &Path) -> Vec<String>;
+
+    /// Environment variables required to run the unit command in `prefix`,
+    /// creating anything they point to
+    fn env(&self, prefix: &Path) -> Result<Vec<(&'static str, String)>, io::Error>;
+}
+
+/// Runs commands as `wine <command>`
+pub struct Wine;
+
+impl RuntimeBackend for Wine {
+    fn command(&self, wine: &Path) -> Vec<String> {
+        vec![wine.to_string_lossy().to_string()]
+    }
+
+    fn env(&self, _prefix: &Path) -> Result<Vec<(&'static str, String)>, io::Error> {
+        Ok(vec![])
+    }
+}
+
+/// Runs commands as `proton run <command>`
+pub struct Proton {
+    pub script: PathBuf,
+}
+
+impl RuntimeBackend for Proton {
+    fn command(&self, _wine: &Path) -> Vec<String> {
+        vec![self.script.to_string_lossy().to_string(), "run".to_owned()]
+    }
+
+    /// Proton expects the wine prefix in a `pfx` subdirectory of its compat data directory,
+    /// so a hidden compat data directory with a `pfx` link back to the prefix is created in it.
+    fn env(&self, prefix: &Path) -> Result<Vec<(&'static str, String)>, io::Error> {
+        let compat_data = prefix.join(".proton");
+        fs::create_dir_all(&compat_data)?;
+        let pfx = compat_data.join("pfx");
+        if fs::symlink_metadata(&pfx).is_err() {
+            symlink("..", &pfx)?;
+        }
+
+        let steam = env::var("HOME")
+            .map(|home| Path::new(&home).join(".steam").join("steam"))
+            .unwrap_or_default();
+
+        Ok(vec![
+            (
+                "STEAM_COMPAT_DATA_PATH",
+                compat_data.to_string_lossy().to_string(),
+            ),
+            (
+                "STEAM_COMPAT_CLIENT_INSTALL_PATH",
+                steam.to_string_lossy().to_string(),
+            ),
+        ])
+    }
+}
+
+/// Returns the backend unit commands are run with for a runtime
+pub fn backend(runtime: &Runtime) -> Box<dyn RuntimeBackend> {
+    match runtime {
+        Runtime::Proton { path } => Box::new(Proton {
+            script: path.join(PROTON_SCRIPT),
+        }),
+        Runtime::System { .. } | Runtime::GeProton { .. } | Runtime::Tkg { .. } => Box::new(Wine),
+    }
+}
+
+fn which_wine(path: impl Fn(&'static str) -> PathBuf) -> Result<PathBuf, which::Error> {
+    which::which(path(WINE_BINARIES[0])).or_else(|e| {
+        WINE_BINARIES[1..]
+            .iter()
+            .find_map(|&binary| which::which(path(binary)).ok())
+            .ok_or(e)
+    })
+}
+
+/// Finds the wine binary in a downloaded runtime, checking that it is executable
+fn find_wine(dir: &Path) -> Result<PathBuf, Error> {
+    WINE_BINARIES
+        .iter()
+        .map(|binary| dir.join(binary))
+        .find(|path| is_executable(path))
+        .ok_or_else(|| Error::LayoutMismatch {
+            expected_path: dir.join(WINE_BINARIES[0]),
+        })
+}
+
+fn is_executable(path: &Path) -> bool {
+    path.metadata()
+        .is_ok_and(|m| m.is_file() && m.permissions().mode() & 0o111 != 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, os::unix::fs::PermissionsExt, path::Path};
+
+    use super::{find_wine, Error, Proton, RuntimeBackend, Wine};
+
+    #[test]
+    fn find_wine_layout() {
+        let dir = Path::new(".tmp").join("find_wine_layout");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+
+        let wine = dir.join("wine");
+        assert!(matches!(
+            find_wine(&dir),
+            Err(Error::LayoutMismatch { expected_path }) if expected_path == wine
+        ));
+
+        fs::write(&wine, "").unwrap();
+        assert!(find_wine(&dir).is_err());
+
+        let wine64 = dir.join("wine64");
+        fs::write(&wine64, "").unwrap();
+        fs::set_permissions(&wine64, fs::Permissions::from_mode(0o755)).unwrap();
+        assert_eq!(find_wine(&dir).unwrap(), wine64);
+
+        fs::set_permissions(&wine, fs::Permissions::from_mode(0o755)).unwrap();
+        assert_eq!(find_wine(&dir).unwrap(), wine);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn backend_commands() {
+        let wine = Path::new("/opt/wine/bin/wine");
+        assert_eq!(Wine.command(wine), ["/opt/wine/bin/wine"]);
+
+        let proton = Proton {
+            script: Path::new("/opt/proton/proton").to_owned(),
+        };
+        assert_eq!(proton.command(wine), ["/opt/proton/proton", "run"]);
+
+        let prefix = Path::new(".tmp").join("backend_commands");
+        let _ = fs::remove_dir_all(&prefix);
+        fs::create_dir_all(&prefix).unwrap();
+
+        assert!(Wine.env(&prefix).unwrap().is_empty());
+        let env = proton.env(&prefix).unwrap();
+        let compat_data = prefix.join(".proton");
+        assert_eq!(
+            env[0],
+            (
+                "STEAM_COMPAT_DATA_PATH",
+                compat_data.to_string_lossy().to_string()
+            )
+        );
+        assert_eq!(
+            fs::canonicalize(compat_data.join("pfx")).unwrap(),
+            fs::canonicalize(&prefix).unwrap()
+        );
+        // The link is kept on subsequent runs
+        proton.env(&prefix).unwrap();
+
+        fs::remove_dir_all(&prefix).unwrap();
+    }
+}
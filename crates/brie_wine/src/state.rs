@@ -4,7 +4,7 @@ use std::{
     time::SystemTime,
 };
 
-use brie_cfg::Library;
+use brie_cfg::{Library, ReleaseVersion};
 use log::info;
 use serde::{Deserialize, Serialize};
 use ureq::serde_json;
@@ -13,6 +13,27 @@ use ureq::serde_json;
 pub struct State {
     pub wine: Option<SystemTime>,
     pub libraries: HashMap<Library, SystemTime>,
+    /// Release versions `latest` and `latest-<n>` links were last resolved to,
+    /// keyed by the library directory and the link name, e.g. `dxvk/latest-1`
+    #[serde(default)]
+    pub versions: HashMap<String, String>,
+}
+
+impl State {
+    /// Release version a link of a downloaded runtime or library was last resolved to
+    pub fn resolved(&self, name: &str, version: &ReleaseVersion) -> Option<&str> {
+        self.versions
+            .get(&version_key(name, version))
+            .map(String::as_str)
+    }
+
+    pub fn set_resolved(&mut self, name: &str, version: &ReleaseVersion, resolved: String) {
+        self.versions.insert(version_key(name, version), resolved);
+    }
+}
+
+fn version_key(name: &str, version: &ReleaseVersion) -> String {
+    format!("{name}/{}", version.to_str())
 }
 
 fn path(library_path: &Path) -> PathBuf {
@@ -33,3 +54,36 @@ pub fn write(library_path: &Path, state: &State) -> std::io::Result<()> {
     std::fs::write(path(library_path), state)?;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use ureq::serde_json;
+
+    use brie_cfg::ReleaseVersion;
+
+    use super::State;
+
+    #[test]
+    fn read_without_versions() {
+        let state = r#"{ "wine": null, "libraries": {} }"#;
+        let state: State = serde_json::from_str(state).unwrap();
+        assert!(state.versions.is_empty());
+    }
+
+    #[test]
+    fn resolved_per_link() {
+        let mut state = State::default();
+        state.set_resolved("dxvk", &ReleaseVersion::Latest, "v2.5".to_owned());
+        state.set_resolved("dxvk", &ReleaseVersion::LatestOffset(1), "v2.4".to_owned());
+
+        assert_eq!(
+            state.resolved("dxvk", &ReleaseVersion::Latest),
+            Some("v2.5")
+        );
+        assert_eq!(
+            state.resolved("dxvk", &ReleaseVersion::LatestOffset(1)),
+            Some("v2.4")
+        );
+        assert_eq!(state.resolved("wine-tkg", &ReleaseVersion::Latest), None);
+    }
+}
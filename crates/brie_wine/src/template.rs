@@ -0,0 +1,95 @@
+use std::path::{Path, PathBuf};
+
+use indexmap::IndexMap;
+
+/// Substitutes `{prefix}`, `{drive_c}` and `{game_drive}` tokens in unit commands and `cd`.
+/// `{{` and `}}` produce literal braces, unknown tokens are kept as they are.
+pub struct Template {
+    vars: Vec<(&'static str, PathBuf)>,
+}
+
+impl Template {
+    /// `{game_drive}` is the first mounted drive, e.g. `<prefix>/dosdevices/d:`
+    pub fn new(prefix: &Path, mounts: &IndexMap<char, String>) -> Self {
+        let mut vars = vec![
+            ("prefix", prefix.to_owned()),
+            ("drive_c", prefix.join("drive_c")),
+        ];
+        if let Some(drive) = mounts.keys().next() {
+            vars.push((
+                "game_drive",
+                prefix.join("dosdevices").join(format!("{drive}:")),
+            ));
+        }
+
+        Self { vars }
+    }
+
+    pub fn apply(&self, s: &str) -> String {
+        let mut result = String::with_capacity(s.len());
+        let mut rest = s;
+
+        while let Some(i) = rest.find(['{', '}']) {
+            result.push_str(&rest[..i]);
+            rest = &rest[i..];
+
+            if rest.starts_with("{{") || rest.starts_with("}}") {
+                result.push_str(&rest[..1]);
+                rest = &rest[2..];
+                continue;
+            }
+
+            let var = rest[1..].split_once('}').and_then(|(name, _)| {
+                self.vars
+                    .iter()
+                    .find(|(var, _)| *var == name)
+                    .map(|(_, path)| (name.len() + 2, path))
+            });
+
+            match var {
+                Some((len, path)) => {
+                    result.push_str(&path.to_string_lossy());
+                    rest = &rest[len..];
+                }
+                None => {
+                    result.push_str(&rest[..1]);
+                    rest = &rest[1..];
+                }
+            }
+        }
+
+        result.push_str(rest);
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use indexmap::IndexMap;
+
+    use super::Template;
+
+    #[test]
+    fn apply() {
+        let prefix = Path::new("/prefixes/game");
+        let template = Template::new(prefix, &[('d', "/mnt/games".to_owned())].into());
+
+        assert_eq!(template.apply("{prefix}"), "/prefixes/game");
+        assert_eq!(
+            template.apply("{drive_c}/Games/game.exe"),
+            "/prefixes/game/drive_c/Games/game.exe"
+        );
+        assert_eq!(
+            template.apply("{game_drive}/bin"),
+            "/prefixes/game/dosdevices/d:/bin"
+        );
+        assert_eq!(template.apply("{{prefix}}"), "{prefix}");
+        assert_eq!(template.apply("{unknown} {prefix"), "{unknown} {prefix");
+        assert_eq!(template.apply("a}b{"), "a}b{");
+
+        let template = Template::new(prefix, &IndexMap::new());
+        assert_eq!(template.apply("{game_drive}"), "{game_drive}");
+    }
+}
@@ -1,16 +1,21 @@
 use std::{
     borrow::Cow,
-    collections::HashMap,
-    io::Read,
+    collections::{BTreeMap, HashMap, HashSet},
+    io::{ErrorKind, Read},
     path::{Path, PathBuf},
+    sync::Mutex,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
-use brie_cfg::Brie;
-use brie_download::{download_file, mp, ureq, TlsError};
-use image::{GenericImageView, ImageFormat};
+use brie_cfg::{Brie, UnitCommon};
+use brie_download::{download_file, mp, retry_after, ureq, TlsError};
+use image::{
+    codecs::ico::{IcoEncoder, IcoFrame},
+    GenericImageView, ImageFormat,
+};
 use indicatif::{ProgressBar, ProgressFinish, ProgressStyle};
 use log::{debug, error, info, warn};
-use rayon::iter::{IntoParallelIterator, IntoParallelRefIterator, ParallelIterator};
+use rayon::iter::{IntoParallelIterator, ParallelIterator};
 use serde::{Deserialize, Serialize};
 
 #[derive(thiserror::Error, Debug)]
@@ -33,6 +38,53 @@ pub enum Error {
     Png(#[from] png::EncodingError),
     #[error("JSON error. {0}")]
     Json(#[from] serde_json::Error),
+    #[error("steamgriddb API error ({status}). {message}")]
+    SteamGridDb { status: u16, message: String },
+    #[error("steamgriddb request timed out after {}s.", .0.as_secs())]
+    Timeout(Duration),
+    #[error("All steamgriddb tokens are rate limited.")]
+    RateLimited,
+}
+
+/// Timeout of steamgriddb API requests, unless set with `steamgriddb_timeout` in the config
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(30);
+/// How long a token is not used after a `429` response without a `Retry-After` header
+const RATE_LIMIT_RESET: Duration = Duration::from_secs(60);
+
+/// Tokens and timeout of steamgriddb API requests
+struct Api<'a> {
+    tokens: &'a [String],
+    /// When each token stops being rate limited, by token index
+    limited: Mutex<Vec<Option<Instant>>>,
+    timeout: Duration,
+}
+
+impl<'a> Api<'a> {
+    fn new(tokens: &'a [String], timeout: Duration) -> Self {
+        Self {
+            tokens,
+            limited: Mutex::new(vec![None; tokens.len()]),
+            timeout,
+        }
+    }
+
+    /// Index of the first token which is not rate limited
+    fn available(&self) -> Option<usize> {
+        let now = Instant::now();
+        let limited = self.limited.lock().unwrap();
+        limited
+            .iter()
+            .position(|until| until.map_or(true, |u| u <= now))
+    }
+
+    fn rate_limited(&self, index: usize, reset: Duration) {
+        warn!(
+            "steamgriddb token {} is rate limited, not using it for {}s",
+            index + 1,
+            reset.as_secs()
+        );
+        self.limited.lock().unwrap()[index] = Some(Instant::now() + reset);
+    }
 }
 
 #[derive(Deserialize)]
@@ -40,12 +92,84 @@ struct Container<T> {
     data: T,
 }
 
+/// Error response of the steamgriddb API
+#[derive(Deserialize)]
+struct ErrorResponse {
+    #[serde(default)]
+    errors: Vec<String>,
+}
+
+/// Makes an authorized GET request to the steamgriddb API.
+/// Error responses are reported as [`Error::SteamGridDb`], separately from network errors,
+/// and requests which take longer than the timeout as [`Error::Timeout`].
+/// A rate limited request is retried with the next token which is not rate limited.
+fn get<T: serde::de::DeserializeOwned>(api: &Api, url: &url::Url) -> Result<T, Error> {
+    for _ in 0..api.tokens.len() {
+        let Some(index) = api.available() else { break };
+        let res = ureq()?
+            .request_url("GET", url)
+            .set("Authorization", &format!("Bearer {}", api.tokens[index]))
+            .timeout(api.timeout)
+            .call();
+
+        let res = match res {
+            Err(ureq::Error::Status(429, res)) => {
+                let reset = retry_after(&res).unwrap_or(RATE_LIMIT_RESET);
+                api.rate_limited(index, reset.max(Duration::from_secs(1)));
+                continue;
+            }
+            res => res,
+        };
+
+        return match res {
+            Ok(res) => res.into_json().map_err(|e| match is_timeout(&e) {
+                true => Error::Timeout(api.timeout),
+                false => Error::Io(e),
+            }),
+            Err(ureq::Error::Status(status, res)) => Err(api_error(status, res.into_string().ok())),
+            Err(e) if is_timeout(&e) => Err(Error::Timeout(api.timeout)),
+            Err(e) => Err(Error::Http(Box::new(e))),
+        };
+    }
+
+    Err(Error::RateLimited)
+}
+
+/// Whether the error is caused by a timed out socket operation
+fn is_timeout(error: &(dyn std::error::Error + 'static)) -> bool {
+    let mut source = Some(error);
+    while let Some(error) = source {
+        let io = error.downcast_ref::<std::io::Error>();
+        if io.is_some_and(|e| matches!(e.kind(), ErrorKind::TimedOut | ErrorKind::WouldBlock)) {
+            return true;
+        }
+        source = error.source();
+    }
+
+    false
+}
+
+fn api_error(status: u16, body: Option<String>) -> Error {
+    let errors = body
+        .as_deref()
+        .and_then(|body| serde_json::from_str::<ErrorResponse>(body).ok())
+        .map(|res| res.errors.join(", "))
+        .filter(|errors| !errors.is_empty());
+
+    let mut message = errors.unwrap_or_else(|| "Unexpected response".to_owned());
+    if matches!(status, 401 | 403) {
+        message.push_str(". Check your steamgriddb token");
+    }
+
+    Error::SteamGridDb { status, message }
+}
+
 #[derive(Deserialize)]
 struct AutocompleteResponse {
     id: u32,
 }
 
-fn autocomplete(token: &str, name: &str) -> Result<Option<u32>, Error> {
+fn autocomplete(api: &Api, name: &str) -> Result<Option<u32>, Error> {
     info!("Finding steamgriddb id for `{name}`");
 
     let mut url = url::Url::parse("https://www.steamgriddb.com/api/v2/search/autocomplete")?;
@@ -53,12 +177,7 @@ fn autocomplete(token: &str, name: &str) -> Result<Option<u32>, Error> {
         .map_err(|()| Error::InvalidUrl)?
         .push(name);
 
-    let res: Container<Vec<AutocompleteResponse>> = ureq()?
-        .request_url("GET", &url)
-        .set("Authorization", &format!("Bearer {token}"))
-        .call()
-        .map_err(Box::new)?
-        .into_json()?;
+    let res: Container<Vec<AutocompleteResponse>> = get(api, &url)?;
 
     Ok(res.data.first().map(|r| r.id))
 }
@@ -93,6 +212,14 @@ impl ImageKind {
     fn require_png(self) -> bool {
         matches!(self, Self::Grid | Self::Icon)
     }
+
+    /// Images larger than this are downscaled, keeping the aspect ratio
+    fn max_size(self) -> Option<u32> {
+        match self {
+            ImageKind::Icon => Some(256),
+            ImageKind::Grid | ImageKind::Hero | ImageKind::Logo => None,
+        }
+    }
 }
 
 impl std::fmt::Display for ImageKind {
@@ -128,20 +255,15 @@ struct ImageResponse {
     width: u32,
 }
 
-fn image(token: &str, kind: ImageKind, id: u32, name: &str) -> Result<Option<Vec<u8>>, Error> {
+fn image(api: &Api, kind: ImageKind, id: u32, name: &str) -> Result<Option<Vec<u8>>, Error> {
     info!("Downloading and re-encoding `{kind}` image for {id} ({name})");
 
-    let url = format!(
+    let url = url::Url::parse(&format!(
         "https://www.steamgriddb.com/api/v2/{kind}/game/{id}",
         kind = kind.path()
-    );
+    ))?;
 
-    let res: Container<Vec<ImageResponse>> = ureq()?
-        .get(&url)
-        .set("Authorization", &format!("Bearer {token}"))
-        .call()
-        .map_err(Box::new)?
-        .into_json()?;
+    let res: Container<Vec<ImageResponse>> = get(api, &url)?;
 
     let Some(url) = kind.filter(&res.data) else {
         return Ok(None);
@@ -163,15 +285,22 @@ fn image(token: &str, kind: ImageKind, id: u32, name: &str) -> Result<Option<Vec
                         .unwrap(),
                 ),
         );
-        img = convert_to_png(&img)?;
+        img = convert_to_png(&img, kind.max_size())?;
         pb.finish_with_message(format!("Converted {id}-{kind} to png"));
     }
 
     Ok(Some(img))
 }
 
-fn convert_to_png(image: &[u8]) -> Result<Vec<u8>, Error> {
-    let image = image::load_from_memory(image)?;
+fn convert_to_png(image: &[u8], max_size: Option<u32>) -> Result<Vec<u8>, Error> {
+    let mut image = image::load_from_memory(image)?;
+    if let Some(max) = max_size {
+        let (width, height) = image.dimensions();
+        if width > max || height > max {
+            debug!("Downscaling {width}x{height} image to fit {max}x{max}");
+            image = image.resize(max, max, image::imageops::FilterType::Lanczos3);
+        }
+    }
     let (width, height) = image.dimensions();
 
     let (color_type, image) = match &image {
@@ -213,12 +342,90 @@ impl Images {
     }
 }
 
+/// How long a failed steamgriddb lookup is remembered before it is retried
+const MISS_TTL: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
 #[derive(Default, Serialize, Deserialize)]
 struct CachedAssets {
     ids: HashMap<String, Option<u32>>,
+    /// Unix timestamps of failed lookups by unit key
+    #[serde(default)]
+    misses: HashMap<String, u64>,
+    /// Queries which produced the cached ids by unit key, see [`lookup_key`]
+    #[serde(default)]
+    queries: HashMap<String, String>,
     images: HashMap<u32, Images>,
 }
 
+impl CachedAssets {
+    /// Whether a steamgriddb id lookup should be done for the unit
+    fn needs_lookup(&self, key: &str, unit: &UnitCommon, now: u64) -> bool {
+        if unit.steamgriddb_id.is_some() {
+            return false;
+        }
+
+        // Ids cached before the queries were stored are kept
+        if self
+            .queries
+            .get(key)
+            .is_some_and(|queried| *queried != lookup_key(key, unit))
+        {
+            return true;
+        }
+
+        match self.ids.get(key) {
+            Some(Some(_)) => false,
+            Some(None) => !self
+                .misses
+                .get(key)
+                .is_some_and(|&at| now.saturating_sub(at) < MISS_TTL.as_secs()),
+            None => true,
+        }
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
+
+/// Search queries for a unit in order of preference: explicit query, display name, unit key
+fn queries<'a>(key: &'a str, unit: &'a UnitCommon) -> Vec<&'a str> {
+    let mut queries = Vec::with_capacity(3);
+    for query in [
+        unit.steamgriddb_query.as_deref(),
+        unit.name.as_deref(),
+        Some(key),
+    ]
+    .into_iter()
+    .flatten()
+    {
+        if !queries.contains(&query) {
+            queries.push(query);
+        }
+    }
+
+    queries
+}
+
+/// All queries of a unit joined, to notice when the id has to be looked up again
+fn lookup_key(key: &str, unit: &UnitCommon) -> String {
+    queries(key, unit).join("\n")
+}
+
+/// Tries all queries for the unit until one matches
+fn find_id(api: &Api, key: &str, unit: &UnitCommon) -> Result<Option<u32>, Error> {
+    for query in queries(key, unit) {
+        if let Some(id) = autocomplete(api, query)? {
+            return Ok(Some(id));
+        }
+        debug!("No steamgriddb match for `{query}` (unit {key})");
+    }
+
+    Ok(None)
+}
+
 #[derive(Default, Serialize, Deserialize)]
 pub struct Assets {
     ids: HashMap<String, u32>,
@@ -247,42 +454,93 @@ impl Assets {
     }
 }
 
-fn ensure_steamgriddb_ids(
+/// Looks up missing steamgriddb ids and downloads missing images. Images of a unit are downloaded
+/// as soon as its id is known, without waiting for the other lookups to finish. Images are downloaded
+/// once per id, no matter how many units share it. The cache is only updated after everything finishes.
+///
+/// Ideally found ids would be appended to `brie.yaml`, but that might be complicated,
+/// considering formatting and comments should remain intact.
+fn fetch_assets<L, F>(
     assets: &mut CachedAssets,
-    token: &str,
     config: &Brie,
-) -> HashMap<String, u32> {
-    info!("Finding missing steamgriddb ids");
+    cache_dir: &Path,
+    lookup: L,
+    download: F,
+) -> HashMap<String, u32>
+where
+    L: Fn(&str, &UnitCommon) -> Result<Option<u32>, Error> + Sync,
+    F: Fn(ImageKind, u32, &str) -> Result<Option<Vec<u8>>, Error> + Sync,
+{
+    let _ = std::fs::create_dir_all(cache_dir.join("images"));
 
-    // Find ids in steamgriddb for units missing it. Ideally it should append it to `brie.yaml`, but
-    // that might be complicated, considering formatting and comments should remain intact.
-    let found_ids = config
+    let now = now();
+    let known = grouped(&known_ids(assets, config));
+    let pending = config
         .units
-        .par_iter()
-        .map(|(k, v)| (k, v.common()))
-        .filter(|(k, v)| !assets.ids.contains_key(*k) && v.steamgriddb_id.is_none())
-        .filter_map(
-            |(k, v)| match autocomplete(token, v.name.as_ref().unwrap_or(k)) {
-                Ok(Some(id)) => Some((k, Some(id))),
-                Ok(None) => {
-                    warn!("No id found for unit {k} in steamgriddb");
-                    Some((k, None))
-                }
-                Err(e) => {
-                    error!("Failed to find id for {k}: {e}");
-                    None
-                }
-            },
-        )
-        .map(|(k, id)| (k.to_owned(), id))
-        .collect::<HashMap<_, _>>();
+        .iter()
+        .map(|(k, v)| (k.as_str(), v.common()))
+        .filter(|(k, v)| assets.needs_lookup(k, v, now))
+        .collect::<Vec<_>>();
+
+    let cached = &assets.images;
+    let scheduled = Mutex::new(HashSet::new());
+    let fetch = |id: u32, name: &str| {
+        let first = scheduled.lock().unwrap().insert(id);
+        match first {
+            true => fetch_id(cached, cache_dir, id, name, &download),
+            false => Vec::new(),
+        }
+    };
+
+    if !pending.is_empty() {
+        info!("Finding missing steamgriddb ids");
+    }
+    let (known_images, found) = rayon::join(
+        || {
+            known
+                .into_par_iter()
+                .flat_map_iter(|(id, name)| fetch(id, &name))
+                .collect::<Vec<_>>()
+        },
+        || {
+            pending
+                .into_par_iter()
+                .filter_map(|(k, v)| match lookup(k, v) {
+                    Ok(Some(id)) => Some((k, v, Some(id), fetch(id, k))),
+                    Ok(None) => {
+                        warn!("No id found for unit {k} in steamgriddb, set `steamgriddb_query` or `steamgriddb_id` for it");
+                        Some((k, v, None, Vec::new()))
+                    }
+                    Err(e) => {
+                        error!("Failed to find id for {k}: {e}");
+                        None
+                    }
+                })
+                .collect::<Vec<_>>()
+        },
+    );
+
+    let mut images = known_images;
+    for (key, unit, id, found_images) in found {
+        debug!("Found id for {key}: {id:?}");
+        match id {
+            Some(_) => assets.misses.remove(key),
+            None => assets.misses.insert(key.to_owned(), now),
+        };
+        assets.ids.insert(key.to_owned(), id);
+        assets.queries.insert(key.to_owned(), lookup_key(key, unit));
+        images.extend(found_images);
+    }
 
-    if !found_ids.is_empty() {
-        debug!("Found ids: {found_ids:?}");
-        assets.ids.extend(found_ids);
+    for ((id, kind), path) in images {
+        assets.images.entry(id).or_default().0.insert(kind, path);
     }
 
-    // Merge cached ids with ids defined in the unit file
+    known_ids(assets, config)
+}
+
+/// Merges cached ids with ids defined in the unit file
+fn known_ids(assets: &CachedAssets, config: &Brie) -> HashMap<String, u32> {
     let cached_ids = assets
         .ids
         .iter()
@@ -299,31 +557,49 @@ fn ensure_steamgriddb_ids(
     predefined_ids
 }
 
-fn ensure_images_exist(
-    assets: &mut CachedAssets,
-    id_map: &HashMap<String, u32>,
-    token: &str,
-    cache_dir: &Path,
-) {
-    let _ = std::fs::create_dir_all(cache_dir.join("images"));
+/// Groups units by id. Units sharing an id are listed together in logs, in a stable order
+fn grouped(id_map: &HashMap<String, u32>) -> Vec<(u32, String)> {
+    let mut units = BTreeMap::<u32, Vec<&str>>::new();
+    for (unit, &id) in id_map {
+        units.entry(id).or_default().push(unit);
+    }
 
-    let ids = id_map
-        .iter()
-        .map(|(name, id)| (id, (name, assets.images.get(id))))
-        .collect::<HashMap<_, _>>();
-    let paths = ids
-        .par_iter()
-        .flat_map(|(&&id, &name)| ImageKind::all().map(|kind| (id, name, kind)))
+    units
+        .into_iter()
+        .map(|(id, mut units)| {
+            units.sort_unstable();
+            (id, units.join(", "))
+        })
+        .collect()
+}
+
+/// Downloads images of all kinds missing for the id in parallel, returns paths of all its images
+fn fetch_id<F>(
+    cached: &HashMap<u32, Images>,
+    cache_dir: &Path,
+    id: u32,
+    name: &str,
+    download: &F,
+) -> Vec<((u32, ImageKind), PathBuf)>
+where
+    F: Fn(ImageKind, u32, &str) -> Result<Option<Vec<u8>>, Error> + Sync,
+{
+    ImageKind::all()
         .into_par_iter()
-        .filter_map(|(id, (name, cache), kind)| {
-            if let Some(cached) = cache.and_then(|c| c.0.get(&kind)) {
+        .filter_map(|kind| {
+            if let Some(cached) = cached.get(&id).and_then(|c| c.get(kind)) {
                 if cached.exists() {
-                    return Some(((id, kind), cached.clone()));
+                    if let Err(e) = ensure_max_size(cached, kind) {
+                        warn!(
+                            "Failed to downscale cached `{kind}` image for id {id} ({name}): {e}"
+                        );
+                    }
+                    return Some(((id, kind), cached.to_owned()));
                 }
             }
 
             let path = cache_dir.join("images").join(format!("{id}-{kind}"));
-            match image(token, kind, id, name) {
+            match download(kind, id, name) {
                 Ok(Some(img)) => {
                     let ext = match image::guess_format(&img) {
                         Ok(ImageFormat::Jpeg) => "jpg",
@@ -336,10 +612,13 @@ fn ensure_images_exist(
                     };
 
                     let path = path.with_extension(ext);
-
-                    // TODO: error handling?
-                    let _ = std::fs::write(&path, img);
-                    Some(((id, kind), path))
+                    match std::fs::write(&path, img) {
+                        Ok(()) => Some(((id, kind), path)),
+                        Err(e) => {
+                            error!("Failed to save `{kind}` image for id {id} ({name}): {e}");
+                            None
+                        }
+                    }
                 }
                 Ok(None) => {
                     warn!("No `{kind}` image found for id {id} ({name})");
@@ -351,24 +630,146 @@ fn ensure_images_exist(
                 }
             }
         })
-        .collect::<HashMap<_, _>>();
+        .collect()
+}
 
-    for ((id, kind), path) in paths {
-        assets.images.entry(id).or_default().0.insert(kind, path);
+/// Downscales images cached before the size limit was introduced
+fn ensure_max_size(path: &Path, kind: ImageKind) -> Result<(), Error> {
+    let Some(max) = kind.max_size() else {
+        return Ok(());
+    };
+
+    let (width, height) = image::image_dimensions(path)?;
+    if width > max || height > max {
+        let img = convert_to_png(&std::fs::read(path)?, Some(max))?;
+        std::fs::write(path, img)?;
+    }
+
+    Ok(())
+}
+
+/// Sizes of the images in generated `.ico` icons
+const ICO_SIZES: [u32; 4] = [16, 32, 48, 256];
+
+/// Converts an icon to a multi-resolution `.ico` next to it, unless it is already converted.
+/// Sizes larger than the icon are skipped, except for the smallest one.
+fn ensure_ico(icon: &Path) -> Result<PathBuf, Error> {
+    let ico = icon.with_extension("ico");
+    let modified = |path: &Path| path.metadata().and_then(|m| m.modified()).ok();
+    if ico.exists() && modified(&ico) >= modified(icon) {
+        return Ok(ico);
+    }
+
+    debug!("Converting {} to ico", icon.display());
+    let image = image::open(icon)?;
+    let (width, height) = image.dimensions();
+    let mut frames = Vec::new();
+    for (i, size) in ICO_SIZES.into_iter().enumerate() {
+        if i > 0 && size > width.max(height) {
+            break;
+        }
+
+        let frame = image
+            .resize(size, size, image::imageops::FilterType::Lanczos3)
+            .into_rgba8();
+        frames.push(IcoFrame::as_png(
+            frame.as_raw(),
+            frame.width(),
+            frame.height(),
+            image::ColorType::Rgba8,
+        )?);
+    }
+
+    let mut file = Vec::new();
+    IcoEncoder::new(&mut file).encode_images(&frames)?;
+    std::fs::write(&ico, file)?;
+
+    Ok(ico)
+}
+
+/// Replaces icons with `.ico` ones, converting them if `convert` is set,
+/// otherwise only using already converted ones
+fn use_ico(images: &mut HashMap<u32, Images>, convert: bool) {
+    for (id, images) in images {
+        let Some(icon) = images.0.get_mut(&ImageKind::Icon) else {
+            continue;
+        };
+
+        let ico = match convert {
+            true => ensure_ico(icon),
+            false => match icon.with_extension("ico") {
+                ico if ico.exists() => Ok(ico),
+                _ => continue,
+            },
+        };
+        match ico {
+            Ok(ico) => *icon = ico,
+            Err(e) => warn!("Failed to convert icon for id {id} to ico: {e}"),
+        }
     }
 }
 
+fn read_cache(path: &Path) -> CachedAssets {
+    std::fs::read(path)
+        .ok()
+        .and_then(|bytes| serde_json::from_slice(&bytes).ok())
+        .unwrap_or_default()
+}
+
+/// Reports steamgriddb lookups and image downloads which [`download_all`] would do,
+/// without making network requests or writing files. Returns cached assets only.
+pub fn plan(cache_dir: &Path, config: &Brie) -> Assets {
+    let assets = read_cache(&cache_dir.join("assets.json"));
+    let ids = known_ids(&assets, config);
+
+    if !config
+        .tokens
+        .as_ref()
+        .is_some_and(|t| !t.steamgriddb.is_empty())
+    {
+        warn!("steamgriddb_token is not defined in the config, nothing would be downloaded");
+        return Assets {
+            ids,
+            images: assets.images,
+        };
+    }
+
+    let now = now();
+    for (key, unit) in config.units.iter().map(|(k, v)| (k, v.common())) {
+        if assets.needs_lookup(key, unit, now) {
+            let queries = queries(key, unit).join("`, `");
+            info!("Would look up steamgriddb id for `{queries}` and download its images");
+        }
+    }
+
+    for (name, id) in &ids {
+        let cached = assets.images.get(id);
+        for kind in ImageKind::all() {
+            if !cached.and_then(|c| c.get(kind)).is_some_and(Path::exists) {
+                info!("Would download `{kind}` image for {id} ({name})");
+            }
+        }
+    }
+
+    // Icons which are not converted yet are listed as downloaded
+    let mut images = assets.images;
+    if config.ico_icons {
+        info!("Would convert icons to ico");
+        use_ico(&mut images, false);
+    }
+
+    Assets { ids, images }
+}
+
 pub fn download_all(cache_dir: &Path, config: &Brie) -> Result<Assets, Error> {
     info!("Downloading banners and icons from steamgriddb");
     let _ = std::fs::create_dir_all(cache_dir);
 
     let asset_cache = cache_dir.join("assets.json");
-    let mut assets: CachedAssets = std::fs::read(&asset_cache)
-        .ok()
-        .and_then(|bytes| serde_json::from_slice(&bytes).ok())
-        .unwrap_or_default();
+    let mut assets = read_cache(&asset_cache);
 
-    let Some(token) = config.tokens.as_ref().and_then(|t| t.steamgriddb.as_ref()) else {
+    let tokens = config.tokens.as_ref().map(|t| t.steamgriddb.as_slice());
+    let Some(tokens) = tokens.filter(|t| !t.is_empty()) else {
         warn!("steamgriddb_token is not defined in the config");
         return Ok(Assets {
             ids: assets
@@ -380,48 +781,308 @@ pub fn download_all(cache_dir: &Path, config: &Brie) -> Result<Assets, Error> {
         });
     };
 
-    let id_map = ensure_steamgriddb_ids(&mut assets, token, config);
-    ensure_images_exist(&mut assets, &id_map, token, cache_dir);
+    let timeout = config.steamgriddb_timeout;
+    let api = Api::new(tokens, timeout.map_or(DEFAULT_TIMEOUT, Duration::from_secs));
+    let id_map = fetch_assets(
+        &mut assets,
+        config,
+        cache_dir,
+        |key, unit| find_id(&api, key, unit),
+        |kind, id, name| image(&api, kind, id, name),
+    );
 
     let cached_ids = serde_json::to_vec(&assets)?;
     std::fs::write(&asset_cache, cached_ids)?;
 
+    // Only returned paths are replaced, the cache keeps the downloaded icons
+    let mut images = assets.images;
+    if config.ico_icons {
+        use_ico(&mut images, true);
+    }
+
     Ok(Assets {
         ids: id_map,
-        images: assets.images,
+        images,
     })
 }
 
 #[cfg(test)]
 mod tests {
-    use std::path::Path;
+    use std::{path::Path, sync::Mutex, time::Duration};
 
     use brie_cfg::Tokens;
     use brie_download::mp;
+    use image::{GenericImageView, ImageFormat};
     use indicatif_log_bridge::LogWrapper;
 
     use crate::assets::ImageKind;
 
-    use super::{autocomplete, download_all, image};
+    use super::{
+        api_error, autocomplete, convert_to_png, download_all, ensure_ico, fetch_assets, image,
+        is_timeout, queries, Api, Assets, CachedAssets, DEFAULT_TIMEOUT, MISS_TTL,
+    };
+
+    fn config(units: &[(&str, Option<u32>)]) -> brie_cfg::Brie {
+        let unit = |steamgriddb_id| {
+            brie_cfg::Unit::Native(brie_cfg::NativeUnit {
+                common: brie_cfg::UnitCommon {
+                    steamgriddb_id,
+                    ..Default::default()
+                },
+                ..Default::default()
+            })
+        };
+
+        brie_cfg::Brie {
+            units: units
+                .iter()
+                .map(|&(key, id)| (key.to_owned(), unit(id)))
+                .collect(),
+            ..Default::default()
+        }
+    }
+
+    fn png() -> Vec<u8> {
+        let mut png = Vec::new();
+        image::DynamicImage::new_rgba8(1, 1)
+            .write_to(
+                &mut std::io::Cursor::new(&mut png),
+                image::ImageOutputFormat::Png,
+            )
+            .unwrap();
+        png
+    }
+
+    #[test]
+    pub fn test_ensure_ico() {
+        let dir = Path::new(".tmp").join("ensure_ico");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let icon = dir.join("1-icon.png");
+        std::fs::write(&icon, png()).unwrap();
+        let ico = ensure_ico(&icon).unwrap();
+        assert_eq!(ico, dir.join("1-icon.ico"));
+
+        let converted = std::fs::read(&ico).unwrap();
+        assert_eq!(image::guess_format(&converted).unwrap(), ImageFormat::Ico);
+        assert_eq!(image::load_from_memory(&converted).unwrap().width(), 16);
+
+        // Already converted icons are kept
+        std::fs::write(&ico, "").unwrap();
+        assert_eq!(std::fs::read(ensure_ico(&icon).unwrap()).unwrap(), b"");
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
 
     const TOKEN: &str = "82e919fd236407ddbf5012fdb1b13126";
 
     #[test]
     pub fn test_autocomplete() {
-        let res = autocomplete(TOKEN, "The witcher 3").unwrap();
+        let tokens = [TOKEN.to_owned()];
+        let res = autocomplete(&Api::new(&tokens, DEFAULT_TIMEOUT), "The witcher 3").unwrap();
         assert_eq!(res, Some(4265));
     }
 
+    #[test]
+    pub fn test_rate_limited_tokens() {
+        let tokens = ["first".to_owned(), "second".to_owned()];
+        let api = Api::new(&tokens, DEFAULT_TIMEOUT);
+        assert_eq!(api.available(), Some(0));
+
+        api.rate_limited(0, Duration::from_secs(60));
+        assert_eq!(api.available(), Some(1));
+        api.rate_limited(1, Duration::from_secs(60));
+        assert_eq!(api.available(), None);
+    }
+
+    #[test]
+    pub fn test_api_error() {
+        let message = |status, body: &str| match api_error(status, Some(body.to_owned())) {
+            super::Error::SteamGridDb { message, .. } => message,
+            e => panic!("Unexpected error {e}"),
+        };
+
+        assert_eq!(
+            message(401, r#"{"success":false,"errors":["Invalid API key"]}"#),
+            "Invalid API key. Check your steamgriddb token"
+        );
+        assert_eq!(
+            message(404, r#"{"success":false,"errors":["Game not found"]}"#),
+            "Game not found"
+        );
+        assert_eq!(message(500, "<html>"), "Unexpected response");
+    }
+
+    #[test]
+    pub fn test_is_timeout() {
+        use std::io::{Error, ErrorKind};
+
+        assert!(is_timeout(&Error::from(ErrorKind::TimedOut)));
+        assert!(!is_timeout(&Error::from(ErrorKind::ConnectionRefused)));
+
+        let transport = ureq::Error::from(Error::from(ErrorKind::TimedOut));
+        assert!(is_timeout(&transport));
+    }
+
+    #[test]
+    pub fn test_queries() {
+        let unit = brie_cfg::UnitCommon {
+            name: Some("The Witcher 3".to_owned()),
+            steamgriddb_query: Some("The Witcher 3: Wild Hunt".to_owned()),
+            ..Default::default()
+        };
+        assert_eq!(
+            queries("witcher3", &unit),
+            ["The Witcher 3: Wild Hunt", "The Witcher 3", "witcher3"]
+        );
+
+        let unit = brie_cfg::UnitCommon {
+            name: Some("witcher3".to_owned()),
+            ..Default::default()
+        };
+        assert_eq!(queries("witcher3", &unit), ["witcher3"]);
+    }
+
+    #[test]
+    pub fn test_downscale() {
+        let mut img = Vec::new();
+        image::DynamicImage::new_rgba8(1024, 512)
+            .write_to(
+                &mut std::io::Cursor::new(&mut img),
+                image::ImageOutputFormat::Png,
+            )
+            .unwrap();
+
+        let icon = convert_to_png(&img, ImageKind::Icon.max_size()).unwrap();
+        let icon = image::load_from_memory(&icon).unwrap();
+        assert_eq!(image::GenericImageView::dimensions(&icon), (256, 128));
+
+        let grid = convert_to_png(&img, ImageKind::Grid.max_size()).unwrap();
+        let grid = image::load_from_memory(&grid).unwrap();
+        assert_eq!(image::GenericImageView::dimensions(&grid), (1024, 512));
+    }
+
+    #[test]
+    pub fn test_shared_ids() {
+        let cache_dir = Path::new(".tmp/shared_ids");
+        let _ = std::fs::remove_dir_all(cache_dir);
+
+        let png = png();
+        let config = config(&[
+            ("witcher3", Some(4265)),
+            ("witcher3-modded", Some(4265)),
+            ("outerwilds", Some(1)),
+        ]);
+        let downloads = Mutex::new(Vec::new());
+        let mut assets = CachedAssets::default();
+        let ids = fetch_assets(
+            &mut assets,
+            &config,
+            cache_dir,
+            |key, _| panic!("Unexpected lookup for {key}"),
+            |kind, id, name| {
+                downloads.lock().unwrap().push((id, kind, name.to_owned()));
+                Ok(Some(png.clone()))
+            },
+        );
+
+        let mut downloads = downloads.into_inner().unwrap();
+        assert_eq!(downloads.len(), 2 * ImageKind::all().len());
+        downloads.retain(|(id, _, _)| *id == 4265);
+        assert_eq!(downloads.len(), ImageKind::all().len());
+        assert!(downloads
+            .iter()
+            .all(|(_, _, name)| name == "witcher3, witcher3-modded"));
+
+        let assets = Assets {
+            ids,
+            images: assets.images,
+        };
+        for kind in ImageKind::all() {
+            let path = assets.get("witcher3", kind).unwrap();
+            assert_eq!(assets.get("witcher3-modded", kind), Some(path));
+            assert!(path.exists());
+        }
+
+        std::fs::remove_dir_all(cache_dir).unwrap();
+    }
+
+    #[test]
+    pub fn test_lookup_then_download() {
+        let cache_dir = Path::new(".tmp/lookup_then_download");
+        let _ = std::fs::remove_dir_all(cache_dir);
+
+        let png = png();
+        let config = config(&[("found", None), ("missing", None), ("known", Some(3))]);
+        let downloads = Mutex::new(Vec::new());
+        let mut assets = CachedAssets::default();
+        let ids = fetch_assets(
+            &mut assets,
+            &config,
+            cache_dir,
+            |key, _| Ok((key == "found").then_some(1)),
+            |kind, id, _| {
+                downloads.lock().unwrap().push((id, kind));
+                Ok(Some(png.clone()))
+            },
+        );
+
+        assert_eq!(
+            ids,
+            [("found".to_owned(), 1), ("known".to_owned(), 3)].into()
+        );
+        assert_eq!(assets.ids["found"], Some(1));
+        assert_eq!(assets.ids["missing"], None);
+        assert!(assets.misses.contains_key("missing"));
+        assert!(!assets.misses.contains_key("found"));
+
+        // Images of each id are downloaded once
+        let downloads = downloads.into_inner().unwrap();
+        assert_eq!(downloads.len(), 2 * ImageKind::all().len());
+        assert!(ImageKind::all()
+            .into_iter()
+            .all(|kind| assets.images[&1].get(kind).is_some()));
+
+        std::fs::remove_dir_all(cache_dir).unwrap();
+    }
+
+    #[test]
+    pub fn test_miss_ttl() {
+        let unit = brie_cfg::UnitCommon::default();
+        let mut assets = CachedAssets::default();
+        assert!(assets.needs_lookup("game", &unit, 1000));
+
+        assets.ids.insert("game".to_owned(), None);
+        assert!(assets.needs_lookup("game", &unit, 1000));
+
+        assets.misses.insert("game".to_owned(), 1000);
+        assert!(!assets.needs_lookup("game", &unit, 1000));
+        assert!(assets.needs_lookup("game", &unit, 1000 + MISS_TTL.as_secs()));
+
+        assets.ids.insert("game".to_owned(), Some(1));
+        assert!(!assets.needs_lookup("game", &unit, 1000 + MISS_TTL.as_secs()));
+
+        // A changed query is looked up again, even if an id was found
+        assets
+            .queries
+            .insert("game".to_owned(), lookup_key("game", &unit));
+        assert!(!assets.needs_lookup("game", &unit, 1000));
+        let renamed = brie_cfg::UnitCommon {
+            steamgriddb_query: Some("Game".to_owned()),
+            ..Default::default()
+        };
+        assert!(assets.needs_lookup("game", &renamed, 1000));
+    }
+
     #[test]
     #[ignore]
     pub fn test_banners() {
-        let res = image(TOKEN, ImageKind::Grid, 4265, "game")
-            .unwrap()
-            .unwrap();
+        let tokens = [TOKEN.to_owned()];
+        let api = Api::new(&tokens, DEFAULT_TIMEOUT);
+        let res = image(&api, ImageKind::Grid, 4265, "game").unwrap().unwrap();
         assert!(res == std::fs::read("tests/grid.png").unwrap());
-        let res = image(TOKEN, ImageKind::Icon, 4265, "game")
-            .unwrap()
-            .unwrap();
+        let res = image(&api, ImageKind::Icon, 4265, "game").unwrap().unwrap();
         assert!(res == std::fs::read("tests/icon.png").unwrap());
     }
 
@@ -435,7 +1096,7 @@ mod tests {
         let cache_dir = Path::new(".tmp/cache");
         let config = brie_cfg::Brie {
             tokens: Some(Tokens {
-                steamgriddb: Some(TOKEN.to_owned()),
+                steamgriddb: vec![TOKEN.to_owned()],
                 github: None,
             }),
             units: [
@@ -446,6 +1107,7 @@ mod tests {
                             name: Some("The Witcher 3".to_owned()),
                             ..Default::default()
                         },
+                        ..brie_cfg::NativeUnit::default()
                     }),
                 ),
                 (
@@ -460,7 +1122,7 @@ mod tests {
                 ),
             ]
             .into(),
-            paths: brie_cfg::Paths::default(),
+            ..Default::default()
         };
 
         download_all(cache_dir, &config).unwrap();
@@ -0,0 +1,66 @@
+use std::{
+    fs::{self, File},
+    io::{self, Write},
+    path::{Path, PathBuf},
+};
+
+use log::debug;
+
+/// Writes a file by writing to a temporary file in the same directory and renaming it over the
+/// target, so that the target file is never left partially written.
+/// Previous contents of the file are kept next to it with a `.bak` suffix.
+pub fn write(path: &Path, contents: &[u8]) -> io::Result<()> {
+    let tmp = with_suffix(path, ".tmp")?;
+
+    if let Err(e) = write_synced(&tmp, contents) {
+        let _ = fs::remove_file(&tmp);
+        return Err(e);
+    }
+
+    if path.exists() {
+        let backup = with_suffix(path, ".bak")?;
+        debug!("Backing up {} to {}", path.display(), backup.display());
+        fs::copy(path, backup)?;
+    }
+
+    fs::rename(tmp, path)
+}
+
+fn write_synced(path: &Path, contents: &[u8]) -> io::Result<()> {
+    let mut file = File::create(path)?;
+    file.write_all(contents)?;
+    file.sync_all()
+}
+
+fn with_suffix(path: &Path, suffix: &str) -> io::Result<PathBuf> {
+    let mut name = path
+        .file_name()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "Path has no file name"))?
+        .to_os_string();
+    name.push(suffix);
+
+    Ok(path.with_file_name(name))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::write;
+
+    #[test]
+    fn write_with_backup() {
+        let dir = Path::new(".tmp/atomic");
+        let _ = std::fs::remove_dir_all(dir);
+        std::fs::create_dir_all(dir).unwrap();
+
+        let path = dir.join("apps.json");
+        write(&path, b"first").unwrap();
+        assert!(!dir.join("apps.json.bak").exists());
+
+        write(&path, b"second").unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), b"second");
+        assert_eq!(std::fs::read(dir.join("apps.json.bak")).unwrap(), b"first");
+        assert!(!dir.join("apps.json.tmp").exists());
+    }
+}
@@ -0,0 +1,48 @@
+use std::{io, path::Path};
+
+use log::info;
+
+use crate::{exe, output::Output};
+
+const FILE_NAME: &str = "briectl-watch.desktop";
+
+/// Writes an XDG autostart entry which runs `briectl watch` on login
+pub fn install(
+    autostart: &Path,
+    exe: &str,
+    config: Option<&Path>,
+    out: Output,
+) -> Result<(), io::Error> {
+    out.create_dir_all(autostart);
+
+    let config = config
+        .map(|c| format!(" --config {}", exe::quote(&c.to_string_lossy())))
+        .map(|args| exe::escape_exec(&args))
+        .unwrap_or_default();
+    let desktop = format!(
+        "[Desktop Entry]\n\
+        Type=Application\n\
+        Version=1.0\n\
+        Name=briectl watch\n\
+        Comment=Regenerate brie launchers when the config changes\n\
+        Exec=\"{exe}\"{config} watch\n\
+        Terminal=false\n\
+        X-GNOME-Autostart-enabled=true\n"
+    );
+
+    let path = autostart.join(FILE_NAME);
+    info!("Writing autostart entry to {}", path.display());
+    out.write_owned(&path, desktop.as_bytes())
+}
+
+/// Removes the autostart entry written by [`install`]
+pub fn remove(autostart: &Path, out: Output) {
+    let path = autostart.join(FILE_NAME);
+    if !path.exists() {
+        info!("Autostart entry {} does not exist", path.display());
+        return;
+    }
+
+    info!("Removing autostart entry {}", path.display());
+    out.remove(&path);
+}
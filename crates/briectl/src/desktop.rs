@@ -1,10 +1,15 @@
 use std::{env::VarError, io, path::Path};
 
-use brie_cfg::Brie;
-use log::{debug, info};
+use brie_cfg::{Brie, Unit};
+use log::info;
 use shellexpand::LookupError;
 
-use crate::assets::{Assets, ImageKind};
+use crate::{
+    assets::{Assets, ImageKind},
+    exe,
+    output::Output,
+    Filter,
+};
 
 #[derive(thiserror::Error, Debug)]
 pub enum Error {
@@ -14,7 +19,13 @@ pub enum Error {
     Expand(#[from] LookupError<VarError>),
 }
 
-pub fn update(exe: &str, assets: &Assets, config: &Brie) -> Result<(), Error> {
+pub fn update(
+    exe: &str,
+    assets: &Assets,
+    config: &Brie,
+    filter: Filter,
+    out: Output,
+) -> Result<(), Error> {
     let Some(desktop_path) = config.paths.desktop.as_ref() else {
         info!("Desktop file path not provided, skipping generation");
         return Ok(());
@@ -22,27 +33,33 @@ pub fn update(exe: &str, assets: &Assets, config: &Brie) -> Result<(), Error> {
 
     let desktop_path = shellexpand::full(desktop_path)?;
     let desktop_path = Path::new(desktop_path.as_ref());
-    let _ = std::fs::create_dir_all(desktop_path);
-
-    // Remove existing files
-    desktop_path.read_dir()?.for_each(|entry| {
-        let entry = entry.unwrap();
-        let path = entry.path();
-        if path.is_file()
-            && path.starts_with("brie-")
-            && path.extension().is_some_and(|e| e == "desktop")
-        {
-            debug!("Removing {}", path.display());
-            let _ = std::fs::remove_file(&path);
-        }
-    });
-
-    // Recreate files for all units
+    out.create_dir_all(desktop_path);
+
+    // Remove existing files of the selected units. The directory may not exist in a dry run.
+    desktop_path
+        .read_dir()
+        .into_iter()
+        .flatten()
+        .filter_map(Result::ok)
+        .for_each(|entry| {
+            let path = entry.path();
+            let key = path
+                .file_name()
+                .and_then(|n| n.to_str())
+                .and_then(|n| n.strip_prefix("brie-"))
+                .and_then(|n| n.strip_suffix(".desktop"));
+
+            if path.is_file() && key.is_some_and(|key| filter.matches(key)) {
+                out.remove(&path);
+            }
+        });
+
+    // Recreate files for all selected units
     for (key, unit) in config
         .units
         .iter()
-        .map(|(k, v)| (k, v.common()))
-        .filter(|(_, u)| u.generate.desktop)
+        .filter(|(k, _)| filter.matches(k))
+        .filter(|(_, u)| u.common().generate.desktop)
     {
         let path = desktop_path.join(format!("brie-{key}.desktop"));
 
@@ -50,22 +67,98 @@ pub fn update(exe: &str, assets: &Assets, config: &Brie) -> Result<(), Error> {
             .get(key, ImageKind::Icon)
             .unwrap_or_else(|| Path::new(""));
 
-        let name = unit.name.as_ref().unwrap_or(key);
-        let desktop = format!(
-            "[Desktop Entry]\n\
-            Type=Application\n\
-            Version=1.0\n\
-            Name={name}\n\
-            Exec=\"{exe}\" {key}\n\
-            Icon={icon}\n\
-            Terminal=false\n\
-            Categories=Games;\n",
-            icon = icon.display()
-        );
+        let name = unit.common().name.as_ref().unwrap_or(key);
+        let commands = match unit {
+            Unit::Wine(unit) => unit.commands.keys().collect(),
+            Unit::Native(_) => Vec::new(),
+        };
+        let args = exe::escape_exec(&exe::args(key, &unit.common().launch_args));
+        let desktop = desktop_entry(exe, key, &args, name, icon, &commands);
 
         info!("Writing desktop file for {key} to {}", path.display());
-        std::fs::write(&path, desktop)?;
+        out.write_owned(&path, desktop.as_bytes())?;
     }
 
     Ok(())
 }
+
+/// Builds a desktop entry, with a desktop action for each named command of the unit
+fn desktop_entry(
+    exe: &str,
+    key: &str,
+    args: &str,
+    name: &str,
+    icon: &Path,
+    commands: &[&String],
+) -> String {
+    let actions = commands
+        .iter()
+        .map(|command| action_id(command))
+        .collect::<Vec<_>>();
+
+    let mut desktop = format!(
+        "[Desktop Entry]\n\
+        Type=Application\n\
+        Version=1.0\n\
+        Name={name}\n\
+        Exec=\"{exe}\" {args}\n\
+        Icon={icon}\n\
+        Terminal=false\n\
+        Categories=Games;\n",
+        icon = icon.display()
+    );
+
+    if !actions.is_empty() {
+        desktop.push_str(&format!("Actions={};\n", actions.join(";")));
+    }
+
+    for (command, action) in commands.iter().zip(&actions) {
+        let args = exe::escape_exec(&exe::args(key, &[(*command).clone()]));
+        desktop.push_str(&format!(
+            "\n[Desktop Action {action}]\n\
+            Name={command}\n\
+            Exec=\"{exe}\" {args}\n"
+        ));
+    }
+
+    desktop
+}
+
+/// Desktop action identifiers may only contain alphanumeric characters and `-`
+fn action_id(command: &str) -> String {
+    command
+        .chars()
+        .map(|c| match c.is_ascii_alphanumeric() {
+            true => c,
+            false => '-',
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::desktop_entry;
+
+    #[test]
+    fn desktop_action() {
+        let commands = ["Config \"100%\"".to_owned()];
+        let commands = commands.iter().collect::<Vec<_>>();
+        let desktop = desktop_entry(
+            "/usr/bin/brie",
+            "Hollow Knight",
+            "\"Hollow Knight\"",
+            "Hollow Knight",
+            Path::new(""),
+            &commands,
+        );
+
+        assert!(desktop.contains("Actions=Config--100--;\n"));
+        assert!(desktop.contains(
+            "[Desktop Action Config--100--]\n\
+            Name=Config \"100%\"\n\
+            Exec=\"/usr/bin/brie\" \"Hollow Knight\" \"Config \\\\\"100%%\\\\\"\"\n"
+        ));
+    }
+}
@@ -0,0 +1,179 @@
+use std::{
+    fs::OpenOptions,
+    io::{self, Write},
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use brie_cfg::Brie;
+use log::warn;
+use serde::Serialize;
+use serde_json::Value;
+
+/// A single processing cycle of the watcher, written as one JSON line
+#[derive(Serialize)]
+struct Cycle<'a> {
+    /// Unix timestamp in seconds
+    timestamp: u64,
+    /// Units which were added, removed or modified since the previous cycle
+    changed: Vec<&'a str>,
+    /// Generators which updated their files
+    generated: Vec<&'static str>,
+    error: Option<String>,
+}
+
+/// Appends reports of watch cycles to a file, if one is configured
+pub struct Events(Option<PathBuf>);
+
+impl Events {
+    pub fn new(path: Option<PathBuf>) -> Self {
+        Self(path)
+    }
+
+    pub fn cycle(&self, old: Option<&Brie>, new: &Brie, result: &Result<(), crate::Error>) {
+        let Some(path) = &self.0 else {
+            return;
+        };
+
+        let cycle = Cycle {
+            timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |d| d.as_secs()),
+            changed: changed(old, new),
+            generated: match result {
+                Ok(()) => generated(new),
+                Err(_) => Vec::new(),
+            },
+            error: result.as_ref().err().map(ToString::to_string),
+        };
+
+        if let Err(err) = append(path, &cycle) {
+            warn!("Unable to write event to {}. {err}", path.display());
+        }
+    }
+}
+
+fn append(path: &Path, cycle: &Cycle) -> Result<(), io::Error> {
+    let mut line = serde_json::to_vec(cycle)?;
+    line.push(b'\n');
+
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)?
+        .write_all(&line)
+}
+
+fn changed<'a>(old: Option<&'a Brie>, new: &'a Brie) -> Vec<&'a str> {
+    let Some(old) = old else {
+        return new.units.keys().map(String::as_str).collect();
+    };
+
+    let removed = old
+        .units
+        .keys()
+        .filter(|k| !new.units.contains_key(k.as_str()));
+
+    new.units
+        .iter()
+        .filter(|(k, u)| old.units.get(k.as_str()) != Some(*u))
+        .map(|(k, _)| k)
+        .chain(removed)
+        .map(String::as_str)
+        .collect()
+}
+
+/// Describes changes between two configs, e.g. "unit `game` changed: command, env".
+/// Only names of changed fields are listed, so that secrets don't end up in the log.
+pub fn describe_changes(old: &Brie, new: &Brie) -> Vec<String> {
+    let mut changes = Vec::new();
+
+    let settings = changed_fields(old, new)
+        .into_iter()
+        .filter(|field| field != "units")
+        .collect::<Vec<_>>();
+    if !settings.is_empty() {
+        changes.push(format!("settings changed: {}", settings.join(", ")));
+    }
+
+    for (key, unit) in &new.units {
+        match old.units.get(key) {
+            None => changes.push(format!("unit `{key}` added")),
+            Some(old) if old != unit => {
+                let fields = changed_fields(old, unit);
+                changes.push(format!("unit `{key}` changed: {}", fields.join(", ")));
+            }
+            Some(_) => {}
+        }
+    }
+
+    let removed = old.units.keys().filter(|k| !new.units.contains_key(*k));
+    changes.extend(removed.map(|key| format!("unit `{key}` removed")));
+
+    changes
+}
+
+/// Names of top level fields which differ between the serialized values
+fn changed_fields(old: &impl Serialize, new: &impl Serialize) -> Vec<String> {
+    let (Ok(Value::Object(old)), Ok(Value::Object(new))) =
+        (serde_json::to_value(old), serde_json::to_value(new))
+    else {
+        return Vec::new();
+    };
+
+    let removed = old.keys().filter(|k| !new.contains_key(*k));
+    new.iter()
+        .filter(|(k, v)| old.get(*k) != Some(v))
+        .map(|(k, _)| k)
+        .chain(removed)
+        .cloned()
+        .collect()
+}
+
+fn generated(config: &Brie) -> Vec<&'static str> {
+    [
+        ("sunshine", &config.paths.sunshine),
+        ("desktop", &config.paths.desktop),
+        ("steam", &config.paths.steam_config),
+    ]
+    .into_iter()
+    .filter(|(_, path)| path.is_some())
+    .map(|(name, _)| name)
+    .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use brie_cfg::Brie;
+
+    use super::{changed, describe_changes};
+
+    #[test]
+    fn changed_units() {
+        let parse = |units: &[(&str, &str)]| {
+            let units = units
+                .iter()
+                .map(|(key, command)| format!(r#""{key}": {{ "command": "{command}" }}"#))
+                .collect::<Vec<_>>()
+                .join(", ");
+            serde_json::from_str::<Brie>(&format!(r#"{{ "units": {{ {units} }} }}"#)).unwrap()
+        };
+
+        let old = parse(&[("a", "a"), ("b", "b"), ("c", "c")]);
+        let new = parse(&[("a", "a"), ("b", "b2"), ("d", "d")]);
+
+        assert_eq!(changed(None, &old), ["a", "b", "c"]);
+        assert_eq!(changed(Some(&old), &new), ["b", "d", "c"]);
+        assert!(changed(Some(&old), &old).is_empty());
+
+        assert_eq!(
+            describe_changes(&old, &new),
+            [
+                "unit `b` changed: command",
+                "unit `d` added",
+                "unit `c` removed"
+            ]
+        );
+        assert!(describe_changes(&old, &old).is_empty());
+    }
+}
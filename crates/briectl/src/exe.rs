@@ -1,12 +1,114 @@
 use std::{borrow::Cow, path::PathBuf};
 
+/// Path to the `brie` binary, expected to be next to `briectl`
 pub fn path() -> Cow<'static, str> {
+    sibling("brie")
+}
+
+/// Path to the running `briectl` binary
+pub fn briectl() -> Cow<'static, str> {
+    sibling("briectl")
+}
+
+/// Arguments of a `brie` launch of the unit, its key followed by `launch_args`,
+/// quoted so that shell-like command line parsers split them back as they are
+pub fn args(key: &str, launch_args: &[String]) -> String {
+    std::iter::once(key)
+        .chain(launch_args.iter().map(String::as_str))
+        .map(quote)
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Unit key of `brie` launch arguments built by [`args`], which is their first argument
+/// with the quoting reversed. Empty if there are no arguments
+pub fn unit_key(args: &str) -> Cow<'_, str> {
+    let args = args.trim_start();
+    let Some(quoted) = args.strip_prefix('"') else {
+        return Cow::Borrowed(args.split_whitespace().next().unwrap_or_default());
+    };
+
+    let mut key = String::with_capacity(quoted.len());
+    let mut chars = quoted.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' => break,
+            '\\' => key.extend(chars.next()),
+            c => key.push(c),
+        }
+    }
+
+    Cow::Owned(key)
+}
+
+/// Escapes quoted arguments for an `Exec` key of a desktop entry. Backslashes are escaped
+/// once more, since string values of desktop entries are unescaped before the arguments
+/// are split, and `%` is doubled so that it isn't taken for a field code
+pub fn escape_exec(args: &str) -> String {
+    args.replace('\\', "\\\\").replace('%', "%%")
+}
+
+/// Wraps an argument with anything but safe characters in double quotes,
+/// escaping `"`, `\`, `$` and `` ` `` inside
+pub fn quote(arg: &str) -> Cow<'_, str> {
+    let safe = |c: char| c.is_ascii_alphanumeric() || "-_./=:,+@%".contains(c);
+    if !arg.is_empty() && arg.chars().all(safe) {
+        return Cow::Borrowed(arg);
+    }
+
+    let mut quoted = String::with_capacity(arg.len() + 2);
+    quoted.push('"');
+    for c in arg.chars() {
+        if matches!(c, '"' | '\\' | '$' | '`') {
+            quoted.push('\\');
+        }
+        quoted.push(c);
+    }
+    quoted.push('"');
+
+    Cow::Owned(quoted)
+}
+
+fn sibling(name: &'static str) -> Cow<'static, str> {
     std::env::current_exe()
         .ok()
         .as_ref()
         .and_then(|p| p.parent())
-        .map(|p| p.join("brie"))
+        .map(|p| p.join(name))
         .map(PathBuf::into_os_string)
         .and_then(|p| p.into_string().ok())
-        .map_or(Cow::Borrowed("brie"), Cow::Owned)
+        .map_or(Cow::Borrowed(name), Cow::Owned)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{args, unit_key};
+
+    #[test]
+    fn quoted_args() {
+        assert_eq!(args("witcher3", &[]), "witcher3");
+        assert_eq!(
+            args("witcher3", &["--".to_owned(), "-skipIntro".to_owned()]),
+            "witcher3 -- -skipIntro"
+        );
+        assert_eq!(
+            args("witcher3", &["a b".to_owned(), String::new()]),
+            "witcher3 \"a b\" \"\""
+        );
+        assert_eq!(
+            args("witcher3", &["$HOME \"x\" \\".to_owned()]),
+            "witcher3 \"\\$HOME \\\"x\\\" \\\\\""
+        );
+    }
+
+    #[test]
+    fn unit_keys() {
+        assert_eq!(unit_key("witcher3 -- -skipIntro"), "witcher3");
+        assert_eq!(unit_key(""), "");
+
+        for key in ["Hollow Knight", "a \"b\" $c \\"] {
+            let args = args(key, &["-x".to_owned()]);
+            assert_eq!(unit_key(&args), key);
+        }
+    }
 }
@@ -1,25 +1,41 @@
 use std::{
     io,
+    path::{Path, PathBuf},
     process::Command,
     sync::{mpsc, Arc},
+    time::Duration,
 };
 
 use assets::Assets;
-use brie_cfg::Brie;
+use brie_cfg::{Brie, ByteSize};
 use brie_download::mp;
 use clap::{Parser, Subcommand};
 use log::{error, info};
 use notify::{event::ModifyKind, Event, EventKind, RecursiveMode, Watcher};
+use output::Output;
 
 mod assets;
+mod atomic;
+mod autostart;
 mod desktop;
+mod events;
 mod exe;
+mod output;
 mod steam;
 mod sunshine;
 
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
 struct Cli {
+    /// Path to the config file. Defaults to `$BRIE_CONFIG` or `brie.yaml` in the XDG config directory
+    #[arg(long, global = true)]
+    config: Option<PathBuf>,
+    /// Only report files which would be written or removed and assets which would be downloaded
+    #[arg(long, global = true)]
+    dry_run: bool,
+    /// Log a line with the size and speed of each completed download instead of progress bars
+    #[arg(long, global = true)]
+    progress_summary: bool,
     #[command(subcommand)]
     command: Commands,
 }
@@ -38,26 +54,99 @@ enum Commands {
         #[command(subcommand)]
         command: Generate,
     },
+    /// Remove old downloaded versions of runtimes and libraries
+    Clean {
+        /// Number of most recently downloaded versions to keep for each runtime and library.
+        /// Versions used by `latest` links or pinned in the config are always kept
+        #[arg(long, default_value_t = 2)]
+        keep: usize,
+    },
+    /// Show disk usage of wine prefixes and downloaded runtime and library versions, largest first
+    Du {
+        /// Print JSON instead of a table
+        #[arg(long)]
+        json: bool,
+    },
     /// Watch the configuration file for changes and download necessary assets and generate necessary files on change
-    Watch,
+    Watch {
+        /// Append a JSON line describing each processing cycle to this file
+        #[arg(long)]
+        events: Option<PathBuf>,
+    },
 }
 
 #[derive(Subcommand)]
 enum Generate {
     /// Update sunshine configuration with brie units
-    Sunshine,
+    Sunshine {
+        /// Only update entries for the given units
+        units: Vec<String>,
+    },
     /// Generate .desktop files
-    Desktop,
+    Desktop {
+        /// Only update entries for the given units
+        units: Vec<String>,
+    },
     /// Add unit to steam as non-steam titles
-    Steam,
+    Steam {
+        /// Only update entries for the given units
+        units: Vec<String>,
+        /// Remove brie shortcuts and their images instead, of all units unless some are given
+        #[arg(long)]
+        remove: bool,
+    },
     /// Update sunshine configuration and generate .desktop files
-    All,
+    All {
+        /// Only update entries for the given units
+        units: Vec<String>,
+    },
+    /// Start `briectl watch` on login via an XDG autostart entry
+    Autostart {
+        /// Remove the autostart entry instead
+        #[arg(long)]
+        remove: bool,
+    },
+}
+
+impl Generate {
+    fn units(&self) -> &[String] {
+        match self {
+            Generate::Sunshine { units }
+            | Generate::Desktop { units }
+            | Generate::Steam { units, .. }
+            | Generate::All { units } => units,
+            Generate::Autostart { .. } => &[],
+        }
+    }
+}
+
+/// Unit keys selected on the command line. An empty filter selects all units.
+#[derive(Default, Clone, Copy)]
+pub struct Filter<'a>(&'a [String]);
+
+impl<'a> Filter<'a> {
+    fn new(units: &'a [String], config: &Brie) -> Result<Self, Error> {
+        match units.iter().find(|&u| !config.units.contains_key(u)) {
+            Some(unit) => Err(Error::UnknownUnit(unit.clone())),
+            None => Ok(Self(units)),
+        }
+    }
+
+    pub fn matches(self, key: &str) -> bool {
+        self.0.is_empty() || self.0.iter().any(|u| u == key)
+    }
 }
 
+/// Time to wait for more config file events before processing it,
+/// editors often save a file with several writes and renames
+const DEBOUNCE: Duration = Duration::from_millis(500);
+
 #[derive(Subcommand)]
 enum Config {
     /// Open config file in the editor
     Edit,
+    /// Print the JSON Schema of the config file, e.g. for the YAML language server
+    Schema,
 }
 
 fn main() {
@@ -90,16 +179,31 @@ enum Error {
     Steam(#[from] steam::Error),
     #[error("IO error. {0}")]
     Io(#[from] io::Error),
+    #[error("Unable to clean libraries. {0}")]
+    Clean(#[from] brie_wine::GcError),
+    #[error("JSON error. {0}")]
+    Json(#[from] serde_json::Error),
+    #[error("Unable to expand path. {0}")]
+    Expand(#[from] shellexpand::LookupError<std::env::VarError>),
     #[error("Notify error. {0}")]
     Notify(#[from] notify::Error),
+    #[error("Unit `{0}` not found.")]
+    UnknownUnit(String),
+    #[error("Unable to generate {0}.")]
+    Generate(String),
 }
 
 fn run() -> Result<(), Error> {
     let cli = Cli::parse();
+    brie_download::set_progress_summary(cli.progress_summary);
     let xdg = xdg::BaseDirectories::with_prefix("brie")?;
     let cache_dir = xdg.get_data_home();
-    let config_file = xdg.get_config_file("brie.yaml");
+    let explicit_config = cli.config.clone();
+    let config_file = brie_cfg::config_path(cli.config, || xdg.get_config_file("brie.yaml"));
     let exe = exe::path();
+    let out = Output {
+        dry_run: cli.dry_run,
+    };
 
     match cli.command {
         Commands::Config {
@@ -113,111 +217,250 @@ fn run() -> Result<(), Error> {
                 .unwrap_or_else(|_| "vi".to_string());
             Command::new(editor).arg(&config_file).status()?;
         }
+        Commands::Config {
+            command: Config::Schema,
+        } => {
+            println!("{}", serde_json::to_string_pretty(&brie_cfg::schema())?);
+        }
         Commands::Assets => {
             let config = brie_cfg::read(config_file)?;
-            assets::download_all(&cache_dir, &config)?;
+            load_assets(&cache_dir, &config, out)?;
+        }
+        Commands::Generate {
+            command: Generate::Autostart { remove },
+        } => {
+            let autostart = xdg::BaseDirectories::new()?
+                .get_config_home()
+                .join("autostart");
+            match remove {
+                true => autostart::remove(&autostart, out),
+                false => {
+                    // Autostart entries are not run from the current directory
+                    let config = explicit_config.map(std::fs::canonicalize).transpose()?;
+                    autostart::install(&autostart, &exe::briectl(), config.as_deref(), out)?;
+                }
+            }
+        }
+        Commands::Generate {
+            command:
+                Generate::Steam {
+                    units,
+                    remove: true,
+                },
+        } => {
+            // Units which were removed from the config may still have shortcuts
+            let config = brie_cfg::read(config_file)?;
+            steam::remove(&config, Filter(&units), out)?;
         }
         Commands::Generate { command } => {
             let config = brie_cfg::read(config_file)?;
-            let images = assets::download_all(&cache_dir, &config)?;
+            let filter = Filter::new(command.units(), &config)?;
+            let images = load_assets(&cache_dir, &config, out)?;
             match command {
-                Generate::Sunshine => {
+                Generate::Sunshine { .. } => {
                     info!("Generating sunshine configuration");
-                    sunshine::update(&exe, &images, &config)?;
+                    sunshine::update(&exe, &images, &config, filter, out)?;
                 }
-                Generate::Desktop => {
+                Generate::Desktop { .. } => {
                     info!("Generating .desktop files");
-                    desktop::update(&exe, &images, &config)?;
+                    desktop::update(&exe, &images, &config, filter, out)?;
                 }
-                Generate::Steam => {
+                Generate::Steam { .. } => {
                     info!("Adding units to steam");
-                    steam::update(&exe, &images, &config)?;
+                    steam::update(&exe, &images, &config, filter, out)?;
                 }
-                Generate::All => {
-                    update_all(&exe, &images, &config)?;
+                Generate::All { .. } => {
+                    update_all(&exe, &images, &config, filter, out)?;
                 }
+                Generate::Autostart { .. } => unreachable!(),
             }
         }
-        Commands::Watch => {
-            info!(
-                "Watching config file `{}` for changes",
-                config_file.display()
-            );
-
-            let (sender, receiver) = mpsc::channel::<()>();
-
-            let sender = Arc::new(sender);
-            let on_event = || {
-                let sender = sender.clone();
-                move |res: notify::Result<Event>| {
-                    match &res {
-                        Ok(event) => match event.kind {
-                            EventKind::Create(_)
-                            | EventKind::Modify(ModifyKind::Data(_))
-                            | EventKind::Remove(_) => {
-                                log::debug!("Received event: {event:?}");
-                                let _ = sender.send(());
-                            }
-                            _ => {}
-                        },
-                        Err(err) => {
-                            error!("Event error: {err}");
-                        }
-                    };
-                }
-            };
+        Commands::Clean { keep } => {
+            let config = brie_cfg::read(config_file)?;
+            let libraries = brie_wine::Paths::from_config(&cache_dir, &config.paths)?.libraries;
+            let removed = brie_wine::prune_libraries(&libraries, &config, keep, out.dry_run)?;
+            info!("{} old versions removed", removed.len());
+        }
+        Commands::Du { json } => {
+            let config = brie_cfg::read(config_file)?;
+            let paths = brie_wine::Paths::from_config(&cache_dir, &config.paths)?;
+            disk_usage(&paths, json)?;
+        }
+        Commands::Watch { events } => {
+            watch(
+                &config_file,
+                &cache_dir,
+                &exe,
+                &events::Events::new(events),
+                out,
+            )?;
+        }
+    };
 
-            let process = |config: &Brie| {
-                let assets = assets::download_all(&cache_dir, config)?;
-                update_all(&exe, &assets, config)?;
-                Ok::<_, Error>(())
-            };
+    Ok(())
+}
 
-            let mut config = brie_cfg::read(config_file.clone())?;
+/// Downloads assets, or in a dry run only reports what would be downloaded
+fn load_assets(cache_dir: &Path, config: &Brie, out: Output) -> Result<Assets, Error> {
+    brie_download::set_download_headers(&config.download_headers);
+    Ok(match out.dry_run {
+        true => assets::plan(cache_dir, config),
+        false => assets::download_all(cache_dir, config)?,
+    })
+}
 
-            info!("Processing config before watch");
-            if let Err(err) = process(&config) {
-                error!("Error processing config: {err}");
-            }
+/// Prints sizes of prefixes and downloaded versions with their total
+fn disk_usage(paths: &brie_wine::Paths, json: bool) -> Result<(), Error> {
+    let usage = brie_wine::disk_usage(paths)?;
+    let total = usage.iter().map(|u| u.size).sum::<u64>();
 
-            info!("Starting watcher");
-            let mut watcher = notify::recommended_watcher(on_event())?;
-            watcher.watch(&config_file, RecursiveMode::NonRecursive)?;
+    if json {
+        let report = serde_json::json!({ "entries": usage, "total": total });
+        println!("{}", serde_json::to_string_pretty(&report)?);
+        return Ok(());
+    }
 
-            while let Ok(()) = receiver.recv() {
-                // If a file is edited by deleting the original and creating a new one, without restarting the watcher
-                // after deletion watcher will never receive new events.
-                watcher = notify::recommended_watcher(on_event())?;
-                watcher.watch(&config_file, RecursiveMode::NonRecursive)?;
+    for entry in &usage {
+        let kind = match entry.kind {
+            brie_wine::UsageKind::Prefix => "prefix",
+            brie_wine::UsageKind::Library => "library",
+        };
+        println!(
+            "{:>12}  {kind:<8} {}",
+            ByteSize(entry.size).to_string(),
+            entry.name
+        );
+    }
+    println!("{:>12}  total", ByteSize(total).to_string());
 
-                info!("Received event, processing config");
+    Ok(())
+}
 
-                let new_config = brie_cfg::read(config_file.clone())?;
-                if new_config == config {
-                    info!("Config did not change");
-                    continue;
-                }
-                config = new_config;
+/// Runs all generators, even if some of them fail, and reports which of them failed
+fn update_all(
+    exe: &str,
+    assets: &Assets,
+    config: &Brie,
+    filter: Filter,
+    out: Output,
+) -> Result<(), Error> {
+    let results = [
+        ("sunshine configuration", {
+            info!("Generating sunshine configuration");
+            sunshine::update(exe, assets, config, filter, out).map_err(Error::from)
+        }),
+        (".desktop files", {
+            info!("Generating .desktop files");
+            desktop::update(exe, assets, config, filter, out).map_err(Error::from)
+        }),
+        ("steam shortcuts", {
+            info!("Adding units to steam");
+            steam::update(exe, assets, config, filter, out).map_err(Error::from)
+        }),
+    ];
 
-                if let Err(err) = process(&config) {
-                    error!("Error processing config: {err}");
-                }
+    let mut failed = Vec::new();
+    for (step, result) in results {
+        match result {
+            Ok(()) => info!("Generated {step}"),
+            Err(err) => {
+                error!("Unable to generate {step}. {err}");
+                failed.push(step);
             }
+        }
+    }
+
+    match failed.is_empty() {
+        true => Ok(()),
+        false => Err(Error::Generate(failed.join(", "))),
+    }
+}
+
+/// Watches the config file and regenerates files whenever it changes
+fn watch(
+    config_file: &Path,
+    cache_dir: &Path,
+    exe: &str,
+    events: &events::Events,
+    out: Output,
+) -> Result<(), Error> {
+    info!(
+        "Watching config file `{}` for changes",
+        config_file.display()
+    );
+
+    let (sender, receiver) = mpsc::channel::<()>();
 
-            info!("Loop ended?");
+    let sender = Arc::new(sender);
+    let on_event = || {
+        let sender = sender.clone();
+        move |res: notify::Result<Event>| {
+            match &res {
+                Ok(event) => match event.kind {
+                    EventKind::Create(_)
+                    | EventKind::Modify(ModifyKind::Data(_))
+                    | EventKind::Remove(_) => {
+                        log::debug!("Received event: {event:?}");
+                        let _ = sender.send(());
+                    }
+                    _ => {}
+                },
+                Err(err) => {
+                    error!("Event error: {err}");
+                }
+            };
         }
     };
 
-    Ok(())
-}
+    let process = |config: &Brie| {
+        let assets = load_assets(cache_dir, config, out)?;
+        update_all(exe, &assets, config, Filter::default(), out)?;
+        Ok::<_, Error>(())
+    };
+
+    let mut config = brie_cfg::read(config_file.to_path_buf())?;
+
+    info!("Processing config before watch");
+    let result = process(&config);
+    if let Err(err) = &result {
+        error!("Error processing config: {err}");
+    }
+    events.cycle(None, &config, &result);
+
+    info!("Starting watcher");
+    let mut watcher = notify::recommended_watcher(on_event())?;
+    watcher.watch(config_file, RecursiveMode::NonRecursive)?;
+
+    while let Ok(()) = receiver.recv() {
+        while let Ok(()) = receiver.recv_timeout(DEBOUNCE) {
+            log::debug!("Received another event, waiting for events to settle");
+        }
+
+        // If a file is edited by deleting the original and creating a new one, without restarting the watcher
+        // after deletion watcher will never receive new events.
+        watcher = notify::recommended_watcher(on_event())?;
+        watcher.watch(config_file, RecursiveMode::NonRecursive)?;
+
+        info!("Received event, processing config");
+
+        let new_config = brie_cfg::read(config_file.to_path_buf())?;
+        if new_config == config {
+            info!("Config did not change");
+            continue;
+        }
+        for change in events::describe_changes(&config, &new_config) {
+            info!("Config change: {change}");
+        }
+
+        let result = process(&new_config);
+        if let Err(err) = &result {
+            error!("Error processing config: {err}");
+        }
+        events.cycle(Some(&config), &new_config, &result);
+        config = new_config;
+    }
 
-fn update_all(exe: &str, assets: &Assets, config: &Brie) -> Result<(), Error> {
-    info!("Generating sunshine configuration");
-    sunshine::update(exe, assets, config)?;
-    info!("Generating .desktop files");
-    desktop::update(exe, assets, config)?;
-    info!("Adding units to steam");
-    steam::update(exe, assets, config)?;
+    info!("Loop ended?");
 
     Ok(())
 }
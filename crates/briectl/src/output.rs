@@ -0,0 +1,61 @@
+use std::{io, path::Path};
+
+use log::{debug, info};
+
+use crate::atomic;
+
+/// File system changes made by generators. In a dry run changes are only reported.
+#[derive(Default, Clone, Copy)]
+pub struct Output {
+    pub dry_run: bool,
+}
+
+impl Output {
+    /// Writes the file, replacing it atomically
+    pub fn write(self, path: &Path, contents: &[u8]) -> io::Result<()> {
+        if self.dry_run {
+            info!("Would write {}", path.display());
+            return Ok(());
+        }
+
+        atomic::write(path, contents)
+    }
+
+    /// Writes the file without keeping a backup, for files which are fully owned by brie
+    pub fn write_owned(self, path: &Path, contents: &[u8]) -> io::Result<()> {
+        if self.dry_run {
+            info!("Would write {}", path.display());
+            return Ok(());
+        }
+
+        std::fs::write(path, contents)
+    }
+
+    pub fn copy(self, from: &Path, to: &Path) -> io::Result<()> {
+        if self.dry_run {
+            info!("Would copy {} to {}", from.display(), to.display());
+            return Ok(());
+        }
+
+        debug!("Copying {} to {}", from.display(), to.display());
+        std::fs::copy(from, to).map(|_| ())
+    }
+
+    /// Removes the file, ignoring errors
+    pub fn remove(self, path: &Path) {
+        if self.dry_run {
+            info!("Would remove {}", path.display());
+            return;
+        }
+
+        debug!("Removing {}", path.display());
+        let _ = std::fs::remove_file(path);
+    }
+
+    /// Creates the directory with all of its parents, ignoring errors
+    pub fn create_dir_all(self, path: &Path) {
+        if !self.dry_run {
+            let _ = std::fs::create_dir_all(path);
+        }
+    }
+}
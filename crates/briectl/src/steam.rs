@@ -6,13 +6,18 @@ use std::{
 };
 
 use brie_cfg::Brie;
-use log::{debug, info};
+use log::info;
 use shellexpand::LookupError;
 use steam_shortcuts_util::{
     calculate_app_id_for_shortcut, parse_shortcuts, shortcuts_to_bytes, Shortcut,
 };
 
-use crate::assets::{Assets, ImageKind, Images};
+use crate::{
+    assets::{Assets, ImageKind, Images},
+    exe,
+    output::Output,
+    Filter,
+};
 
 #[derive(thiserror::Error, Debug)]
 pub enum Error {
@@ -24,14 +29,80 @@ pub enum Error {
     Expand(#[from] LookupError<VarError>),
 }
 
-pub fn update(exe: &str, assets: &Assets, config: &Brie) -> Result<(), Error> {
+/// Path to the steam user config directory, if it is configured
+fn config_path(config: &Brie) -> Result<Option<PathBuf>, Error> {
     let Some(steam_config) = config.paths.steam_config.as_ref() else {
-        info!("Steam config path not provided, skipping shortcut generation");
+        return Ok(None);
+    };
+
+    Ok(Some(PathBuf::from(
+        shellexpand::full(steam_config)?.as_ref(),
+    )))
+}
+
+fn read_shortcuts(bytes: Option<&[u8]>) -> Result<Vec<Shortcut<'_>>, Error> {
+    match bytes {
+        Some(s) => parse_shortcuts(s).map_err(Error::Steam),
+        None => Ok(Vec::new()),
+    }
+}
+
+fn is_brie(shortcut: &Shortcut) -> bool {
+    shortcut.tags.iter().any(|&t| t == "brie")
+}
+
+/// Removes shortcuts generated by brie and their images, of all units or only the filtered ones.
+/// Shortcuts of units which are no longer in the config are removed as well.
+pub fn remove(config: &Brie, filter: Filter, out: Output) -> Result<(), Error> {
+    let Some(steam_config) = config_path(config)? else {
+        info!("Steam config path not provided, skipping shortcut removal");
+        return Ok(());
+    };
+
+    let shortcuts_path = steam_config.join("shortcuts.vdf");
+    let Ok(shortcuts) = std::fs::read(&shortcuts_path) else {
+        info!("No shortcuts found at {shortcuts_path:?}, nothing to remove");
         return Ok(());
     };
 
-    let steam_config = shellexpand::full(steam_config)?;
-    let steam_config = Path::new(steam_config.as_ref());
+    let (shortcuts, delete) = parse_shortcuts(&shortcuts)
+        .map_err(Error::Steam)?
+        .into_iter()
+        .partition::<Vec<_>, _>(|s| {
+            !is_brie(s) || !filter.matches(&exe::unit_key(s.launch_options))
+        });
+
+    if delete.is_empty() {
+        info!("No brie shortcuts to remove");
+        return Ok(());
+    }
+
+    let existing_images = ls(&steam_config.join("grid"));
+    for shortcut in delete {
+        let key = exe::unit_key(shortcut.launch_options);
+        match out.dry_run {
+            true => info!("Would remove shortcut for `{key}`"),
+            false => info!("Removing shortcut for `{key}`"),
+        }
+        delete_images(&existing_images, shortcut.app_id, out);
+    }
+
+    out.write(&shortcuts_path, &shortcuts_to_bytes(&shortcuts))?;
+
+    Ok(())
+}
+
+pub fn update(
+    exe: &str,
+    assets: &Assets,
+    config: &Brie,
+    filter: Filter,
+    out: Output,
+) -> Result<(), Error> {
+    let Some(steam_config) = config_path(config)? else {
+        info!("Steam config path not provided, skipping shortcut generation");
+        return Ok(());
+    };
 
     let shortcuts_path = steam_config.join("shortcuts.vdf");
     let grid_path = steam_config.join("grid");
@@ -39,6 +110,7 @@ pub fn update(exe: &str, assets: &Assets, config: &Brie) -> Result<(), Error> {
     let units = config
         .units
         .iter()
+        .filter(|(k, _)| filter.matches(k))
         .map(|(k, v)| (k, v.common()))
         .filter(|(_, unit)| unit.generate.steam_shortcut)
         .map(|(k, u)| (k.as_str(), u))
@@ -49,29 +121,34 @@ pub fn update(exe: &str, assets: &Assets, config: &Brie) -> Result<(), Error> {
         return Ok(());
     }
 
+    let launch_options = units
+        .iter()
+        .map(|(&key, unit)| (key, exe::args(key, &unit.launch_args)))
+        .collect::<HashMap<_, _>>();
+
     let shortcuts = std::fs::read(&shortcuts_path).ok();
-    let shortcuts = match shortcuts.as_ref() {
-        Some(s) => {
-            info!("Reading shortcuts from {shortcuts_path:?}");
-            parse_shortcuts(s).map_err(Error::Steam)?
-        }
-        None => {
-            info!("No shortcuts found, generating new ones");
-            Vec::new()
-        }
-    };
+    match shortcuts.is_some() {
+        true => info!("Reading shortcuts from {shortcuts_path:?}"),
+        false => info!("No shortcuts found, generating new ones"),
+    }
+    let shortcuts = read_shortcuts(shortcuts.as_deref())?;
 
     let existing_images = ls(&grid_path);
 
-    // Remove shortcuts that are not in the config any more
+    // Remove shortcuts of selected units that are not in the config any more
     let (mut shortcuts, delete) = shortcuts.into_iter().partition::<Vec<_>, _>(|s| {
-        units.contains_key(s.launch_options) || !s.tags.iter().any(|&t| t == "brie")
+        let key = exe::unit_key(s.launch_options);
+        units.contains_key(&*key) || !filter.matches(&key) || !is_brie(s)
     });
 
     // Remove images for deleted shortcuts
     for shortcut in delete {
-        info!("Removing shortcut for `{}`", shortcut.launch_options);
-        delete_images(&existing_images, shortcut.app_id);
+        let key = exe::unit_key(shortcut.launch_options);
+        match out.dry_run {
+            true => info!("Would remove shortcut for `{key}`"),
+            false => info!("Removing shortcut for `{key}`"),
+        }
+        delete_images(&existing_images, shortcut.app_id, out);
     }
 
     let mut updated_keys = HashSet::new();
@@ -79,27 +156,29 @@ pub fn update(exe: &str, assets: &Assets, config: &Brie) -> Result<(), Error> {
     let mut icons = HashMap::new();
 
     // Icons will be copied to steam config folder so that steam has access to them if running from flatpak
-    let icon_path = |app_id: u32| {
+    let icon_path = |key: &str, app_id: u32| {
+        let icon = assets.get(key, ImageKind::Icon);
+        let ext = icon.and_then(Path::extension).unwrap_or("png".as_ref());
         grid_path
             .join(ImageKind::Icon.steam_file_name(app_id))
-            .with_extension("png")
+            .with_extension(ext)
     };
 
     // Update shortcuts that are in the config
-    let update_iter = shortcuts
-        .iter_mut()
-        .filter(|s| s.tags.iter().any(|&t| t == "brie"))
-        .filter_map(|s| units.get(s.launch_options).map(|u| (s, u)));
+    let update_iter = shortcuts.iter_mut().filter(|s| is_brie(s)).filter_map(|s| {
+        let (&name, unit) = units.get_key_value(&*exe::unit_key(s.launch_options))?;
+        Some((s, name, unit))
+    });
 
-    for (shortcut, unit) in update_iter {
-        let name = shortcut.launch_options;
+    for (shortcut, name, unit) in update_iter {
         info!("Updating shortcut for `{name}`");
         updated_keys.insert(name);
         shortcut.exe = exe;
+        shortcut.launch_options = &launch_options[name];
         shortcut.app_name = unit.name.as_deref().unwrap_or(name);
         shortcut.app_id = calculate_app_id_for_shortcut(shortcut);
         app_ids.insert(name, shortcut.app_id);
-        icons.insert(shortcut.app_id, icon_path(shortcut.app_id));
+        icons.insert(shortcut.app_id, icon_path(name, shortcut.app_id));
     }
 
     // Insert missing units
@@ -108,30 +187,27 @@ pub fn update(exe: &str, assets: &Assets, config: &Brie) -> Result<(), Error> {
     for (key, unit) in insert_iter {
         info!("Adding shortcut for `{key}`");
         let name = unit.name.as_deref().unwrap_or(key);
-        let mut shortcut = Shortcut::new("0", name, exe, "", "", "", key);
+        let mut shortcut = Shortcut::new("0", name, exe, "", "", "", &launch_options[key]);
 
         shortcut.tags = vec!["brie"];
         app_ids.insert(key, shortcut.app_id);
-        icons.insert(shortcut.app_id, icon_path(shortcut.app_id));
+        icons.insert(shortcut.app_id, icon_path(key, shortcut.app_id));
         shortcuts.push(shortcut);
     }
 
     // Copy all images into grid folder
     info!("Copying images");
-    let _ = std::fs::create_dir_all(&grid_path);
+    out.create_dir_all(&grid_path);
     for (key, _) in units {
         let (Some(&app_id), images) = (app_ids.get(key), assets.get_all(key)) else {
             continue;
         };
 
-        copy_images(&grid_path, app_id, images.as_ref())?;
+        copy_images(&grid_path, app_id, images.as_ref(), out)?;
     }
 
     // Update icons
-    for shortcut in shortcuts
-        .iter_mut()
-        .filter(|s| s.tags.iter().any(|&t| t == "brie"))
-    {
+    for shortcut in shortcuts.iter_mut().filter(|s| is_brie(s)) {
         let icon = icons.get(&shortcut.app_id);
         let Some(icon) = icon else { continue };
         let Some(icon) = icon.to_str() else { continue };
@@ -139,7 +215,7 @@ pub fn update(exe: &str, assets: &Assets, config: &Brie) -> Result<(), Error> {
     }
 
     let shortcuts = shortcuts_to_bytes(&shortcuts);
-    std::fs::write(shortcuts_path, shortcuts).unwrap_or_default();
+    out.write(&shortcuts_path, &shortcuts)?;
 
     Ok(())
 }
@@ -165,7 +241,7 @@ fn ls(path: &Path) -> Vec<PathBuf> {
         .unwrap_or_default()
 }
 
-fn copy_images(grid_path: &Path, app_id: u32, images: &Images) -> Result<(), Error> {
+fn copy_images(grid_path: &Path, app_id: u32, images: &Images, out: Output) -> Result<(), Error> {
     for kind in ImageKind::all() {
         let name = kind.steam_file_name(app_id);
         let Some(image) = images.get(kind) else {
@@ -174,14 +250,13 @@ fn copy_images(grid_path: &Path, app_id: u32, images: &Images) -> Result<(), Err
 
         let ext = image.extension().unwrap_or_default();
         let path = grid_path.join(name).with_extension(ext);
-        debug!("Copying image {image:?} to {path:?}");
-        let _ = std::fs::copy(image, path)?;
+        out.copy(image, &path)?;
     }
 
     Ok(())
 }
 
-fn delete_images(images: &[PathBuf], id: u32) {
+fn delete_images(images: &[PathBuf], id: u32, out: Output) {
     for image in images {
         let Some(name) = image.file_name() else {
             continue;
@@ -189,8 +264,7 @@ fn delete_images(images: &[PathBuf], id: u32) {
 
         let name = name.to_string_lossy();
         if name.starts_with(&format!("{id}_")) || name.starts_with(&format!("{id}p")) {
-            debug!("Removing image {image:?}");
-            let _ = std::fs::remove_file(image);
+            out.remove(image);
         }
     }
 }
@@ -1,4 +1,5 @@
 use std::{
+    borrow::Cow,
     env::VarError,
     io,
     path::{Path, PathBuf},
@@ -9,7 +10,12 @@ use log::info;
 use serde::{Deserialize, Serialize};
 use shellexpand::LookupError;
 
-use crate::assets::{Assets, ImageKind};
+use crate::{
+    assets::{Assets, ImageKind},
+    exe,
+    output::Output,
+    Filter,
+};
 
 #[derive(thiserror::Error, Debug)]
 pub enum Error {
@@ -47,7 +53,13 @@ pub struct Config {
     pub rest: serde_json::Value,
 }
 
-pub fn update(exe: &str, assets: &Assets, config: &Brie) -> Result<(), Error> {
+pub fn update(
+    exe: &str,
+    assets: &Assets,
+    config: &Brie,
+    filter: Filter,
+    out: Output,
+) -> Result<(), Error> {
     let Some(sunshine_path) = config.paths.sunshine.as_ref() else {
         info!("Sunshine path not provided, skipping sunshine generation");
         return Ok(());
@@ -57,7 +69,7 @@ pub fn update(exe: &str, assets: &Assets, config: &Brie) -> Result<(), Error> {
     let sunshine_path = Path::new(sunshine_path.as_ref());
 
     if let Some(path) = sunshine_path.parent() {
-        let _ = std::fs::create_dir_all(path);
+        out.create_dir_all(path);
     }
 
     info!("Loading sunshine config from {}", sunshine_path.display());
@@ -66,37 +78,55 @@ pub fn update(exe: &str, assets: &Assets, config: &Brie) -> Result<(), Error> {
         .and_then(|s| serde_json::from_slice(&s).ok())
         .unwrap_or_default();
 
-    // Retain foreign entries
-    // FIXME: find a better way to do this
-    sunshine_config
-        .apps
-        .retain(|a| !a.cmd.as_deref().map_or(false, |cmd| cmd.contains("brie ")));
+    // Retain foreign entries and entries of units not selected by the filter
+    sunshine_config.apps.retain(|a| {
+        let key = a.cmd.as_deref().and_then(unit_key);
+        let selected = key.is_some_and(|key| filter.matches(&key));
+        if selected && out.dry_run {
+            info!("Would remove sunshine app `{}`", a.name);
+        }
+        !selected
+    });
 
     config
         .units
         .iter()
+        .filter(|(k, _)| filter.matches(k))
         .map(|(k, v)| (k, v.common()))
         .filter(|(_, unit)| unit.generate.sunshine)
         .map(|(k, unit)| App {
             name: unit.name.as_ref().unwrap_or(k).clone(),
             output: None,
-            cmd: Some(format!("{exe} {k}")),
+            cmd: Some(format!("{exe} {}", exe::args(k, &unit.launch_args))),
             image_path: assets.get(k, ImageKind::Grid).map(Path::to_path_buf),
             rest: serde_json::Value::Object(serde_json::Map::default()),
         })
-        .for_each(|app| sunshine_config.apps.push(app));
+        .for_each(|app| {
+            if out.dry_run {
+                info!("Would add sunshine app `{}`", app.name);
+            }
+            sunshine_config.apps.push(app);
+        });
 
     let sunshine_apps = serde_json::to_string_pretty(&sunshine_config)?;
 
     info!("Saving sunshine config to {}", sunshine_path.display());
-    std::fs::write(sunshine_path, sunshine_apps)?;
+    out.write(sunshine_path, sunshine_apps.as_bytes())?;
 
     Ok(())
 }
 
+/// Extracts the unit key from a command of an app generated by brie
+// FIXME: find a better way to do this
+fn unit_key(cmd: &str) -> Option<Cow<'_, str>> {
+    let (_, args) = cmd.split_once("brie ")?;
+    let key = exe::unit_key(args);
+    (!key.is_empty()).then_some(key)
+}
+
 #[cfg(test)]
 mod tests {
-    use super::Config;
+    use super::{unit_key, Config};
 
     #[test]
     fn serialization() {
@@ -106,4 +136,23 @@ mod tests {
 
         assert_eq!(json, src);
     }
+
+    #[test]
+    fn unit_keys() {
+        assert_eq!(
+            unit_key("/usr/bin/brie witcher3").as_deref(),
+            Some("witcher3")
+        );
+        assert_eq!(unit_key("brie witcher3").as_deref(), Some("witcher3"));
+        assert_eq!(
+            unit_key("/usr/bin/brie witcher3 -- --brie \"a b\"").as_deref(),
+            Some("witcher3")
+        );
+        assert_eq!(
+            unit_key("/usr/bin/brie \"Hollow Knight\" -x").as_deref(),
+            Some("Hollow Knight")
+        );
+        assert_eq!(unit_key("/usr/bin/brie "), None);
+        assert_eq!(unit_key("steam -bigpicture"), None);
+    }
 }